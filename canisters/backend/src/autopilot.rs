@@ -0,0 +1,343 @@
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize};
+
+use crate::games::{self, Difficulty, Game, GameInstance, GameKind};
+use crate::llm::{ChatMessage, Llm, Role};
+use crate::state::{
+    autopilot::{ActionRecord, FeedPost},
+    read_agents, read_autopilot, read_config, read_games, read_holders, read_trades,
+    trades::TradeSide,
+    write_autopilot, write_games,
+};
+
+const TICK_SECS: u64 = 10 * 60;
+const ACTION_PREFIX: &str = "ACTION:";
+const MAX_ANNOUNCEMENT_LEN: usize = 280;
+const MAX_REASONING_LEN: usize = 1_000;
+
+/// The actions an agent can take on its own, anything else the llm comes up with is rejected
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum AgentAction {
+    Announce {
+        message: String,
+    },
+    AdjustDifficulty {
+        game: GameKind,
+        difficulty: Difficulty,
+    },
+    StartLuckyDrawRound,
+    Nothing,
+}
+
+#[derive(serde::Deserialize)]
+struct RawAction {
+    action: String,
+    #[serde(default)]
+    reasoning: String,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    game: Option<String>,
+    #[serde(default)]
+    difficulty: Option<String>,
+}
+
+/// the action and the reasoning the llm gave for it
+pub fn parse_action(reply: &str) -> Result<(AgentAction, String), String> {
+    let json = reply
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(ACTION_PREFIX))
+        .ok_or_else(|| String::from("reply doesn't contain an action"))?;
+    let raw: RawAction = serde_json::from_str(json.trim())
+        .map_err(|err| format!("action isn't valid json: {err}"))?;
+    let action = match raw.action.as_str() {
+        "announce" => {
+            let message = raw.message.unwrap_or_default().trim().to_string();
+            if message.is_empty() || message.len() > MAX_ANNOUNCEMENT_LEN {
+                return Err(format!(
+                    "announcement should be 1 to {MAX_ANNOUNCEMENT_LEN} bytes"
+                ));
+            }
+            AgentAction::Announce { message }
+        }
+        "adjust_difficulty" => AgentAction::AdjustDifficulty {
+            game: match raw.game.as_deref() {
+                Some("lucky_draw") => GameKind::LuckyDraw,
+                Some("riddle") => GameKind::Riddle,
+                _ => return Err(String::from("`game` should be `lucky_draw` or `riddle`")),
+            },
+            difficulty: match raw.difficulty.as_deref() {
+                Some("easy") => Difficulty::Easy,
+                Some("normal") => Difficulty::Normal,
+                Some("hard") => Difficulty::Hard,
+                _ => {
+                    return Err(String::from(
+                        "`difficulty` should be `easy`, `normal` or `hard`",
+                    ));
+                }
+            },
+        },
+        "start_lucky_draw_round" => AgentAction::StartLuckyDrawRound,
+        "none" => AgentAction::Nothing,
+        action => return Err(format!("unknown action `{action}`")),
+    };
+    let mut reasoning = raw.reasoning.trim().to_string();
+    truncate(&mut reasoning, MAX_REASONING_LEN);
+    Ok((action, reasoning))
+}
+
+fn truncate(text: &mut String, len: usize) {
+    let mut end = len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+}
+
+pub fn start_autopilot_timer() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(TICK_SECS), run_due_agents);
+}
+
+// every agent is run in its own task, so that a trapping llm call only affects that agent
+fn run_due_agents() {
+    let config = read_config(|config| config.autopilot_config());
+    if !config.enabled {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let interval = config.interval_secs * 1_000_000_000;
+    let mut due = read_agents(|agents| {
+        read_autopilot(|state| {
+            agents
+                .mapping
+                .iter()
+                .filter(|(_, agent)| agent.runeid.is_some() && agent.autopilot == Some(true))
+                .map(|(id, _)| (state.last_run.get(&id).unwrap_or(0), id))
+                .filter(|(last_run, _)| now.saturating_sub(*last_run) >= interval)
+                .collect::<Vec<_>>()
+        })
+    });
+    // agents waiting the longest go first
+    due.sort();
+    for (_, agent_id) in due.into_iter().take(config.agents_per_tick as usize) {
+        // marked before running, a failing agent waits for its next turn like any other
+        write_autopilot(|state| state.last_run.insert(agent_id, now));
+        ic_cdk::spawn(run_agent(agent_id));
+    }
+}
+
+async fn run_agent(agent_id: u128) {
+    let (llm, prompt) = describe_state(agent_id);
+    let messages = vec![
+        ChatMessage {
+            role: Role::System,
+            content: prompt,
+        },
+        ChatMessage {
+            role: Role::User,
+            content: String::from("pick your next action"),
+        },
+    ];
+    let reply = Llm::complete(&llm, messages).await;
+    let record = match parse_action(&reply) {
+        Ok((action, reasoning)) => {
+            let result = execute(agent_id, &action).await;
+            ActionRecord {
+                timestamp: ic_cdk::api::time(),
+                action,
+                reasoning,
+                result,
+            }
+        }
+        Err(err) => {
+            let mut reasoning = reply;
+            truncate(&mut reasoning, MAX_REASONING_LEN);
+            ActionRecord {
+                timestamp: ic_cdk::api::time(),
+                action: AgentAction::Nothing,
+                reasoning,
+                result: Err(err),
+            }
+        }
+    };
+    write_autopilot(|state| state.record_action(agent_id, record));
+}
+
+async fn execute(agent_id: u128, action: &AgentAction) -> Result<String, String> {
+    let now = ic_cdk::api::time();
+    match action {
+        AgentAction::Announce { message } => {
+            let index = write_autopilot(|state| {
+                state.post(
+                    agent_id,
+                    FeedPost {
+                        timestamp: now,
+                        message: message.clone(),
+                    },
+                )
+            });
+            Ok(format!("posted announcement {index}"))
+        }
+        AgentAction::AdjustDifficulty { game, difficulty } => write_games(|games| {
+            games.update(agent_id, *game, |game| {
+                game.round_mut().difficulty.replace(*difficulty);
+            })
+        })
+        .map(|_| format!("{difficulty:?} from the next round"))
+        .ok_or_else(|| format!("agent doesn't run {game:?}")),
+        AgentAction::StartLuckyDrawRound => start_lucky_draw_round(agent_id, now).await,
+        AgentAction::Nothing => Ok(String::new()),
+    }
+}
+
+// replaces the secret of a round nobody managed to win, the prize pool carries over
+async fn start_lucky_draw_round(agent_id: u128, now: u64) -> Result<String, String> {
+    let min_age = read_config(|config| config.autopilot_config().min_round_age_secs);
+    let round = read_games(|games| games.get(agent_id, GameKind::LuckyDraw))
+        .map(|instance| instance.game().round().clone())
+        .ok_or_else(|| String::from("agent doesn't run a lucky draw"))?;
+    if round.winner.is_some() {
+        return Err(String::from(
+            "round is won, the winner has to claim the prize first",
+        ));
+    }
+    if now.saturating_sub(round.started_at) < min_age * 1_000_000_000 {
+        return Err(String::from("round is too young to be replaced"));
+    }
+    let (llm, name, description) = read_agents(|agents| {
+        let agent = agents.mapping.get(&agent_id).unwrap();
+        (agent.llm_config(), agent.name.clone(), agent.description)
+    });
+    let seed = games::seed_round(
+        GameKind::LuckyDraw,
+        &llm,
+        round.difficulty(),
+        &name,
+        &description,
+    )
+    .await;
+    write_games(|games| {
+        let Some(GameInstance::LuckyDraw(mut game)) = games.get(agent_id, GameKind::LuckyDraw)
+        else {
+            return Err(String::from("agent doesn't run a lucky draw"));
+        };
+        // the round could have been won while the secret was generated
+        if game.round.winner.is_some() || game.round.started_at != round.started_at {
+            return Err(String::from("round changed in the meantime"));
+        }
+        // a jailbreak secret is revealed so that anyone can check the round was fair
+        let (abandoned, revealed) = game.abandon_round()?;
+        game.start_round(seed, ic_cdk::api::time());
+        games.insert(agent_id, GameInstance::LuckyDraw(game));
+        let action = if revealed { "revealed" } else { "dropped" };
+        Ok(format!(
+            "{action} the secret committed to {abandoned} and started a new round"
+        ))
+    })
+}
+
+// the llm config of the agent and the prompt describing its token and games
+fn describe_state(agent_id: u128) -> (crate::state::LlmConfig, String) {
+    let agent = read_agents(|agents| agents.mapping.get(&agent_id).unwrap());
+    let now = ic_cdk::api::time();
+    let mut state = format!(
+        "price: {} satoshis per token, market cap: {} satoshis, total supply: {} token units, ",
+        agent.price(),
+        agent.market_cap(),
+        agent.total_supply
+    );
+    state.push_str(&match agent.pool {
+        Some(_) => String::from("trading on the liquidity pool.\n"),
+        None => String::from("trading on the bonding curve.\n"),
+    });
+    state.push_str(&format!(
+        "agent's balance: {} satoshis, {} token units.\n",
+        agent.bitcoin, agent.rune
+    ));
+    let (count, largest) = read_holders(|holders| {
+        (
+            holders.holder_count(agent_id),
+            holders.get_holders(agent_id, 0, 5),
+        )
+    });
+    state.push_str(&format!("holders: {count}, largest:"));
+    for (holder, balance) in largest {
+        state.push_str(&format!(" {holder} with {balance},"));
+    }
+    state.push_str("\nrecent trades:");
+    for (key, trade) in read_trades(|trades| trades.get_trades(agent_id, None, 10)) {
+        let side = match trade.side {
+            TradeSide::Buy => "buy",
+            TradeSide::Sell => "sell",
+        };
+        let hours = now.saturating_sub(key.timestamp) / 3_600_000_000_000;
+        state.push_str(&format!(
+            " {side} of {} token units for {} satoshis {hours} hours ago,",
+            trade.rune, trade.bitcoin
+        ));
+    }
+    state.push_str("\ngames:");
+    for instance in read_games(|games| games.games_of(agent_id)) {
+        let game = instance.game();
+        let round = game.round();
+        state.push_str(&format!(
+            " {:?} with a prize pool of {} satoshis and {} token units, {} entries, {:?} difficulty, started {} hours ago, {},",
+            game.kind(),
+            round.prize_pool.0,
+            round.prize_pool.1,
+            round.entries,
+            round.difficulty(),
+            now.saturating_sub(round.started_at) / 3_600_000_000_000,
+            if round.winner.is_some() { "won" } else { "not won yet" }
+        ));
+    }
+    state.push_str("\nyour latest announcements:");
+    for (_, post) in read_autopilot(|state| state.feed_of(agent_id, None, 3)) {
+        state.push_str(&format!(" \"{}\",", post.message));
+    }
+
+    let persona = agent
+        .persona
+        .as_ref()
+        .map(|persona| persona.to_prompt())
+        .unwrap_or_default();
+    let prompt = format!(
+        r#"You're an AI agent. Your name is {}. Here is some more description about you: {}. You're a tokenized agent running mini games for your token holders. Every few hours you look at the state of your token and games and pick one action.{persona}
+    STATE:
+{state}
+    ACTIONS:
+    announce {{"message": "..."}} - post an announcement of up to {MAX_ANNOUNCEMENT_LEN} characters to your feed
+    adjust_difficulty {{"game": "lucky_draw" or "riddle", "difficulty": "easy", "normal" or "hard"}} - applies from the next round, e.g. make it easier when nobody plays
+    start_lucky_draw_round {{}} - replace the secret of a lucky draw round nobody has won for a long time
+    none {{}} - do nothing
+    reply with a single line of the form
+    ACTION: {{"action": "<action>", "reasoning": "<why you picked it>", ...arguments}}
+"#,
+        agent.name, agent.description
+    );
+    (agent.llm_config(), prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_whitelisted_actions_are_accepted() {
+        let reply = "thinking...\nACTION: {\"action\": \"adjust_difficulty\", \"reasoning\": \"nobody plays\", \"game\": \"riddle\", \"difficulty\": \"easy\"}";
+        assert_eq!(
+            parse_action(reply),
+            Ok((
+                AgentAction::AdjustDifficulty {
+                    game: GameKind::Riddle,
+                    difficulty: Difficulty::Easy
+                },
+                String::from("nobody plays")
+            ))
+        );
+        assert!(parse_action("ACTION: {\"action\": \"withdraw\"}").is_err());
+        assert!(parse_action("ACTION: {\"action\": \"announce\", \"message\": \"\"}").is_err());
+        assert!(parse_action("no action today").is_err());
+    }
+}
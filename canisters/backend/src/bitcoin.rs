@@ -1,49 +1,99 @@
+pub mod fee;
 pub mod runestone;
 pub mod signer;
 pub mod transaction;
 pub mod utils;
+pub mod wallet;
 
 // re export
 pub use transaction::transfer;
 
-use crate::bitcoin_lib::{Address, address::NetworkUnchecked};
+use crate::bitcoin_lib::{
+    Address, CompressedPublicKey, XOnlyPublicKey, address::NetworkUnchecked, key::TweakedPublicKey,
+};
+use candid::{CandidType, Deserialize};
 use icrc_ledger_types::icrc1::account::Account;
 
 use bitcoin::Network;
-use ic_cdk::api::management_canister::bitcoin::{
-    BitcoinNetwork as IcBitcoinNetwork, GetCurrentFeePercentilesRequest,
-    bitcoin_get_current_fee_percentiles,
-};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork as IcBitcoinNetwork;
 use utils::{account_to_derivation_path, derive_public_key, ripemd160, sha256};
 
+use crate::EcdsaPublicKey;
+
 use crate::state::read_config;
 
 pub const DUST_THRESHOLD: u64 = 1_000;
 pub const DEFAULT_POSTAGE: u64 = 10_000;
 
+/// Script type of the addresses derived for an account
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum AddressType {
+    #[default]
+    P2pkh, // legacy, signed with ecdsa in the script sig
+    P2wpkh, // segwit v0, signed with ecdsa over the BIP143 sighash
+    P2tr,   // taproot key path, signed with schnorr
+}
+
+pub fn network() -> Network {
+    read_config(|config| match config.bitcoin_network() {
+        IcBitcoinNetwork::Mainnet => Network::Bitcoin,
+        IcBitcoinNetwork::Testnet => Network::Testnet,
+        IcBitcoinNetwork::Regtest => Network::Regtest,
+    })
+}
+
 pub fn address_validation(addr: &str) -> Result<Address, String> {
-    read_config(|config| {
-        let bitcoin_network = match config.bitcoin_network() {
-            IcBitcoinNetwork::Mainnet => Network::Bitcoin,
-            IcBitcoinNetwork::Testnet => Network::Testnet,
-            IcBitcoinNetwork::Regtest => Network::Regtest,
-        };
-        let parsed_addr: Address<NetworkUnchecked> = match addr.parse() {
-            Err(_e) => return Err(String::from("failed to parse into bitcoin address")),
-            Ok(addr) => addr,
-        };
-        if !parsed_addr.is_valid_for_network(bitcoin_network) {
-            let msg = format!(
-                "Invalid Address.\n{} isn't valid for {:?} network",
-                addr, bitcoin_network
-            );
-            return Err(msg);
+    let bitcoin_network = network();
+    let parsed_addr: Address<NetworkUnchecked> = match addr.parse() {
+        Err(_e) => return Err(String::from("failed to parse into bitcoin address")),
+        Ok(addr) => addr,
+    };
+    if !parsed_addr.is_valid_for_network(bitcoin_network) {
+        let msg = format!(
+            "Invalid Address.\n{} isn't valid for {:?} network",
+            addr, bitcoin_network
+        );
+        return Err(msg);
+    }
+    match parsed_addr.require_network(bitcoin_network) {
+        Ok(addr) => Ok(addr),
+        Err(_) => Err(String::from("Failed to validate with network")),
+    }
+}
+
+pub fn account_to_address(account: &Account, address_type: AddressType) -> Address {
+    match address_type {
+        AddressType::P2pkh => address_validation(&account_to_p2pkh_address(account))
+            .expect("derived address should be valid"),
+        AddressType::P2wpkh => {
+            let public_key = read_config(|config| {
+                let path = account_to_derivation_path(account);
+                derive_public_key(&config.ecdsa_public_key(), &path).public_key
+            });
+            let public_key =
+                CompressedPublicKey::from_slice(&public_key).expect("should be compressed");
+            Address::p2wpkh(&public_key, network())
         }
-        match parsed_addr.require_network(bitcoin_network) {
-            Ok(addr) => Ok(addr),
-            Err(_) => Err(String::from("Failed to validate with network")),
+        AddressType::P2tr => {
+            let public_key = read_config(|config| {
+                let schnorr_public_key = config.schnorr_public_key();
+                let path = account_to_derivation_path(account);
+                let root = EcdsaPublicKey {
+                    public_key: schnorr_public_key.public_key,
+                    chain_code: schnorr_public_key.chain_code,
+                };
+                derive_public_key(&root, &path).public_key
+            });
+            let public_key =
+                XOnlyPublicKey::from_slice(&public_key[1..]).expect("should be a valid key");
+            // the key is used as the output key as is, so that the management canister's
+            // untweaked signatures are valid for the key path spend
+            Address::p2tr_tweaked(
+                TweakedPublicKey::dangerous_assume_tweaked(public_key),
+                network(),
+            )
         }
-    })
+    }
 }
 
 pub fn account_to_p2pkh_address(account: &Account) -> String {
@@ -63,23 +113,3 @@ pub fn account_to_p2pkh_address(account: &Account) -> String {
         bs58::encode(raw_address).into_string()
     })
 }
-
-pub async fn get_fee_per_vbyte() -> u64 {
-    let network = read_config(|config| config.bitcoin_network());
-    // Get fee percentiles from previous transactions to estimate our own fee.
-    let fee_percentiles =
-        bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest { network })
-            .await
-            .unwrap()
-            .0;
-
-    if fee_percentiles.is_empty() {
-        // There are no fee percentiles. This case can only happen on a regtest
-        // network where there are no non-coinbase transactions. In this case,
-        // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
-        2000
-    } else {
-        // Choose the 50th percentile for sending fees.
-        fee_percentiles[50]
-    }
-}
@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::bitcoin::{
+    GetCurrentFeePercentilesRequest, bitcoin_get_current_fee_percentiles,
+};
+
+use crate::state::{FeePolicy, read_config};
+
+// used when there are no fee percentiles. This can only happen on a regtest
+// network where there are no non-coinbase transactions
+const REGTEST_FEE_PER_VBYTE: u64 = 2_000;
+
+/// How fast a transaction should confirm, a faster tier picks a higher fee percentile
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum FeeTier {
+    Economy,
+    #[default]
+    Normal,
+    Fast,
+}
+
+/// Fee rate picked by the caller of an endpoint, either a tier or an explicit rate
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FeeRate {
+    Tier(FeeTier),
+    SatsPerVbyte(u64),
+}
+
+thread_local! {
+    // (fetched at, fee percentiles in millisatoshis/vbyte)
+    static PERCENTILES: RefCell<Option<(u64, Vec<u64>)>> = const { RefCell::new(None) };
+}
+
+/// fee rate in millisatoshis/vbyte for the tier, clamped to the policy's floor and ceiling
+pub fn rate_for(percentiles: &[u64], tier: FeeTier, policy: &FeePolicy) -> u64 {
+    let percentile = match tier {
+        FeeTier::Economy => policy.economy_percentile,
+        FeeTier::Normal => policy.normal_percentile,
+        FeeTier::Fast => policy.fast_percentile,
+    } as usize;
+    let rate = match percentiles.last() {
+        None => REGTEST_FEE_PER_VBYTE,
+        Some(&highest) => percentiles.get(percentile).copied().unwrap_or(highest),
+    };
+    policy.clamp(rate)
+}
+
+async fn fee_percentiles(ttl_secs: u64) -> Vec<u64> {
+    let now = ic_cdk::api::time();
+    let cached = PERCENTILES.with_borrow(|cache| {
+        cache
+            .as_ref()
+            .filter(|(fetched_at, _)| now.saturating_sub(*fetched_at) < ttl_secs * 1_000_000_000)
+            .map(|(_, percentiles)| percentiles.clone())
+    });
+    if let Some(percentiles) = cached {
+        return percentiles;
+    }
+    let network = read_config(|config| config.bitcoin_network());
+    let percentiles =
+        bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest { network })
+            .await
+            .unwrap()
+            .0;
+    PERCENTILES.with_borrow_mut(|cache| cache.replace((now, percentiles.clone())));
+    percentiles
+}
+
+/// fee rate in millisatoshis/vbyte, defaults to the normal tier
+pub async fn fee_per_vbyte(rate: Option<FeeRate>) -> u64 {
+    let policy = read_config(|config| config.fee_policy());
+    match rate.unwrap_or(FeeRate::Tier(FeeTier::default())) {
+        FeeRate::SatsPerVbyte(rate) => policy.clamp(rate.saturating_mul(1000)),
+        FeeRate::Tier(tier) => {
+            let percentiles = fee_percentiles(policy.cache_ttl_secs).await;
+            rate_for(&percentiles, tier, &policy)
+        }
+    }
+}
+
+/// drops the cached percentiles, e.g. after the policy changed
+pub fn clear_cache() {
+    PERCENTILES.with_borrow_mut(|cache| cache.take());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_are_clamped_to_the_policy() {
+        let policy = FeePolicy {
+            min_msat_per_vbyte: 1_500,
+            max_msat_per_vbyte: 80_000,
+            ..Default::default()
+        };
+        let percentiles = (0..=100).map(|p| p * 1_000).collect::<Vec<u64>>();
+        assert_eq!(rate_for(&percentiles, FeeTier::Economy, &policy), 25_000);
+        assert_eq!(rate_for(&percentiles, FeeTier::Normal, &policy), 50_000);
+        assert_eq!(rate_for(&percentiles, FeeTier::Fast, &policy), 75_000);
+
+        let low = vec![100; 101];
+        assert_eq!(rate_for(&low, FeeTier::Fast, &policy), 1_500);
+        let high = vec![500_000; 101];
+        assert_eq!(rate_for(&high, FeeTier::Economy, &policy), 80_000);
+        assert_eq!(rate_for(&[], FeeTier::Normal, &policy), 2_000);
+    }
+
+    #[test]
+    fn bad_policies_are_rejected() {
+        let inverted = FeePolicy {
+            min_msat_per_vbyte: 80_000,
+            max_msat_per_vbyte: 1_500,
+            ..Default::default()
+        };
+        assert!(inverted.validate().is_err());
+        assert_eq!(inverted.clamp(2_000), 1_500);
+        let percentile = FeePolicy {
+            fast_percentile: 101,
+            ..Default::default()
+        };
+        assert!(percentile.validate().is_err());
+        assert!(FeePolicy::default().validate().is_ok());
+    }
+}
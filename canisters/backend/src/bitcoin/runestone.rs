@@ -10,7 +10,7 @@ use ordinals::{Etching, SpacedRune};
 
 const DEFAULT_POSTAGE: u64 = 546;
 const TARGET_POSTAGE: Amount = Amount::from_sat(546);
-const MAX_STANDARD_OP_RETURN_SIZE: usize = 83;
+pub const MAX_STANDARD_OP_RETURN_SIZE: usize = 83;
 
 pub fn validate_etching(
     runename: &str,
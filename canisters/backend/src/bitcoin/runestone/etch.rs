@@ -1,27 +1,24 @@
 use crate::bitcoin_lib::{
-    Address, Amount, EcdsaSighashType, Network, OutPoint, PublicKey, Script, ScriptBuf, Sequence,
-    TapLeafHash, TapSighashType, Transaction, TxIn, TxOut, Witness, XOnlyPublicKey,
+    Address, Amount, Network, OutPoint, PublicKey, Script, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Witness, XOnlyPublicKey,
     absolute::LockTime,
-    hashes::Hash,
     key::{Secp256k1, constants::SCHNORR_SIGNATURE_SIZE},
     opcodes,
-    script::{Builder, PushBytesBuf},
-    secp256k1::schnorr,
-    sighash::{Prevouts, SighashCache},
+    script::Builder,
     taproot::{self, ControlBlock, LeafVersion, TaprootBuilder},
     transaction::Version,
 };
-use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Utxo};
-use icrc_ledger_types::icrc1::account::Account;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use ordinals::{Artifact, Etching, Runestone, SpacedRune};
 
 use crate::{
     bitcoin::{
         DUST_THRESHOLD,
-        signer::{ecdsa::ecdsa_sign, schnorr::schnorr_sign},
-        utils::{account_to_derivation_path, derive_public_key, sec1_to_der, slice_to_txid},
+        signer::{RevealInput, mock_signatures, sign_reveal_transaction, sign_transaction},
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
     },
-    state::{read_config, write_utxo_manager},
+    state::{read_config, utxo_manager::Operation, write_utxo_manager},
     txn_handler::TransactionType,
 };
 
@@ -86,8 +83,7 @@ pub struct EtchingArgs {
     pub divisibility: u8,
     pub symbol: Option<char>,
     pub turbo: bool,
-    pub fee_payer: Address,
-    pub fee_payer_account: Account,
+    pub fee_payer: Wallet,
     pub fee_per_vbytes: u64,
 }
 
@@ -106,7 +102,6 @@ pub async fn etch(
         symbol,
         turbo,
         fee_payer,
-        fee_payer_account,
         fee_per_vbytes,
     }: EtchingArgs,
 ) -> Result<(TransactionType, (String, String)), u64> {
@@ -213,40 +208,11 @@ pub async fn etch(
     ic_cdk::println!("commit txn before signing");
     ic_cdk::println!("{}", hex::encode(txn_bytes));
 
-    let (path, pubkey) = read_config(|config| {
-        let ecdsa_pubkey = config.ecdsa_public_key();
-        let path = account_to_derivation_path(&fee_payer_account);
-        let pubkey = derive_public_key(&ecdsa_pubkey, &path).public_key;
-        (
-            path.iter().map(|x| x.to_vec()).collect::<Vec<Vec<u8>>>(),
-            pubkey,
-        )
-    });
-
-    let txn_cache = SighashCache::new(commit_txn.clone());
-
-    for (index, input) in commit_txn.input.iter_mut().enumerate() {
-        let sighash = txn_cache
-            .legacy_signature_hash(
-                index,
-                &fee_payer.script_pubkey(),
-                EcdsaSighashType::All.to_u32(),
-            )
-            .unwrap();
-
-        let signature = ecdsa_sign(sighash.as_byte_array().to_vec(), path.clone())
-            .await
-            .signature;
-        let mut signature = sec1_to_der(signature);
-        signature.push(EcdsaSighashType::All.to_u32() as u8);
-        let signature = PushBytesBuf::try_from(signature).unwrap();
-        let pubkey = PushBytesBuf::try_from(pubkey.clone()).unwrap();
-        input.script_sig = Builder::new()
-            .push_slice(signature)
-            .push_slice(pubkey)
-            .into_script();
-        input.witness.clear();
-    }
+    let signers = utxos
+        .iter()
+        .map(|utxo| fee_payer.signer_for(utxo))
+        .collect::<Vec<_>>();
+    sign_transaction(&mut commit_txn, &signers).await;
 
     reveal_input[commit_input_index] = OutPoint {
         txid: commit_txn.compute_txid(),
@@ -268,34 +234,12 @@ pub async fn etch(
         }
     }
 
-    let mut sighashcache = SighashCache::new(&mut reveal_txn);
-    let sighash = sighashcache
-        .taproot_script_spend_signature_hash(
-            commit_input_index,
-            &Prevouts::All(&[commit_txn.output[vout].clone()]),
-            TapLeafHash::from_script(&reveal_script, LeafVersion::TapScript),
-            TapSighashType::Default,
-        )
-        .expect("signature hash should compute");
-
-    let sig = schnorr_sign(sighash.as_byte_array().to_vec(), vec![])
-        .await
-        .signature;
-
-    let witness = sighashcache
-        .witness_mut(commit_input_index)
-        .expect("getting mutable reference should work");
-    witness.push(
-        taproot::Signature {
-            signature: schnorr::Signature::from_slice(sig.as_slice())
-                .expect("should parse signature"),
-            sighash_type: TapSighashType::Default,
-        }
-        .to_vec(),
-    );
-
-    witness.push(reveal_script);
-    witness.push(control_block.serialize());
+    let reveal_input = RevealInput {
+        commit_output: commit_txn.output[vout].clone(),
+        reveal_script,
+        control_block,
+    };
+    sign_reveal_transaction(&mut reveal_txn, &reveal_input, &[]).await;
 
     if Runestone::decipher(&reveal_txn).unwrap() != Artifact::Runestone(runestone) {
         ic_cdk::trap("Transaction doesn't contain runestone")
@@ -316,12 +260,11 @@ pub async fn etch(
 }
 
 fn build_commit_transaction_with_fee(
-    fee_payer: &Address,
+    fee_payer: &Wallet,
     recipient: ScriptBuf,
     fee_per_vbytes: u64,
     target: Amount,
-) -> Result<(Transaction, Vec<Utxo>), u64> {
-    let payer = &fee_payer.to_string();
+) -> Result<(Transaction, Vec<WalletUtxo>), u64> {
     if !recipient.is_op_return() {
         let dust_value = recipient.minimal_non_dust();
 
@@ -329,6 +272,7 @@ fn build_commit_transaction_with_fee(
             ic_cdk::trap("DUST VALUE")
         }
     }
+    let operation = Operation::start();
     let mut total_fee = 0;
     loop {
         let (mut input, mut output) = (vec![], vec![]);
@@ -336,21 +280,21 @@ fn build_commit_transaction_with_fee(
         let (utxos_to_spend, total_spent) = write_utxo_manager(|manager| {
             let mut utxos = vec![];
             let mut total_spent = 0;
-            while let Some(utxo) = manager.get_bitcoin_utxo(payer) {
-                total_spent += utxo.value;
+            while let Some(utxo) = fee_payer.take_bitcoin_utxo(manager, operation) {
+                total_spent += utxo.utxo.value;
                 utxos.push(utxo);
                 if total_spent >= target.to_sat() + total_fee {
                     break;
                 }
             }
             if total_spent < target.to_sat() + total_fee {
-                manager.record_bitcoin_utxos(payer, utxos);
+                fee_payer.release(manager, utxos);
                 return Err(target.to_sat() + total_fee);
             }
             Ok((utxos, total_spent))
         })?;
 
-        utxos_to_spend.iter().for_each(|utxo| {
+        utxos_to_spend.iter().for_each(|WalletUtxo { utxo, .. }| {
             let txin = TxIn {
                 previous_output: OutPoint {
                     txid: slice_to_txid(&utxo.outpoint.txid),
@@ -358,7 +302,7 @@ fn build_commit_transaction_with_fee(
                 },
                 script_sig: ScriptBuf::new(),
                 sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-                witness: Witness::from_slice(&[&[0; SCHNORR_SIGNATURE_SIZE]]),
+                witness: Witness::new(),
             };
             input.push(txin);
         });
@@ -373,7 +317,7 @@ fn build_commit_transaction_with_fee(
         if remaining >= DUST_THRESHOLD {
             output.push(TxOut {
                 value: Amount::from_sat(remaining),
-                script_pubkey: fee_payer.script_pubkey(),
+                script_pubkey: fee_payer.address().script_pubkey(),
             });
         }
 
@@ -384,13 +328,17 @@ fn build_commit_transaction_with_fee(
             lock_time: LockTime::ZERO,
         };
 
-        let txn_vsize = txn.vsize() as u64;
+        let address_types = utxos_to_spend
+            .iter()
+            .map(|utxo| utxo.address_type)
+            .collect::<Vec<_>>();
+        let txn_vsize = mock_signatures(&txn, &address_types).vsize() as u64;
 
         if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
             return Ok((txn, utxos_to_spend));
         } else {
             write_utxo_manager(|manager| {
-                manager.record_bitcoin_utxos(payer, utxos_to_spend);
+                fee_payer.release(manager, utxos_to_spend);
             });
             total_fee = (txn_vsize * fee_per_vbytes) / 1000;
         }
@@ -2,27 +2,29 @@ use crate::bitcoin_lib::{
     Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
     absolute::LockTime, transaction::Version,
 };
-use ic_cdk::api::management_canister::bitcoin::Utxo;
-use icrc_ledger_types::icrc1::account::Account;
 use ordinals::{Edict, Runestone};
 
 use crate::{
     bitcoin::{
-        DEFAULT_POSTAGE, DUST_THRESHOLD, signer::ecdsa::mock_ecdsa_signature, utils::slice_to_txid,
+        DEFAULT_POSTAGE, DUST_THRESHOLD,
+        signer::mock_signatures,
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
     },
     indexer::RuneId,
-    state::{utxo_manager::RunicUtxo, write_utxo_manager},
+    state::{
+        utxo_manager::{Operation, RunicUtxo},
+        write_utxo_manager,
+    },
     txn_handler::TransactionType,
 };
 
 pub struct RuneTransferArgs {
     pub runeid: RuneId,
     pub rune_amount: u128,
-    pub rune_sender: Address,
+    pub rune_sender: Wallet,
     pub rune_receiver: Address,
-    pub rune_sender_account: Account,
-    pub fee_payer: Address,
-    pub fee_payer_account: Account,
+    pub fee_payer: Wallet,
     pub postage: Option<u64>,
     pub fee_per_vbytes: u64,
 }
@@ -33,13 +35,12 @@ pub fn transfer(
         rune_amount,
         rune_sender,
         rune_receiver,
-        rune_sender_account,
         fee_payer,
-        fee_payer_account,
         postage,
         fee_per_vbytes,
     }: RuneTransferArgs,
 ) -> Result<TransactionType, (u128, u64)> {
+    let operation = Operation::start();
     let mut total_fee = 0;
     let postage = Amount::from_sat(postage.unwrap_or(DEFAULT_POSTAGE));
     loop {
@@ -51,8 +52,14 @@ pub fn transfer(
             &fee_payer,
             postage,
             total_fee,
+            operation,
         )?;
-        let signed_txn = mock_ecdsa_signature(&txn);
+        let address_types = runic_utxos
+            .iter()
+            .map(|_| rune_sender.preferred())
+            .chain(fee_utxos.iter().map(|utxo| utxo.address_type))
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signatures(&txn, &address_types);
         let txn_vsize = signed_txn.vsize() as u64;
         if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
             return Ok(TransactionType::Rune {
@@ -61,17 +68,19 @@ pub fn transfer(
                 rune_amount,
                 rune_sender: Box::new(rune_sender),
                 rune_receiver: Box::new(rune_receiver),
-                rune_sender_account,
                 fee_utxos,
                 fee: total_fee,
                 fee_payer: Box::new(fee_payer),
-                fee_payer_account,
                 postage,
             });
         } else {
             write_utxo_manager(|manager| {
-                manager.record_runic_utxos(rune_sender.to_string().as_str(), runeid, runic_utxos);
-                manager.record_bitcoin_utxos(fee_payer.to_string().as_str(), fee_utxos)
+                manager.release_runic_utxos(
+                    rune_sender.address().to_string().as_str(),
+                    runeid,
+                    runic_utxos,
+                );
+                fee_payer.release(manager, fee_utxos)
             });
             total_fee = (txn_vsize * fee_per_vbytes) / 1000;
         }
@@ -81,20 +90,22 @@ pub fn transfer(
 fn build_transaction_with_fee(
     runeid: &RuneId,
     rune_amount: u128,
-    rune_sender: &Address,
+    rune_sender: &Wallet,
     rune_receiver: &Address,
-    fee_payer: &Address,
+    fee_payer: &Wallet,
     postage: Amount,
     fee: u64,
-) -> Result<(Transaction, Vec<RunicUtxo>, Vec<Utxo>), (u128, u64)> {
+    operation: Operation,
+) -> Result<(Transaction, Vec<RunicUtxo>, Vec<WalletUtxo>), (u128, u64)> {
     let mut input = vec![];
     let mut output = vec![];
     let (runic_utxos, runic_total_spent, bitcoin_spent_in_runic) = write_utxo_manager(|manager| {
-        let addr = rune_sender.to_string();
+        // runes are only held on the preferred address
+        let addr = rune_sender.address().to_string();
         let mut utxos = vec![];
         let mut runic_total_spent = 0;
         let mut bitcoin_spent_in_runic = 0;
-        while let Some(utxo) = manager.get_runic_utxo(&addr, *runeid) {
+        while let Some(utxo) = manager.get_runic_utxo(&addr, *runeid, operation) {
             runic_total_spent += utxo.balance;
             bitcoin_spent_in_runic += utxo.utxo.value;
             utxos.push(utxo);
@@ -103,7 +114,7 @@ fn build_transaction_with_fee(
             }
         }
         if runic_total_spent < rune_amount {
-            manager.record_runic_utxos(&addr, *runeid, utxos);
+            manager.release_runic_utxos(&addr, *runeid, utxos);
             return Err((rune_amount, fee));
         }
         Ok((utxos, runic_total_spent, bitcoin_spent_in_runic))
@@ -117,7 +128,7 @@ fn build_transaction_with_fee(
                     txid: slice_to_txid(&utxo.outpoint.txid),
                     vout: utxo.outpoint.vout,
                 },
-                sequence: Sequence::MAX,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                 script_sig: ScriptBuf::new(),
                 witness: Witness::new(),
             };
@@ -145,7 +156,7 @@ fn build_transaction_with_fee(
         });
         output.push(TxOut {
             value: postage,
-            script_pubkey: rune_sender.script_pubkey(),
+            script_pubkey: rune_sender.address().script_pubkey(),
         });
         output.push(TxOut {
             value: postage,
@@ -167,11 +178,10 @@ fn build_transaction_with_fee(
     let required_bitcoin_amount = fee + required_postage_bitcoin_amount;
 
     let (fee_utxos, fee_total_spent) = write_utxo_manager(|manager| {
-        let addr = fee_payer.to_string();
         let mut utxos = vec![];
         let mut fee_total_spent = 0;
-        while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-            fee_total_spent += utxo.value;
+        while let Some(utxo) = fee_payer.take_bitcoin_utxo(manager, operation) {
+            fee_total_spent += utxo.utxo.value;
             utxos.push(utxo);
             if fee_total_spent > required_bitcoin_amount {
                 break;
@@ -180,13 +190,13 @@ fn build_transaction_with_fee(
         Ok((utxos, fee_total_spent))
     })?;
 
-    fee_utxos.iter().for_each(|utxo| {
+    fee_utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
         let txin = TxIn {
             previous_output: OutPoint {
                 txid: slice_to_txid(&utxo.outpoint.txid),
                 vout: utxo.outpoint.vout,
             },
-            sequence: Sequence::MAX,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
             script_sig: ScriptBuf::new(),
             witness: Witness::new(),
         };
@@ -198,7 +208,7 @@ fn build_transaction_with_fee(
     if remaining >= DUST_THRESHOLD {
         output.push(TxOut {
             value: Amount::from_sat(remaining),
-            script_pubkey: fee_payer.script_pubkey(),
+            script_pubkey: fee_payer.address().script_pubkey(),
         });
     }
 
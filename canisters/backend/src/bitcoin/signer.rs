@@ -1,2 +1,206 @@
 pub mod ecdsa;
 pub mod schnorr;
+
+use crate::bitcoin_lib::{
+    Address, Amount, EcdsaSighashType, ScriptBuf, TapLeafHash, TapSighashType, Transaction, TxOut,
+    Witness,
+    hashes::Hash,
+    script::{Builder, PushBytesBuf},
+    sighash::{Prevouts, SighashCache},
+    taproot::{ControlBlock, LeafVersion},
+};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::state::read_config;
+
+use super::{
+    AddressType,
+    utils::{account_to_derivation_path, derive_public_key, sec1_to_der},
+};
+
+/// The output spent by an input and the account holding its key
+#[derive(Clone)]
+pub struct InputSigner {
+    pub account: Account,
+    pub address_type: AddressType,
+    pub address: Address,
+    pub value: u64,
+}
+
+/// fills every input with a signature of the size it will have, for estimating the vsize
+pub fn mock_signatures(txn: &Transaction, address_types: &[AddressType]) -> Transaction {
+    let mut txn = txn.clone();
+    for (input, address_type) in txn.input.iter_mut().zip(address_types) {
+        match address_type {
+            AddressType::P2pkh => {
+                input.script_sig = ecdsa::mock_ecdsa_script_sig();
+                input.witness.clear();
+            }
+            AddressType::P2wpkh => {
+                input.script_sig = ScriptBuf::new();
+                input.witness = ecdsa::mock_ecdsa_witness();
+            }
+            AddressType::P2tr => {
+                input.script_sig = ScriptBuf::new();
+                input.witness = Witness::from_slice(&[schnorr::mock_schnorr_signature()]);
+            }
+        }
+    }
+    txn
+}
+
+/// The commit output of an etching, spent through the script holding the inscription
+pub struct RevealInput {
+    pub commit_output: TxOut,
+    pub reveal_script: ScriptBuf,
+    pub control_block: ControlBlock,
+}
+
+impl RevealInput {
+    /// recovers the reveal script and control block from the witness of a signed reveal
+    pub fn from_witness(commit_output: TxOut, witness: &Witness) -> Option<Self> {
+        let reveal_script = ScriptBuf::from_bytes(witness.nth(1)?.to_vec());
+        let control_block = ControlBlock::decode(witness.nth(2)?).ok()?;
+        Some(Self {
+            commit_output,
+            reveal_script,
+            control_block,
+        })
+    }
+}
+
+/// signs the inputs in order, `signers` has an entry for every input of the transaction
+pub async fn sign_transaction(txn: &mut Transaction, signers: &[InputSigner]) {
+    sign_inputs(txn, None, signers).await
+}
+
+/// signs a reveal, its first input spends the commit output and `signers` cover the rest
+pub async fn sign_reveal_transaction(
+    txn: &mut Transaction,
+    reveal: &RevealInput,
+    signers: &[InputSigner],
+) {
+    sign_inputs(txn, Some(reveal), signers).await
+}
+
+async fn sign_inputs(txn: &mut Transaction, reveal: Option<&RevealInput>, signers: &[InputSigner]) {
+    // taproot sighashes commit to every output being spent
+    let prevouts = reveal
+        .map(|reveal| reveal.commit_output.clone())
+        .into_iter()
+        .chain(signers.iter().map(|signer| TxOut {
+            value: Amount::from_sat(signer.value),
+            script_pubkey: signer.address.script_pubkey(),
+        }))
+        .collect::<Vec<_>>();
+    let mut txn_cache = SighashCache::new(txn.clone());
+
+    if let Some(reveal) = reveal {
+        let sighash = txn_cache
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                TapLeafHash::from_script(&reveal.reveal_script, LeafVersion::TapScript),
+                TapSighashType::Default,
+            )
+            .expect("signature hash should compute");
+        // the reveal script is locked to the canister's root schnorr key
+        let signature = schnorr::schnorr_sign(sighash.as_byte_array().to_vec(), vec![])
+            .await
+            .signature;
+        txn.input[0].witness = Witness::from_slice(&[
+            signature,
+            reveal.reveal_script.to_bytes(),
+            reveal.control_block.serialize(),
+        ]);
+    }
+
+    let offset = usize::from(reveal.is_some());
+    for ((index, input), signer) in txn.input.iter_mut().enumerate().skip(offset).zip(signers) {
+        let path = account_to_derivation_path(&signer.account);
+        let script_pubkey = signer.address.script_pubkey();
+        match signer.address_type {
+            AddressType::P2pkh | AddressType::P2wpkh => {
+                let pubkey = read_config(|config| {
+                    derive_public_key(&config.ecdsa_public_key(), &path).public_key
+                });
+                let sighash = if signer.address_type == AddressType::P2pkh {
+                    txn_cache
+                        .legacy_signature_hash(
+                            index,
+                            &script_pubkey,
+                            EcdsaSighashType::All.to_u32(),
+                        )
+                        .unwrap()
+                        .to_byte_array()
+                } else {
+                    txn_cache
+                        .p2wpkh_signature_hash(
+                            index,
+                            &script_pubkey,
+                            Amount::from_sat(signer.value),
+                            EcdsaSighashType::All,
+                        )
+                        .unwrap()
+                        .to_byte_array()
+                };
+                let path = path.iter().map(|x| x.to_vec()).collect::<Vec<Vec<u8>>>();
+                let signature = ecdsa::ecdsa_sign(sighash.to_vec(), path).await.signature;
+                let mut signature = sec1_to_der(signature);
+                signature.push(EcdsaSighashType::All.to_u32() as u8);
+                if signer.address_type == AddressType::P2pkh {
+                    let signature = PushBytesBuf::try_from(signature).unwrap();
+                    let pubkey = PushBytesBuf::try_from(pubkey).unwrap();
+                    input.script_sig = Builder::new()
+                        .push_slice(signature)
+                        .push_slice(pubkey)
+                        .into_script();
+                    input.witness.clear();
+                } else {
+                    input.script_sig = ScriptBuf::new();
+                    input.witness = Witness::from_slice(&[signature, pubkey]);
+                }
+            }
+            AddressType::P2tr => {
+                let sighash = txn_cache
+                    .taproot_key_spend_signature_hash(
+                        index,
+                        &Prevouts::All(&prevouts),
+                        TapSighashType::Default,
+                    )
+                    .unwrap();
+                let path = path.iter().map(|x| x.to_vec()).collect::<Vec<Vec<u8>>>();
+                // signatures with the default sighash type are pushed without the type byte
+                let signature = schnorr::schnorr_sign(sighash.as_byte_array().to_vec(), path)
+                    .await
+                    .signature;
+                input.script_sig = ScriptBuf::new();
+                input.witness = Witness::from_slice(&[signature]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin_lib::{OutPoint, Sequence, TxIn, absolute::LockTime, transaction::Version};
+
+    #[test]
+    fn segwit_and_taproot_inputs_are_cheaper() {
+        let txn = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+        };
+        let vsize = |address_type| mock_signatures(&txn, &[address_type]).vsize();
+        assert!(vsize(AddressType::P2tr) < vsize(AddressType::P2wpkh));
+        assert!(vsize(AddressType::P2wpkh) < vsize(AddressType::P2pkh));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::bitcoin_lib::{
-    Sequence, Transaction, TxIn, Witness,
+    ScriptBuf, Witness,
+    key::constants::PUBLIC_KEY_SIZE,
     script::{Builder, PushBytesBuf},
     sighash::EcdsaSighashType,
 };
@@ -11,38 +12,25 @@ use crate::state::read_config;
 
 use crate::bitcoin::utils::*;
 
-pub fn mock_ecdsa_signature(txn: &Transaction) -> Transaction {
-    let pubkey = read_config(|config| {
-        let ecdsa_key = config.ecdsa_public_key();
-        let path = vec![];
-        derive_public_key(&ecdsa_key, &path).public_key
-    });
-    let input = txn
-        .input
-        .iter()
-        .map(|input| {
-            let signature = vec![255; 64];
-            let mut der_signature = sec1_to_der(signature);
-            der_signature.push(EcdsaSighashType::All.to_u32() as u8);
-            let signature_as_pushbytes = PushBytesBuf::try_from(der_signature).unwrap();
-            let publickey_as_pushbytes = PushBytesBuf::try_from(pubkey.clone()).unwrap();
-            TxIn {
-                previous_output: input.previous_output,
-                witness: Witness::new(),
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-                script_sig: Builder::new()
-                    .push_slice(signature_as_pushbytes)
-                    .push_slice(publickey_as_pushbytes)
-                    .into_script(),
-            }
-        })
-        .collect::<Vec<TxIn>>();
-    Transaction {
-        input,
-        output: txn.output.clone(),
-        version: txn.version,
-        lock_time: txn.lock_time,
-    }
+// largest der encoded signature along with the sighash type
+fn mock_ecdsa_signature() -> Vec<u8> {
+    let signature = vec![255; 64];
+    let mut der_signature = sec1_to_der(signature);
+    der_signature.push(EcdsaSighashType::All.to_u32() as u8);
+    der_signature
+}
+
+pub fn mock_ecdsa_script_sig() -> ScriptBuf {
+    let signature_as_pushbytes = PushBytesBuf::try_from(mock_ecdsa_signature()).unwrap();
+    let publickey_as_pushbytes = PushBytesBuf::try_from(vec![2; PUBLIC_KEY_SIZE]).unwrap();
+    Builder::new()
+        .push_slice(signature_as_pushbytes)
+        .push_slice(publickey_as_pushbytes)
+        .into_script()
+}
+
+pub fn mock_ecdsa_witness() -> Witness {
+    Witness::from_slice(&[mock_ecdsa_signature(), vec![2; PUBLIC_KEY_SIZE]])
 }
 
 pub async fn ecdsa_sign(
@@ -1,10 +1,13 @@
+use crate::bitcoin_lib::key::constants::SCHNORR_SIGNATURE_SIZE;
 use ic_cdk::api::management_canister::schnorr::{
     SignWithSchnorrArgument, SignWithSchnorrResponse, sign_with_schnorr,
 };
 
 use crate::state::read_config;
 
-pub fn mock_schnorr_signature() {}
+pub fn mock_schnorr_signature() -> Vec<u8> {
+    vec![0; SCHNORR_SIGNATURE_SIZE]
+}
 
 pub async fn schnorr_sign(
     message: Vec<u8>,
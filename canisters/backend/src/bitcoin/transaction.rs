@@ -1,20 +1,27 @@
+pub mod batch;
 pub mod combined;
+pub mod cpfp;
 
 use crate::bitcoin_lib::{
     Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
     absolute::LockTime, transaction::Version,
 };
-use ic_cdk::api::management_canister::bitcoin::Utxo;
-use icrc_ledger_types::icrc1::account::Account;
 
-use crate::{state::write_utxo_manager, txn_handler::TransactionType};
+use crate::{
+    state::{utxo_manager::Operation, write_utxo_manager},
+    txn_handler::TransactionType,
+};
 
-use super::{DUST_THRESHOLD, signer::ecdsa::mock_ecdsa_signature, utils::slice_to_txid};
+use super::{
+    DUST_THRESHOLD,
+    signer::mock_signatures,
+    utils::slice_to_txid,
+    wallet::{Wallet, WalletUtxo},
+};
 
 pub struct BtcTransferArgs {
-    pub sender: Address,
+    pub sender: Wallet,
     pub receiver: Address,
-    pub sender_account: Account,
     pub amount: u64,
     pub paid_by_sender: bool,
     pub fee_per_vbytes: u64,
@@ -24,68 +31,71 @@ pub fn transfer(
     BtcTransferArgs {
         sender,
         receiver,
-        sender_account,
         amount,
         paid_by_sender,
         fee_per_vbytes,
     }: BtcTransferArgs,
 ) -> Result<TransactionType, u64> {
+    let operation = Operation::start();
     let mut total_fee = 0;
     loop {
-        let (txn, utxos) =
-            build_transaction_with_fee(&sender, &receiver, amount, paid_by_sender, total_fee)?;
-        let signed_txn = mock_ecdsa_signature(&txn);
+        let (txn, utxos) = build_transaction_with_fee(
+            &sender,
+            &receiver,
+            amount,
+            paid_by_sender,
+            total_fee,
+            operation,
+        )?;
+        let address_types = utxos
+            .iter()
+            .map(|utxo| utxo.address_type)
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signatures(&txn, &address_types);
         let txn_vsize = signed_txn.vsize() as u64;
         if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
-            return Ok(TransactionType::Bitcoin {
-                utxos,
-                txn,
-                sender,
-                sender_account,
-            });
+            return Ok(TransactionType::Bitcoin { utxos, txn, sender });
         } else {
-            write_utxo_manager(|manager| {
-                manager.record_bitcoin_utxos(sender.to_string().as_str(), utxos)
-            });
+            write_utxo_manager(|manager| sender.release(manager, utxos));
             total_fee = (txn_vsize * fee_per_vbytes) / 1000;
         }
     }
 }
 
 fn build_transaction_with_fee(
-    sender: &Address,
+    sender: &Wallet,
     receiver: &Address,
     amount: u64,
     paid_by_sender: bool,
     fee: u64,
-) -> Result<(Transaction, Vec<Utxo>), u64> {
+    operation: Operation,
+) -> Result<(Transaction, Vec<WalletUtxo>), u64> {
     let (mut input, mut output) = (vec![], vec![]);
     let required_amount = if paid_by_sender { amount + fee } else { amount };
     let (utxos, total_spent) = write_utxo_manager(|manager| {
-        let addr = sender.to_string();
         let mut utxos = vec![];
         let mut total_spent = 0;
-        while let Some(utxo) = manager.get_bitcoin_utxo(&addr) {
-            total_spent += utxo.value;
+        while let Some(utxo) = sender.take_bitcoin_utxo(manager, operation) {
+            total_spent += utxo.utxo.value;
             utxos.push(utxo);
             if total_spent > required_amount {
                 break;
             }
         }
         if total_spent < required_amount {
-            manager.record_bitcoin_utxos(&addr, utxos);
+            sender.release(manager, utxos);
             return Err(required_amount);
         }
         Ok((utxos, total_spent))
     })?;
 
-    utxos.iter().for_each(|utxo| {
+    utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
         let txin = TxIn {
             previous_output: OutPoint {
                 txid: slice_to_txid(&utxo.outpoint.txid),
                 vout: utxo.outpoint.vout,
             },
-            sequence: Sequence::MAX,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
             script_sig: ScriptBuf::new(),
             witness: Witness::new(),
         };
@@ -104,7 +114,7 @@ fn build_transaction_with_fee(
     if remaining >= DUST_THRESHOLD {
         output.push(TxOut {
             value: Amount::from_sat(remaining),
-            script_pubkey: sender.script_pubkey(),
+            script_pubkey: sender.address().script_pubkey(),
         });
     }
 
@@ -0,0 +1,402 @@
+use crate::bitcoin_lib::{
+    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+    absolute::LockTime, transaction::Version,
+};
+use ordinals::{Edict, Runestone};
+
+use crate::{
+    bitcoin::{
+        DEFAULT_POSTAGE, DUST_THRESHOLD,
+        runestone::MAX_STANDARD_OP_RETURN_SIZE,
+        signer::mock_signatures,
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
+    },
+    indexer::RuneId,
+    state::{
+        utxo_manager::{Operation, RunicUtxo, UtxoManager},
+        write_utxo_manager,
+    },
+    txn_handler::TransactionType,
+};
+
+/// One trader's part of an agent's settlement
+#[derive(Clone)]
+pub struct BatchLeg {
+    pub trader: Wallet,                    // deposit wallet of the trader
+    pub bitcoin_in: u64,                   // satoshis owed to the agent by the deposit
+    pub bitcoin_out: u64,                  // satoshis owed to the deposit by the agent
+    pub rune_out: Option<(u128, Address)>, // runes delivered to the address
+    pub free_bitcoin: u64,                 // satoshis of the deposit free for fee and postage
+}
+
+impl BatchLeg {
+    fn net_in(&self) -> u64 {
+        self.bitcoin_in.saturating_sub(self.bitcoin_out)
+    }
+
+    fn net_out(&self) -> u64 {
+        self.bitcoin_out.saturating_sub(self.bitcoin_in)
+    }
+}
+
+pub struct SettlementBatchArgs {
+    pub runeid: Option<RuneId>,
+    pub agent: Wallet,
+    pub legs: Vec<BatchLeg>,
+    pub fee_per_vbytes: u64,
+}
+
+/// utxos reserved for a batch, `deposits` follows the order of the legs
+pub struct BatchUtxos {
+    pub runic: Vec<RunicUtxo>,
+    pub agent: Vec<WalletUtxo>,
+    pub deposits: Vec<(Wallet, Vec<WalletUtxo>)>,
+}
+
+impl BatchUtxos {
+    pub fn release(self, manager: &mut UtxoManager, runeid: Option<RuneId>, agent: &Wallet) {
+        if let Some(runeid) = runeid {
+            manager.release_runic_utxos(&agent.address().to_string(), runeid, self.runic);
+        }
+        agent.release(manager, self.agent);
+        for (trader, utxos) in self.deposits {
+            trader.release(manager, utxos);
+        }
+    }
+}
+
+/// share of the network fee paid by each leg, the first one pays the remainder
+pub fn fee_shares(fee: u64, legs: usize) -> Vec<u64> {
+    let legs = legs as u64;
+    (0..legs)
+        .map(|index| fee / legs + if index == 0 { fee % legs } else { 0 })
+        .collect()
+}
+
+/*
+ * returns (satoshis the deposit pays in, payout to the deposit) for a leg owing `debit`
+ * the debit comes out of the payout when it's large enough, otherwise out of the deposit
+*/
+fn leg_flows(net_in: u64, net_out: u64, debit: u64) -> (u64, u64) {
+    if net_out >= debit + DUST_THRESHOLD {
+        (0, net_out - debit)
+    } else {
+        (net_in + debit, net_out)
+    }
+}
+
+/*
+ * satoshis going back to the deposit in a single output, its payout along with its change
+ * none when they'd be dust, the change is the trader's own and can't go to the network fee
+*/
+fn returned_to_deposit(spent: u64, paid_in: u64, payout: u64) -> Option<u64> {
+    let returned = payout + spent - paid_in;
+    (returned == 0 || returned >= DUST_THRESHOLD).then_some(returned)
+}
+
+/*
+ * settles the legs of an agent in a single transaction
+ * every leg owes an even share of the network fee plus the postage of its rune delivery
+ * the agent's address receives what the deposits owe it and pays out what it owes
+ * errors carry the leg that can't be settled, if any, so that it can wait for a later batch
+*/
+pub fn transfer(
+    SettlementBatchArgs {
+        runeid,
+        agent,
+        legs,
+        fee_per_vbytes,
+    }: SettlementBatchArgs,
+) -> Result<TransactionType, (Option<usize>, String)> {
+    let operation = Operation::start();
+    let mut total_fee = 0;
+    loop {
+        let (txn, utxos, change) =
+            build_transaction_with_fee(runeid, &agent, &legs, total_fee, operation)?;
+        let address_types = utxos
+            .runic
+            .iter()
+            .map(|_| agent.preferred())
+            .chain(utxos.agent.iter().map(|utxo| utxo.address_type))
+            .chain(
+                utxos
+                    .deposits
+                    .iter()
+                    .flat_map(|(_, utxos)| utxos.iter().map(|utxo| utxo.address_type)),
+            )
+            .collect::<Vec<_>>();
+        let txn_vsize = mock_signatures(&txn, &address_types).vsize() as u64;
+        if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
+            return Ok(TransactionType::Batch {
+                txn,
+                runeid,
+                agent: Box::new(agent),
+                utxos,
+                fee: total_fee,
+                change,
+            });
+        } else {
+            write_utxo_manager(|manager| manager.release_operation(operation.id));
+            total_fee = (txn_vsize * fee_per_vbytes) / 1000;
+        }
+    }
+}
+
+fn build_transaction_with_fee(
+    runeid: Option<RuneId>,
+    agent: &Wallet,
+    legs: &[BatchLeg],
+    fee: u64,
+    operation: Operation,
+) -> Result<(Transaction, BatchUtxos, usize), (Option<usize>, String)> {
+    let rune_total = legs
+        .iter()
+        .filter_map(|leg| leg.rune_out.as_ref())
+        .fold(0, |total, (amount, _)| total + amount);
+    let shares = fee_shares(fee, legs.len());
+
+    let (utxos, flows, need_change_rune_output, surplus) = write_utxo_manager(|manager| {
+        let mut utxos = BatchUtxos {
+            runic: vec![],
+            agent: vec![],
+            deposits: vec![],
+        };
+
+        // runes are only held on the agent's preferred address
+        let mut runic_total = 0;
+        let mut runic_bitcoin = 0;
+        if let Some(runeid) = runeid.filter(|_| rune_total > 0) {
+            let addr = agent.address().to_string();
+            while let Some(utxo) = manager.get_runic_utxo(&addr, runeid, operation) {
+                runic_total += utxo.balance;
+                runic_bitcoin += utxo.utxo.value;
+                utxos.runic.push(utxo);
+                if runic_total >= rune_total {
+                    break;
+                }
+            }
+            if runic_total < rune_total {
+                manager.release_operation(operation.id);
+                return Err((None, format!("not enough runes. required: {rune_total}")));
+            }
+        }
+
+        // (deposit spent, paid in, payout) of every leg
+        let mut flows = vec![];
+        for (index, (leg, share)) in legs.iter().zip(shares.iter()).enumerate() {
+            let postage = if leg.rune_out.is_some() {
+                DEFAULT_POSTAGE
+            } else {
+                0
+            };
+            let (paid_in, payout) = leg_flows(leg.net_in(), leg.net_out(), share + postage);
+            if paid_in - leg.net_in() > leg.free_bitcoin {
+                manager.release_operation(operation.id);
+                return Err((
+                    Some(index),
+                    String::from("not enough balance for paying the network fee"),
+                ));
+            }
+            let mut deposit = vec![];
+            let mut spent = 0;
+            if paid_in > 0 {
+                while let Some(utxo) = leg.trader.take_bitcoin_utxo(manager, operation) {
+                    spent += utxo.utxo.value;
+                    deposit.push(utxo);
+                    if spent >= paid_in && returned_to_deposit(spent, paid_in, payout).is_some() {
+                        break;
+                    }
+                }
+            }
+            utxos.deposits.push((leg.trader.clone(), deposit));
+            if spent < paid_in {
+                manager.release_operation(operation.id);
+                return Err((
+                    Some(index),
+                    format!("not enough balance. required: {paid_in}"),
+                ));
+            }
+            if returned_to_deposit(spent, paid_in, payout).is_none() {
+                manager.release_operation(operation.id);
+                return Err((
+                    Some(index),
+                    String::from("satoshis returned to the deposit would be dust"),
+                ));
+            }
+            flows.push((spent, paid_in, payout));
+        }
+
+        // the agent's change takes what's left, it's kept above dust
+        let need_change_rune_output = runic_total > rune_total;
+        let rune_change = if need_change_rune_output {
+            DEFAULT_POSTAGE
+        } else {
+            0
+        };
+        let deliveries = legs.iter().filter(|leg| leg.rune_out.is_some()).count() as u64;
+        let available = flows
+            .iter()
+            .fold(runic_bitcoin, |total, (_, paid_in, _)| total + paid_in);
+        let required = flows.iter().fold(
+            fee + rune_change + deliveries * DEFAULT_POSTAGE,
+            |total, (_, _, payout)| total + payout,
+        ) + DUST_THRESHOLD;
+        let mut agent_total = available;
+        if agent_total < required {
+            while let Some(utxo) = agent.take_bitcoin_utxo(manager, operation) {
+                agent_total += utxo.utxo.value;
+                utxos.agent.push(utxo);
+                if agent_total >= required {
+                    break;
+                }
+            }
+        }
+        if agent_total < required {
+            manager.release_operation(operation.id);
+            return Err((None, format!("not enough balance. required: {required}")));
+        }
+        Ok((
+            utxos,
+            flows,
+            need_change_rune_output,
+            agent_total - required,
+        ))
+    })?;
+
+    let mut input = vec![];
+    let outpoints = utxos
+        .runic
+        .iter()
+        .map(|RunicUtxo { utxo, .. }| utxo)
+        .chain(utxos.agent.iter().map(|WalletUtxo { utxo, .. }| utxo))
+        .chain(
+            utxos
+                .deposits
+                .iter()
+                .flat_map(|(_, utxos)| utxos.iter().map(|WalletUtxo { utxo, .. }| utxo)),
+        );
+    for utxo in outpoints {
+        input.push(TxIn {
+            previous_output: OutPoint {
+                txid: slice_to_txid(&utxo.outpoint.txid),
+                vout: utxo.outpoint.vout,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        });
+    }
+
+    let mut output = vec![];
+    let postage = Amount::from_sat(DEFAULT_POSTAGE);
+
+    // runes, unallocated ones go to the change output right after the runestone
+    if let Some(runeid) = runeid.filter(|_| rune_total > 0) {
+        let id = ordinals::RuneId {
+            block: runeid.block,
+            tx: runeid.tx,
+        };
+        let first_delivery = if need_change_rune_output { 2 } else { 1 };
+        let mut edicts = vec![];
+        let mut deliveries = vec![];
+        for (index, leg) in legs.iter().enumerate() {
+            let Some((amount, address)) = leg.rune_out.as_ref() else {
+                continue;
+            };
+            edicts.push(Edict {
+                id,
+                amount: *amount,
+                output: first_delivery + deliveries.len() as u32,
+            });
+            deliveries.push((index, address));
+        }
+        let runestone = Runestone {
+            edicts,
+            ..Default::default()
+        }
+        .encipher();
+        if runestone.len() > MAX_STANDARD_OP_RETURN_SIZE {
+            let last = deliveries.last().map(|(index, _)| *index);
+            write_utxo_manager(|manager| manager.release_operation(operation.id));
+            return Err((
+                last,
+                String::from("too many rune deliveries for one runestone"),
+            ));
+        }
+        output.push(TxOut {
+            value: Amount::from_sat(0),
+            script_pubkey: runestone,
+        });
+        if need_change_rune_output {
+            output.push(TxOut {
+                value: postage,
+                script_pubkey: agent.address().script_pubkey(),
+            });
+        }
+        for (_, address) in deliveries {
+            output.push(TxOut {
+                value: postage,
+                script_pubkey: address.script_pubkey(),
+            });
+        }
+    }
+
+    // the payout and the change of a deposit go to the same address
+    for (leg, &(spent, paid_in, payout)) in legs.iter().zip(flows.iter()) {
+        let returned = payout + spent - paid_in;
+        if returned > 0 {
+            output.push(TxOut {
+                value: Amount::from_sat(returned),
+                script_pubkey: leg.trader.address().script_pubkey(),
+            });
+        }
+    }
+
+    let change = output.len();
+    output.push(TxOut {
+        value: Amount::from_sat(surplus + DUST_THRESHOLD),
+        script_pubkey: agent.address().script_pubkey(),
+    });
+
+    let txn = Transaction {
+        input,
+        output,
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+    };
+    Ok((txn, utxos, change))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_is_split_across_legs() {
+        assert_eq!(fee_shares(1_000, 3), vec![334, 333, 333]);
+        assert_eq!(fee_shares(1_000, 3).iter().sum::<u64>(), 1_000);
+        assert_eq!(fee_shares(0, 2), vec![0, 0]);
+    }
+
+    #[test]
+    fn debit_comes_out_of_a_large_enough_payout() {
+        // the payout covers the debit and stays above dust
+        assert_eq!(leg_flows(0, 50_000, 2_000), (0, 48_000));
+        // a small payout is paid in full, the deposit pays the debit
+        assert_eq!(leg_flows(0, 2_500, 2_000), (2_000, 2_500));
+        // bitcoin owed to the agent comes along with the debit
+        assert_eq!(leg_flows(30_000, 0, 2_000), (32_000, 0));
+    }
+
+    #[test]
+    fn deposit_change_is_never_dust() {
+        // the change rides along with the payout
+        assert_eq!(returned_to_deposit(10_100, 10_000, 2_500), Some(2_600));
+        assert_eq!(returned_to_deposit(10_000, 10_000, 0), Some(0));
+        assert_eq!(returned_to_deposit(15_000, 10_000, 0), Some(5_000));
+        // dust change without a payout can't be returned
+        assert_eq!(returned_to_deposit(10_100, 10_000, 0), None);
+        assert_eq!(returned_to_deposit(10_000, 10_000, 100), None);
+    }
+}
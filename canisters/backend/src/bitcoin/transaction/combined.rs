@@ -2,31 +2,32 @@ use bitcoin::{
     Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
     absolute::LockTime, transaction::Version,
 };
-use ic_cdk::api::management_canister::bitcoin::Utxo;
-use icrc_ledger_types::icrc1::account::Account;
 use ordinals::{Edict, Runestone};
 
 use crate::{
     bitcoin::{
-        DEFAULT_POSTAGE, DUST_THRESHOLD, signer::ecdsa::mock_ecdsa_signature, utils::slice_to_txid,
+        DEFAULT_POSTAGE, DUST_THRESHOLD,
+        signer::mock_signatures,
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
     },
     indexer::RuneId,
-    state::{utxo_manager::RunicUtxo, write_utxo_manager},
+    state::{
+        utxo_manager::{Operation, RunicUtxo},
+        write_utxo_manager,
+    },
     txn_handler::TransactionType,
 };
 
 pub struct CombinedTransferArgs {
     pub runeid: RuneId,
     pub rune_amount: u128,
-    pub rune_sender: Address,
-    pub rune_sender_account: Account,
+    pub rune_sender: Wallet,
     pub rune_receiver: Address,
     pub bitcoin_amount: u64,
-    pub bitcoin_sender: Address,
-    pub bitcoin_sender_account: Account,
+    pub bitcoin_sender: Wallet,
     pub bitcoin_receiver: Address,
-    pub fee_payer: Address,
-    pub fee_payer_account: Account,
+    pub fee_payer: Wallet,
     pub postage: Option<u64>,
     pub fee_per_vbytes: u64,
 }
@@ -36,18 +37,16 @@ pub fn transfer(
         runeid,
         rune_amount,
         rune_sender,
-        rune_sender_account,
         rune_receiver,
         bitcoin_amount,
         bitcoin_sender,
-        bitcoin_sender_account,
         bitcoin_receiver,
         fee_payer,
-        fee_payer_account,
         postage,
         fee_per_vbytes,
     }: CombinedTransferArgs,
 ) -> Result<TransactionType, (u128, u64, u64)> {
+    let operation = Operation::start();
     let mut total_fee = 0;
     let postage = Amount::from_sat(postage.unwrap_or(DEFAULT_POSTAGE));
     loop {
@@ -62,8 +61,15 @@ pub fn transfer(
             &fee_payer,
             postage,
             total_fee,
+            operation,
         )?;
-        let signed_txn = mock_ecdsa_signature(&txn);
+        let address_types = runic_utxos
+            .iter()
+            .map(|_| rune_sender.preferred())
+            .chain(bitcoin_utxos.iter().map(|utxo| utxo.address_type))
+            .chain(fee_utxos.iter().map(|utxo| utxo.address_type))
+            .collect::<Vec<_>>();
+        let signed_txn = mock_signatures(&txn, &address_types);
         let txn_vsize = signed_txn.vsize() as u64;
         if (txn_vsize * fee_per_vbytes) / 1000 == total_fee {
             return Ok(TransactionType::Combined {
@@ -72,23 +78,24 @@ pub fn transfer(
                 rune_amount,
                 rune_sender: Box::new(rune_sender),
                 rune_receiver: Box::new(rune_receiver),
-                rune_sender_account,
                 bitcoin_utxos,
                 bitcoin_amount,
                 bitcoin_sender: Box::new(bitcoin_sender),
                 bitcoin_receiver: Box::new(bitcoin_receiver),
-                bitcoin_sender_account,
                 fee_utxos,
                 fee: total_fee,
                 fee_payer: Box::new(fee_payer),
-                fee_payer_account,
                 postage,
             });
         } else {
             write_utxo_manager(|manager| {
-                manager.record_runic_utxos(rune_sender.to_string().as_str(), runeid, runic_utxos);
-                manager.record_bitcoin_utxos(bitcoin_sender.to_string().as_str(), bitcoin_utxos);
-                manager.record_bitcoin_utxos(fee_payer.to_string().as_str(), fee_utxos);
+                manager.release_runic_utxos(
+                    rune_sender.address().to_string().as_str(),
+                    runeid,
+                    runic_utxos,
+                );
+                bitcoin_sender.release(manager, bitcoin_utxos);
+                fee_payer.release(manager, fee_utxos);
             });
             total_fee = (txn_vsize * fee_per_vbytes) / 1000;
         }
@@ -105,23 +112,33 @@ pub fn transfer(
 fn build_transaction_with_fee(
     runeid: &RuneId,
     rune_amount: u128,
-    rune_sender: &Address,
+    rune_sender: &Wallet,
     rune_receiver: &Address,
     bitcoin_amount: u64,
-    bitcoin_sender: &Address,
+    bitcoin_sender: &Wallet,
     bitcoin_receiver: &Address,
-    fee_payer: &Address,
+    fee_payer: &Wallet,
     postage: Amount,
     fee: u64,
-) -> Result<(Transaction, Vec<RunicUtxo>, Vec<Utxo>, Vec<Utxo>), (u128, u64, u64)> {
+    operation: Operation,
+) -> Result<
+    (
+        Transaction,
+        Vec<RunicUtxo>,
+        Vec<WalletUtxo>,
+        Vec<WalletUtxo>,
+    ),
+    (u128, u64, u64),
+> {
     write_utxo_manager(|manager| {
-        let rune_sender_addr = rune_sender.to_string();
+        // runes are only held on the preferred address
+        let rune_sender_addr = rune_sender.address().to_string();
 
         let mut runic_utxos = vec![];
         let mut runic_total_spent = 0;
         let mut bitcoin_spent_in_runic = 0;
 
-        while let Some(utxo) = manager.get_runic_utxo(&rune_sender_addr, *runeid) {
+        while let Some(utxo) = manager.get_runic_utxo(&rune_sender_addr, *runeid, operation) {
             runic_total_spent += utxo.balance;
             bitcoin_spent_in_runic += utxo.utxo.value;
             runic_utxos.push(utxo);
@@ -131,7 +148,7 @@ fn build_transaction_with_fee(
         }
 
         if runic_total_spent < rune_amount {
-            manager.record_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
+            manager.release_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
             return Err((rune_amount, bitcoin_amount, fee));
         }
 
@@ -145,12 +162,11 @@ fn build_transaction_with_fee(
 
         let required_total_bitcoin_fee = fee + required_postage_amount;
 
-        let bitcoin_sender_addr = bitcoin_sender.to_string();
         let mut bitcoin_utxos = vec![];
         let mut bitcoin_total_spent = 0;
 
-        while let Some(utxo) = manager.get_bitcoin_utxo(&bitcoin_sender_addr) {
-            bitcoin_total_spent += utxo.value;
+        while let Some(utxo) = bitcoin_sender.take_bitcoin_utxo(manager, operation) {
+            bitcoin_total_spent += utxo.utxo.value;
             bitcoin_utxos.push(utxo);
             if bitcoin_total_spent > bitcoin_amount {
                 break;
@@ -158,44 +174,43 @@ fn build_transaction_with_fee(
         }
 
         if bitcoin_total_spent < bitcoin_amount {
-            manager.record_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
-            manager.record_bitcoin_utxos(&bitcoin_sender_addr, bitcoin_utxos);
+            manager.release_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
+            bitcoin_sender.release(manager, bitcoin_utxos);
             return Err((rune_amount, bitcoin_amount, fee));
         }
 
-        let fee_payer_addr = fee_payer.to_string();
         let mut fee_utxos = vec![];
         let mut fee_total_spent = 0;
 
         // NOTE: fee payer and bitcoin sender can be same
-        if fee_payer == bitcoin_sender
-            && (bitcoin_total_spent - bitcoin_amount) < required_total_bitcoin_fee
-        {
-            while let Some(utxo) = manager.get_bitcoin_utxo(&fee_payer_addr) {
-                bitcoin_total_spent += utxo.value;
-                bitcoin_utxos.push(utxo);
-                if bitcoin_total_spent > (bitcoin_amount + required_total_bitcoin_fee) {
-                    break;
+        if fee_payer == bitcoin_sender {
+            if (bitcoin_total_spent - bitcoin_amount) < required_total_bitcoin_fee {
+                while let Some(utxo) = fee_payer.take_bitcoin_utxo(manager, operation) {
+                    bitcoin_total_spent += utxo.utxo.value;
+                    bitcoin_utxos.push(utxo);
+                    if bitcoin_total_spent > (bitcoin_amount + required_total_bitcoin_fee) {
+                        break;
+                    }
                 }
                 if bitcoin_total_spent < (bitcoin_amount + required_total_bitcoin_fee) {
-                    manager.record_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
-                    manager.record_bitcoin_utxos(&bitcoin_sender_addr, bitcoin_utxos);
+                    manager.release_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
+                    bitcoin_sender.release(manager, bitcoin_utxos);
                     return Err((rune_amount, bitcoin_amount, fee));
                 }
             }
         } else {
-            while let Some(utxo) = manager.get_bitcoin_utxo(&fee_payer_addr) {
-                fee_total_spent += utxo.value;
+            while let Some(utxo) = fee_payer.take_bitcoin_utxo(manager, operation) {
+                fee_total_spent += utxo.utxo.value;
                 fee_utxos.push(utxo);
                 if fee_total_spent > required_total_bitcoin_fee {
                     break;
                 }
-                if fee_total_spent < required_total_bitcoin_fee {
-                    manager.record_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
-                    manager.record_bitcoin_utxos(&bitcoin_sender_addr, bitcoin_utxos);
-                    manager.record_bitcoin_utxos(&fee_payer_addr, fee_utxos);
-                    return Err((rune_amount, bitcoin_amount, fee));
-                }
+            }
+            if fee_total_spent < required_total_bitcoin_fee {
+                manager.release_runic_utxos(&rune_sender_addr, *runeid, runic_utxos);
+                bitcoin_sender.release(manager, bitcoin_utxos);
+                fee_payer.release(manager, fee_utxos);
+                return Err((rune_amount, bitcoin_amount, fee));
             }
         }
 
@@ -212,35 +227,35 @@ fn build_transaction_with_fee(
                         vout: utxo.outpoint.vout,
                     },
                     script_sig: ScriptBuf::new(),
-                    sequence: Sequence::MAX,
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                     witness: Witness::new(),
                 };
                 input.push(txin);
             });
 
         // bitcoin utxos
-        bitcoin_utxos.iter().for_each(|utxo| {
+        bitcoin_utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
             let txin = TxIn {
                 previous_output: OutPoint {
                     txid: slice_to_txid(&utxo.outpoint.txid),
                     vout: utxo.outpoint.vout,
                 },
                 script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                 witness: Witness::new(),
             };
             input.push(txin);
         });
 
         // fee utxos
-        fee_utxos.iter().for_each(|utxo| {
+        fee_utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
             let txin = TxIn {
                 previous_output: OutPoint {
                     txid: slice_to_txid(&utxo.outpoint.txid),
                     vout: utxo.outpoint.vout,
                 },
                 script_sig: ScriptBuf::new(),
-                sequence: Sequence::MAX,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                 witness: Witness::new(),
             };
             input.push(txin);
@@ -269,7 +284,7 @@ fn build_transaction_with_fee(
             });
             output.push(TxOut {
                 value: postage,
-                script_pubkey: rune_sender.script_pubkey(),
+                script_pubkey: rune_sender.address().script_pubkey(),
             });
             output.push(TxOut {
                 value: postage,
@@ -292,7 +307,7 @@ fn build_transaction_with_fee(
             if remaining >= DUST_THRESHOLD {
                 output.push(TxOut {
                     value: Amount::from_sat(remaining),
-                    script_pubkey: bitcoin_sender.script_pubkey(),
+                    script_pubkey: bitcoin_sender.address().script_pubkey(),
                 });
             }
         } else {
@@ -300,7 +315,7 @@ fn build_transaction_with_fee(
             if remaining_bitcoin >= DUST_THRESHOLD {
                 output.push(TxOut {
                     value: Amount::from_sat(remaining_bitcoin),
-                    script_pubkey: bitcoin_sender.script_pubkey(),
+                    script_pubkey: bitcoin_sender.address().script_pubkey(),
                 });
             }
 
@@ -308,7 +323,7 @@ fn build_transaction_with_fee(
             if remaining_fee >= DUST_THRESHOLD {
                 output.push(TxOut {
                     value: Amount::from_sat(remaining_fee),
-                    script_pubkey: fee_payer.script_pubkey(),
+                    script_pubkey: fee_payer.address().script_pubkey(),
                 });
             }
         }
@@ -0,0 +1,242 @@
+use crate::bitcoin_lib::{
+    Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, absolute::LockTime,
+    hashes::Hash, transaction::Version,
+};
+use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
+
+use crate::{
+    bitcoin::{
+        AddressType, DUST_THRESHOLD,
+        signer::mock_signatures,
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
+    },
+    state::{utxo_manager::Operation, write_utxo_manager},
+    txn_handler::TransactionType,
+};
+
+pub struct CpfpArgs {
+    pub parent: Transaction,
+    pub parent_fee: u64,
+    pub replaced_fee: Option<u64>, // fee of an earlier child spending the same output
+    pub owner: Wallet,             // receives the parent's change and tops it up
+    pub fee_per_vbytes: u64,       // fee rate of the parent and child together
+}
+
+/// fee the child pays so that the parent and the child together pay `fee_per_vbytes`
+pub fn child_fee(parent_vsize: u64, parent_fee: u64, child_vsize: u64, fee_per_vbytes: u64) -> u64 {
+    let package_fee = ((parent_vsize + child_vsize) * fee_per_vbytes) / 1000;
+    // the child pays at least the minimum relay fee of a satoshi per vbyte
+    package_fee.saturating_sub(parent_fee).max(child_vsize)
+}
+
+// (vout, index of the script, value) of the first output of the parent paying one of the scripts
+fn find_change(parent: &Transaction, scripts: &[ScriptBuf]) -> Option<(u32, usize, u64)> {
+    parent.output.iter().enumerate().find_map(|(vout, output)| {
+        scripts
+            .iter()
+            .position(|script| *script == output.script_pubkey)
+            .map(|index| (vout as u32, index, output.value.to_sat()))
+    })
+}
+
+/*
+ * child spending the inputs back to the receiver, along with the fee it pays
+ * errs with the amount the inputs should add up to when they can't pay for the child
+*/
+fn build_child(
+    inputs: &[(OutPoint, AddressType, u64)],
+    receiver: &ScriptBuf,
+    (parent_vsize, parent_fee): (u64, u64),
+    replaced_fee: Option<u64>,
+    fee_per_vbytes: u64,
+) -> Result<(Transaction, u64), u64> {
+    let total = inputs.iter().fold(0, |total, (_, _, value)| total + value);
+    let address_types = inputs
+        .iter()
+        .map(|(_, address_type, _)| *address_type)
+        .collect::<Vec<_>>();
+    let mut total_fee = 0;
+    loop {
+        if total < total_fee + DUST_THRESHOLD {
+            return Err(total_fee + DUST_THRESHOLD);
+        }
+        let txn = Transaction {
+            input: inputs
+                .iter()
+                .map(|(outpoint, _, _)| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(total - total_fee),
+                script_pubkey: receiver.clone(),
+            }],
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+        };
+        let child_vsize = mock_signatures(&txn, &address_types).vsize() as u64;
+        // replacing an earlier child has to pay for its own relay on top
+        let required = child_fee(parent_vsize, parent_fee, child_vsize, fee_per_vbytes)
+            .max(replaced_fee.map_or(0, |fee| fee + child_vsize));
+        if required == total_fee {
+            return Ok((txn, total_fee));
+        }
+        total_fee = required;
+    }
+}
+
+/*
+ * child spending the parent's change back to the owner, paying for both transactions
+ * when the change can't pay for the child, utxos of the owner are added to it
+ * the change is leased as well, so that it doesn't return to the pool once the parent confirms
+*/
+pub fn transfer(
+    CpfpArgs {
+        parent,
+        parent_fee,
+        replaced_fee,
+        owner,
+        fee_per_vbytes,
+    }: CpfpArgs,
+) -> Result<TransactionType, String> {
+    let scripts = owner
+        .addresses
+        .iter()
+        .map(|(_, address)| address.script_pubkey())
+        .collect::<Vec<_>>();
+    // a parent without change can only be spent by its own children, e.g. the reveal
+    let (vout, index, value) = find_change(&parent, &scripts)
+        .ok_or_else(|| String::from("transaction doesn't have a change output to spend"))?;
+    let (address_type, address) = owner.addresses[index].clone();
+    let txid = parent.compute_txid();
+    let parent_output = WalletUtxo {
+        address_type,
+        utxo: Utxo {
+            outpoint: Outpoint {
+                txid: txid.to_byte_array().to_vec(),
+                vout,
+            },
+            value,
+            height: 0, // unconfirmed
+        },
+    };
+    let parent_size = (parent.vsize() as u64, parent_fee);
+
+    let operation = Operation::start();
+    write_utxo_manager(|manager| {
+        manager.lease_unconfirmed(&address.to_string(), parent_output.utxo.clone(), operation)
+    });
+    let mut fee_utxos: Vec<WalletUtxo> = vec![];
+    loop {
+        let inputs = std::iter::once(&parent_output)
+            .chain(fee_utxos.iter())
+            .map(|WalletUtxo { address_type, utxo }| {
+                let outpoint = OutPoint {
+                    txid: slice_to_txid(&utxo.outpoint.txid),
+                    vout: utxo.outpoint.vout,
+                };
+                (outpoint, *address_type, utxo.value)
+            })
+            .collect::<Vec<_>>();
+        let receiver = owner.address().script_pubkey();
+        let required = match build_child(
+            &inputs,
+            &receiver,
+            parent_size,
+            replaced_fee,
+            fee_per_vbytes,
+        ) {
+            Ok((txn, _)) => {
+                return Ok(TransactionType::Cpfp {
+                    parent_output,
+                    fee_utxos,
+                    txn,
+                    owner,
+                });
+            }
+            Err(required) => required,
+        };
+        let added = write_utxo_manager(|manager| owner.take_bitcoin_utxo(manager, operation));
+        match added {
+            Some(utxo) => fee_utxos.push(utxo),
+            None => {
+                write_utxo_manager(|manager| {
+                    manager.drop_lease(&parent_output.utxo);
+                    owner.release(manager, fee_utxos);
+                });
+                return Err(format!("can't pay for the child. required: {required}"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin_lib::Txid;
+
+    fn outpoint(byte: u8) -> OutPoint {
+        OutPoint {
+            txid: Txid::from_byte_array([byte; 32]),
+            vout: 1,
+        }
+    }
+
+    #[test]
+    fn child_lifts_the_package_fee_rate() {
+        // 200 vbytes paying 1 sat/vbyte, lifted to 10 sat/vbyte with a 100 vbyte child
+        assert_eq!(child_fee(200, 200, 100, 10_000), 2_800);
+        // a parent already paying enough still has the child pay the minimum relay fee
+        assert_eq!(child_fee(200, 5_000, 100, 10_000), 100);
+    }
+
+    #[test]
+    fn change_is_found_among_the_owner_scripts() {
+        let scripts = [
+            ScriptBuf::from_bytes(vec![1]),
+            ScriptBuf::from_bytes(vec![2]),
+        ];
+        let parent = Transaction {
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(546),
+                    script_pubkey: ScriptBuf::from_bytes(vec![9]),
+                },
+                TxOut {
+                    value: Amount::from_sat(20_000),
+                    script_pubkey: scripts[1].clone(),
+                },
+            ],
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+        };
+        assert_eq!(find_change(&parent, &scripts), Some((1, 1, 20_000)));
+        assert_eq!(find_change(&parent, &scripts[..1]), None);
+    }
+
+    #[test]
+    fn child_pays_for_the_package_out_of_its_inputs() {
+        let receiver = ScriptBuf::from_bytes(vec![1]);
+        let change = [(outpoint(1), AddressType::P2wpkh, 20_000)];
+        let (txn, fee) = build_child(&change, &receiver, (200, 200), None, 10_000).unwrap();
+        let child_vsize = mock_signatures(&txn, &[AddressType::P2wpkh]).vsize() as u64;
+        assert_eq!(fee, child_fee(200, 200, child_vsize, 10_000));
+        assert_eq!(txn.output[0].value.to_sat(), 20_000 - fee);
+
+        // a replacement pays for its own relay on top of the child it replaces
+        let (_, replacement) =
+            build_child(&change, &receiver, (200, 200), Some(fee), 10_000).unwrap();
+        assert_eq!(replacement, fee + child_vsize);
+
+        // a change too small asks for more inputs
+        let small = [(outpoint(1), AddressType::P2wpkh, 1_000)];
+        let required = build_child(&small, &receiver, (200, 200), None, 10_000).unwrap_err();
+        let topped_up = [small[0], (outpoint(2), AddressType::P2tr, required)];
+        assert!(build_child(&topped_up, &receiver, (200, 200), None, 10_000).is_ok());
+    }
+}
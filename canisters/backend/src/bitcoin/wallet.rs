@@ -0,0 +1,98 @@
+use crate::bitcoin_lib::Address;
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::state::utxo_manager::{Operation, UtxoManager};
+
+use super::{AddressType, account_to_address, signer::InputSigner};
+
+/// A bitcoin utxo reserved from one of the wallet's addresses
+#[derive(Clone)]
+pub struct WalletUtxo {
+    pub address_type: AddressType,
+    pub utxo: Utxo,
+}
+
+/// The addresses of an account, bitcoin is spent from all of them.
+/// Change and runes only ever go to the preferred address.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Wallet {
+    pub account: Account,
+    pub addresses: Vec<(AddressType, Address)>, // preferred address first
+}
+
+impl Wallet {
+    pub fn new(account: Account, address_types: &[AddressType]) -> Self {
+        let addresses = address_types
+            .iter()
+            .map(|&address_type| (address_type, account_to_address(&account, address_type)))
+            .collect();
+        Self { account, addresses }
+    }
+
+    pub fn preferred(&self) -> AddressType {
+        self.addresses[0].0
+    }
+
+    /// the preferred address
+    pub fn address(&self) -> &Address {
+        &self.addresses[0].1
+    }
+
+    pub fn address_of(&self, address_type: AddressType) -> &Address {
+        self.addresses
+            .iter()
+            .find(|(other, _)| *other == address_type)
+            .map(|(_, address)| address)
+            .expect("wallet should have the address type")
+    }
+
+    pub fn bitcoin_balance(&self, manager: &UtxoManager) -> u64 {
+        self.addresses.iter().fold(0, |total, (_, address)| {
+            total + manager.get_bitcoin_balance(&address.to_string())
+        })
+    }
+
+    /// takes the smallest utxo of the first address that has any, leased to `operation`
+    pub fn take_bitcoin_utxo(
+        &self,
+        manager: &mut UtxoManager,
+        operation: Operation,
+    ) -> Option<WalletUtxo> {
+        self.addresses.iter().find_map(|(address_type, address)| {
+            manager
+                .get_bitcoin_utxo(&address.to_string(), operation)
+                .map(|utxo| WalletUtxo {
+                    address_type: *address_type,
+                    utxo,
+                })
+        })
+    }
+
+    /// hands reserved utxos back to the addresses they belong to
+    pub fn release(&self, manager: &mut UtxoManager, utxos: Vec<WalletUtxo>) {
+        for (address_type, address) in self.addresses.iter() {
+            let utxos = utxos
+                .iter()
+                .filter(|utxo| utxo.address_type == *address_type)
+                .map(|utxo| utxo.utxo.clone())
+                .collect::<Vec<_>>();
+            if !utxos.is_empty() {
+                manager.release_bitcoin_utxos(&address.to_string(), utxos);
+            }
+        }
+    }
+
+    pub fn signer(&self, address_type: AddressType, value: u64) -> InputSigner {
+        InputSigner {
+            account: self.account,
+            address_type,
+            address: self.address_of(address_type).clone(),
+            value,
+        }
+    }
+
+    pub fn signer_for(&self, utxo: &WalletUtxo) -> InputSigner {
+        self.signer(utxo.address_type, utxo.utxo.value)
+    }
+}
@@ -0,0 +1,460 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use ic_cdk::api::management_canister::bitcoin::{
+    GetUtxosRequest, SendTransactionRequest, UtxoFilter, bitcoin_get_utxos,
+    bitcoin_send_transaction,
+};
+
+use crate::bitcoin_lib::{
+    Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, consensus,
+};
+use crate::{
+    bitcoin::{
+        self, DUST_THRESHOLD,
+        fee::{self, FeeRate},
+        signer::{
+            InputSigner, RevealInput, mock_signatures, sign_reveal_transaction, sign_transaction,
+        },
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
+    },
+    state::{
+        RbfConfig,
+        broadcasts::{Broadcast, BroadcastKind, TrackedInput, WatchedInput},
+        read_broadcasts, read_config,
+        utxo_manager::Operation,
+        write_agents, write_broadcasts, write_settlement_state, write_trades, write_utxo_manager,
+    },
+};
+
+// minimum fee rate a replacement has to add on top of the replaced fee, in satoshis/vbyte
+const INCREMENTAL_RELAY_FEE: u64 = 1;
+
+thread_local! {
+    static CHECKING: Cell<bool> = const { Cell::new(false) };
+}
+
+// prevents a transaction from being bumped twice when a check outlives the timer interval
+struct CheckGuard;
+
+impl CheckGuard {
+    fn acquire() -> Option<Self> {
+        CHECKING.with(|checking| {
+            if checking.get() {
+                None
+            } else {
+                checking.set(true);
+                Some(Self)
+            }
+        })
+    }
+}
+
+impl Drop for CheckGuard {
+    fn drop(&mut self) {
+        CHECKING.with(|checking| checking.set(false));
+    }
+}
+
+/// fee of a replacement, it has to pay for its own relay on top of the fee it replaces
+pub fn bumped_fee(replaced_fee: u64, vsize: u64, fee_per_vbytes: u64) -> u64 {
+    ((vsize * fee_per_vbytes) / 1000).max(replaced_fee + vsize * INCREMENTAL_RELAY_FEE)
+}
+
+/// tracks a transaction spending wallet utxos, `change` is the output paying back the fee payer
+pub fn track_transfer(
+    txn: &Transaction,
+    signers: &[InputSigner],
+    change: Option<usize>,
+    fee_payer: &Wallet,
+) {
+    let inputs = signers
+        .iter()
+        .map(|signer| TrackedInput::Wallet {
+            account: signer.account,
+            address_type: signer.address_type,
+            address: signer.address.to_string(),
+            value: signer.value,
+        })
+        .collect();
+    track(BroadcastKind::Transfer, txn, inputs, change, fee_payer);
+}
+
+/// tracks the reveal of an etching, its fee can only be bumped by adding inputs of the fee payer
+pub fn track_reveal(
+    agent_id: u128,
+    txn: &Transaction,
+    commit_tx_address: &str,
+    commit_value: u64,
+    fee_payer: &Wallet,
+) {
+    let inputs = vec![TrackedInput::Reveal {
+        address: commit_tx_address.to_string(),
+        value: commit_value,
+    }];
+    track(
+        BroadcastKind::Reveal { agent_id },
+        txn,
+        inputs,
+        None,
+        fee_payer,
+    );
+}
+
+fn track(
+    kind: BroadcastKind,
+    txn: &Transaction,
+    inputs: Vec<TrackedInput>,
+    change: Option<usize>,
+    fee_payer: &Wallet,
+) {
+    let (Some(first), Some(input)) = (inputs.first(), txn.input.first()) else {
+        return;
+    };
+    let watch = WatchedInput {
+        address: first.address().to_string(),
+        txid: input.previous_output.txid.to_string(),
+        vout: input.previous_output.vout,
+        // wallet utxos are fetched once mined and reveals wait for the commit's confirmations
+        seen_unspent: true,
+    };
+    let spent = inputs.iter().fold(0, |total, input| total + input.value());
+    let created = txn
+        .output
+        .iter()
+        .fold(0, |total, output| total + output.value.to_sat());
+    write_broadcasts(|state| {
+        state.track(Broadcast {
+            kind,
+            txn: consensus::serialize(txn),
+            txid: txn.compute_txid().to_string(),
+            inputs,
+            fee: spent - created,
+            change: change.map(|vout| vout as u32),
+            fee_payer: (
+                fee_payer.account,
+                fee_payer
+                    .addresses
+                    .iter()
+                    .map(|(address_type, _)| *address_type)
+                    .collect(),
+            ),
+            watch,
+            replaced: vec![],
+            attempts: 0,
+            attempted_at: ic_cdk::api::time(),
+        })
+    });
+}
+
+pub fn start_broadcast_timer() {
+    let interval = read_config(|config| config.get_timer_for_broadcasts());
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        ic_cdk::spawn(check_broadcasts())
+    });
+}
+
+/*
+ * tip height once the input is spent by a mined transaction
+ * none while it's unspent, before it was ever seen unspent or when the utxos can't be fetched
+ * seeing the input unspent is recorded on it, the caller stores it
+*/
+pub async fn spent_at_tip(input: &mut WatchedInput) -> Option<u32> {
+    let network = read_config(|config| config.bitcoin_network());
+    let mut filter = None;
+    loop {
+        let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+            address: input.address.clone(),
+            network,
+            filter,
+        })
+        .await
+        .ok()?;
+        let unspent = response.utxos.iter().any(|utxo| {
+            utxo.outpoint.vout == input.vout
+                && slice_to_txid(&utxo.outpoint.txid).to_string() == input.txid
+        });
+        if unspent {
+            input.seen_unspent = true;
+            return None;
+        }
+        match response.next_page {
+            Some(page) => filter = Some(UtxoFilter::Page(page)),
+            None => return input.seen_unspent.then_some(response.tip_height),
+        }
+    }
+}
+
+/*
+ * stops tracking transactions once any of their versions confirmed
+ * the ones unconfirmed for too long are replaced by a version paying a higher fee
+*/
+async fn check_broadcasts() {
+    let Some(_guard) = CheckGuard::acquire() else {
+        return;
+    };
+    let config = read_config(|config| config.rbf_config());
+    for (id, mut broadcast) in read_broadcasts(|state| state.unconfirmed()) {
+        let seen_unspent = broadcast.watch.seen_unspent;
+        let spent = spent_at_tip(&mut broadcast.watch).await;
+        if broadcast.watch.seen_unspent != seen_unspent {
+            write_broadcasts(|state| state.update_watch(id, &broadcast.watch));
+        }
+        if spent.is_some() {
+            write_broadcasts(|state| state.tracked.remove(&id));
+            // its inputs are spent for good, whichever version confirmed
+            let txids = broadcast.txids().cloned().collect::<Vec<_>>();
+            write_utxo_manager(|manager| manager.drop_spent_leases(&txids));
+            ic_cdk::println!("broadcast {} confirmed", txids.join(" or "));
+            continue;
+        }
+
+        let now = ic_cdk::api::time();
+        if !config.enabled
+            || now.saturating_sub(broadcast.attempted_at) < config.bump_after_secs * 1_000_000_000
+        {
+            continue;
+        }
+        if broadcast.attempts >= config.max_bumps {
+            write_broadcasts(|state| state.tracked.remove(&id));
+            ic_cdk::println!(
+                "stopped tracking {} after {} bumps",
+                broadcast.txid,
+                broadcast.attempts
+            );
+            continue;
+        }
+        write_broadcasts(|state| {
+            let mut broadcast = broadcast.clone();
+            broadcast.attempts += 1;
+            broadcast.attempted_at = now;
+            state.tracked.insert(id, broadcast);
+        });
+        if let Err(err) = bump(id, broadcast, &config).await {
+            ic_cdk::println!("bumping broadcast {} failed: {}", id, err);
+        }
+    }
+}
+
+async fn bump(id: u128, broadcast: Broadcast, config: &RbfConfig) -> Result<(), String> {
+    let mut txn: Transaction =
+        consensus::deserialize(&broadcast.txn).map_err(|err| err.to_string())?;
+    let fee_per_vbytes = fee::fee_per_vbyte(Some(FeeRate::Tier(config.tier))).await;
+    let mut inputs = broadcast.inputs.clone();
+    let mut change = broadcast.change.map(|vout| vout as usize);
+    let mut added = vec![];
+    let fee_payer = Wallet::new(broadcast.fee_payer.0, &broadcast.fee_payer.1);
+
+    let fee = bumped_fee(broadcast.fee, txn.vsize() as u64, fee_per_vbytes);
+    let extra = Amount::from_sat(fee - broadcast.fee);
+    let dust = Amount::from_sat(DUST_THRESHOLD);
+    let fee = if let Some(vout) = change.filter(|vout| txn.output[*vout].value >= extra + dust) {
+        // the fee payer's change pays for the bump
+        txn.output[vout].value = txn.output[vout].value - extra;
+        fee
+    } else {
+        let (utxos, vout, fee) = add_fee_inputs(&mut txn, &broadcast, &fee_payer, fee_per_vbytes)?;
+        inputs.extend(utxos.iter().map(|utxo| TrackedInput::Wallet {
+            account: fee_payer.account,
+            address_type: utxo.address_type,
+            address: fee_payer.address_of(utxo.address_type).to_string(),
+            value: utxo.utxo.value,
+        }));
+        change.replace(vout);
+        added = utxos;
+        fee
+    };
+
+    sign(&mut txn, &inputs).await?;
+
+    let network = read_config(|config| config.bitcoin_network());
+    let txid = txn.compute_txid().to_string();
+    let txn_bytes = consensus::serialize(&txn);
+    ic_cdk::println!(
+        "replacing {} with {}",
+        broadcast.txid,
+        hex::encode(&txn_bytes)
+    );
+    if bitcoin_send_transaction(SendTransactionRequest {
+        transaction: txn_bytes.clone(),
+        network,
+    })
+    .await
+    .is_err()
+    {
+        write_utxo_manager(|manager| fee_payer.release(manager, added));
+        return Err(String::from("failed submitting the replacement"));
+    }
+    write_utxo_manager(|manager| manager.mark_spent(&txn, &txid, ic_cdk::api::time()));
+
+    write_broadcasts(|state| {
+        let Some(mut tracked) = state.tracked.get(&id) else {
+            return;
+        };
+        tracked.replaced.push(tracked.txid.clone());
+        tracked.txid = txid.clone();
+        tracked.txn = txn_bytes;
+        tracked.inputs = inputs;
+        tracked.fee = fee;
+        tracked.change = change.map(|vout| vout as u32);
+        state.tracked.insert(id, tracked);
+    });
+    replace_txid(&broadcast.kind, &broadcast.txid, &txid);
+    Ok(())
+}
+
+/*
+ * adds utxos of the fee payer along with a change output paying it back
+ * the utxos stay reserved, they're spent by the replacement
+ * returns the utxos, the change output and the fee of the replacement
+*/
+fn add_fee_inputs(
+    txn: &mut Transaction,
+    broadcast: &Broadcast,
+    fee_payer: &Wallet,
+    fee_per_vbytes: u64,
+) -> Result<(Vec<WalletUtxo>, usize, u64), String> {
+    let operation = Operation::start();
+    let mut total_fee = bumped_fee(broadcast.fee, txn.vsize() as u64, fee_per_vbytes);
+    loop {
+        let extra = total_fee - broadcast.fee;
+        let (utxos, total_spent) = write_utxo_manager(|manager| {
+            let mut utxos = vec![];
+            let mut total_spent = 0;
+            while let Some(utxo) = fee_payer.take_bitcoin_utxo(manager, operation) {
+                total_spent += utxo.utxo.value;
+                utxos.push(utxo);
+                if total_spent >= extra + DUST_THRESHOLD {
+                    break;
+                }
+            }
+            if total_spent < extra + DUST_THRESHOLD {
+                fee_payer.release(manager, utxos);
+                return Err(format!(
+                    "fee payer can't pay for the bump, required: {}",
+                    extra + DUST_THRESHOLD
+                ));
+            }
+            Ok((utxos, total_spent))
+        })?;
+
+        let mut candidate = txn.clone();
+        candidate
+            .input
+            .extend(utxos.iter().map(|WalletUtxo { utxo, .. }| TxIn {
+                previous_output: OutPoint {
+                    txid: slice_to_txid(&utxo.outpoint.txid),
+                    vout: utxo.outpoint.vout,
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }));
+        candidate.output.push(TxOut {
+            value: Amount::from_sat(total_spent - extra),
+            script_pubkey: fee_payer.address().script_pubkey(),
+        });
+
+        // the inputs already there keep their signatures, the added ones get mocked ones
+        let address_types = utxos
+            .iter()
+            .map(|utxo| utxo.address_type)
+            .collect::<Vec<_>>();
+        let offset = txn.input.len();
+        let mocked = mock_signatures(
+            &Transaction {
+                input: candidate.input[offset..].to_vec(),
+                ..candidate.clone()
+            },
+            &address_types,
+        );
+        let mut estimate = candidate.clone();
+        estimate.input.splice(offset.., mocked.input);
+        let required = bumped_fee(broadcast.fee, estimate.vsize() as u64, fee_per_vbytes);
+
+        if required <= total_fee {
+            let vout = candidate.output.len() - 1;
+            *txn = candidate;
+            return Ok((utxos, vout, total_fee));
+        }
+        write_utxo_manager(|manager| fee_payer.release(manager, utxos));
+        total_fee = required;
+    }
+}
+
+async fn sign(txn: &mut Transaction, inputs: &[TrackedInput]) -> Result<(), String> {
+    let mut reveal = None;
+    let mut signers = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        match input {
+            TrackedInput::Wallet {
+                account,
+                address_type,
+                address,
+                value,
+            } => signers.push(InputSigner {
+                account: *account,
+                address_type: *address_type,
+                address: bitcoin::address_validation(address)?,
+                value: *value,
+            }),
+            TrackedInput::Reveal { address, value } if index == 0 => {
+                let commit_output = TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: bitcoin::address_validation(address)?.script_pubkey(),
+                };
+                reveal = Some(
+                    RevealInput::from_witness(commit_output, &txn.input[0].witness)
+                        .ok_or_else(|| String::from("reveal witness is missing the script"))?,
+                );
+            }
+            TrackedInput::Reveal { .. } => {
+                return Err(String::from("only the first input can spend a commit"));
+            }
+        }
+    }
+    match reveal {
+        Some(reveal) => sign_reveal_transaction(txn, &reveal, &signers).await,
+        None => sign_transaction(txn, &signers).await,
+    }
+    Ok(())
+}
+
+// the origin of the transaction keeps the txids of every version, any of them can confirm
+fn replace_txid(kind: &BroadcastKind, txid: &str, replacement: &str) {
+    match kind {
+        BroadcastKind::Reveal { agent_id } => write_agents(|agents| {
+            if let Some(mut agent) = agents.mapping.get(agent_id) {
+                if let Some(replaced) = agent.txns.1.replace(replacement.to_string()) {
+                    agent
+                        .replaced_reveals
+                        .get_or_insert_with(Vec::new)
+                        .push(replaced);
+                }
+                agents.mapping.insert(*agent_id, agent);
+            }
+        }),
+        BroadcastKind::Transfer => {
+            let trades = write_settlement_state(|state| state.replace_txid(txid, replacement));
+            write_trades(|state| {
+                for key in trades.iter() {
+                    state.record_settlement_txid(key, replacement);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bumps_pay_at_least_the_incremental_relay_fee() {
+        // a higher market rate is used as is
+        assert_eq!(bumped_fee(1_000, 200, 20_000), 4_000);
+        // otherwise the replaced fee is topped up by a satoshi per vbyte
+        assert_eq!(bumped_fee(1_000, 200, 5_000), 1_200);
+    }
+}
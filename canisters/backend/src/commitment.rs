@@ -0,0 +1,81 @@
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha256};
+
+/// Secret of a bait the bot round, kept only as its salted hash.
+/// The commitment is published when the round opens and the salt is published once it ends, so
+/// that anyone can check that the winning guess was the secret from the start.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SecretCommitment {
+    pub commitment: String, // hex encoded sha256(salt || normalised secret)
+    salt: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevealedSecret {
+    pub commitment: String,
+    pub salt: String, // hex encoded
+    pub secret: String,
+    pub revealed_at: u64,
+}
+
+/// lowercases and drops whitespace and punctuation, so "Rune Dog!" and "rune dog" are the same
+pub fn normalise(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn commit(salt: &[u8], secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(normalise(secret).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub async fn new_salt() -> Vec<u8> {
+    let (salt,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .expect("failed to get randomness");
+    salt
+}
+
+impl SecretCommitment {
+    pub fn new(salt: Vec<u8>, secret: &str) -> Self {
+        Self {
+            commitment: commit(&salt, secret),
+            salt,
+        }
+    }
+
+    pub fn matches(&self, guess: &str) -> bool {
+        commit(&self.salt, guess) == self.commitment
+    }
+
+    /// publishes the salt along with the secret, which is the normalised winning guess
+    pub fn reveal(self, secret: &str) -> RevealedSecret {
+        RevealedSecret {
+            commitment: self.commitment,
+            salt: hex::encode(self.salt),
+            secret: normalise(secret),
+            revealed_at: ic_cdk::api::time(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_ignores_case_whitespace_and_punctuation() {
+        let secret = SecretCommitment::new(vec![7; 32], "Rune Dog");
+        assert!(secret.matches("rune dog"));
+        assert!(secret.matches("  RUNE-DOG!"));
+        assert!(!secret.matches("rune cat"));
+        assert_ne!(
+            SecretCommitment::new(vec![8; 32], "Rune Dog").commitment,
+            secret.commitment
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::commitment::{self, RevealedSecret, SecretCommitment};
+use crate::llm::Llm;
+use crate::state::{GuessConfig, LlmConfig};
+
+mod last_buyer;
+mod lucky_draw;
+mod riddle;
+
+pub use last_buyer::LastBuyer;
+pub use lucky_draw::{GameMode, LuckyDraw};
+pub use riddle::Riddle;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum GameKind {
+    LuckyDraw,
+    Riddle,
+    LastBuyer,
+}
+
+impl GameKind {
+    pub const ALL: [GameKind; 3] = [GameKind::LuckyDraw, GameKind::Riddle, GameKind::LastBuyer];
+}
+
+/// How hard the secrets and riddles the agent writes for its rounds are
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+/// State every game keeps about its running round
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Round {
+    pub prize_pool: (u64, u128), // (satoshis, runes)
+    pub winner: Option<Principal>,
+    pub entries: u64, // entries made in the round
+    pub started_at: u64,
+    pub past_winners: Vec<PastWinner>,
+    pub difficulty: Option<Difficulty>, // applies from the next round seeded
+}
+
+impl Round {
+    pub fn new(now: u64) -> Self {
+        Self {
+            started_at: now,
+            ..Default::default()
+        }
+    }
+
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty.unwrap_or_default()
+    }
+
+    /// leftovers of the prize pool carry over to the next round
+    pub fn restart(&mut self, now: u64) {
+        self.winner = None;
+        self.entries = 0;
+        self.started_at = now;
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PastWinner {
+    pub time: u64,
+    pub bitcoin: u64,
+    pub rune: u128,
+    pub winner: Principal,
+    pub note: String, // revealed secret or answer of the round, if any
+}
+
+/// Settings picked by the creator when a game is enabled
+#[derive(CandidType, Deserialize, Clone)]
+pub enum GameSettings {
+    LuckyDraw {
+        mode: GameMode,
+    },
+    Riddle,
+    LastBuyer {
+        duration_secs: u64, // the round ends once nobody bought for this long
+        min_buy: u64,       // satoshis a buy needs to spend to count
+        rune_prize: u128,   // runes put in the pool by the agent every round
+    },
+}
+
+impl GameSettings {
+    pub fn kind(&self) -> GameKind {
+        match self {
+            Self::LuckyDraw { .. } => GameKind::LuckyDraw,
+            Self::Riddle => GameKind::Riddle,
+            Self::LastBuyer { .. } => GameKind::LastBuyer,
+        }
+    }
+
+    /// `agent_rune` is the free runes of the agent, see `AgentDetail::free_runes`
+    pub fn validate(&self, agent_rune: u128) -> Result<(), String> {
+        match self {
+            Self::LastBuyer {
+                duration_secs,
+                rune_prize,
+                ..
+            } => LastBuyer::validate(*duration_secs, *rune_prize, agent_rune),
+            Self::LuckyDraw { .. } | Self::Riddle => Ok(()),
+        }
+    }
+}
+
+/// Everything a round needs that can only be produced asynchronously
+pub enum RoundSeed {
+    Secret {
+        secret: String,
+        commitment: SecretCommitment,
+    },
+    Riddle {
+        question: String,
+        commitment: SecretCommitment,
+    },
+    Empty,
+}
+
+pub async fn seed_round(
+    kind: GameKind,
+    llm: &LlmConfig,
+    difficulty: Difficulty,
+    agent_name: &str,
+    agent_description: &str,
+) -> RoundSeed {
+    match kind {
+        GameKind::LuckyDraw => {
+            let secret =
+                Llm::generate_secret_word(llm, difficulty, agent_name, agent_description).await;
+            let commitment = SecretCommitment::new(commitment::new_salt().await, &secret);
+            RoundSeed::Secret { secret, commitment }
+        }
+        GameKind::Riddle => {
+            let (question, answer) =
+                Llm::generate_riddle(llm, difficulty, agent_name, agent_description).await;
+            let commitment = SecretCommitment::new(commitment::new_salt().await, &answer);
+            RoundSeed::Riddle {
+                question,
+                commitment,
+            }
+        }
+        GameKind::LastBuyer => RoundSeed::Empty,
+    }
+}
+
+/// Public view of a game, secrets are left out
+#[derive(CandidType, Clone)]
+pub enum GameInfo {
+    LuckyDraw {
+        mode: GameMode,
+        commitment: Option<String>,
+        revealed_secrets: Vec<RevealedSecret>,
+    },
+    Riddle {
+        question: Option<String>,
+        commitment: Option<String>,
+        revealed_answers: Vec<RevealedSecret>,
+    },
+    LastBuyer {
+        last_buyer: Option<Principal>,
+        deadline: Option<u64>,
+        duration_secs: u64,
+        min_buy: u64,
+    },
+}
+
+#[derive(CandidType, Clone)]
+pub struct GameView {
+    pub kind: GameKind,
+    pub prize_pool: (u64, u128),
+    pub winner: Option<Principal>,
+    pub entries: u64,
+    pub entry_fee: Option<(u64, u128)>,
+    pub info: GameInfo,
+}
+
+/// A mini game run by an agent.
+/// Entry fees are charged by the canister once `play` accepts an entry, and a share of them goes
+/// into the round's prize pool. A round is over once it has a winner, the next one is started by
+/// `start_round` after the prize is paid out.
+pub trait Game {
+    fn kind(&self) -> GameKind;
+
+    fn round(&self) -> &Round;
+
+    fn round_mut(&mut self) -> &mut Round;
+
+    /// (satoshis, runes) charged for the next entry
+    /// none if the game isn't played with entries, `play` then rejects them with the reason
+    fn entry_fee(&self, _config: &GuessConfig) -> Option<(u64, u128)> {
+        None
+    }
+
+    /// handles a paid entry, nothing is charged if it's rejected
+    fn play(&mut self, _player: Principal, _input: &str) -> Result<String, String> {
+        Err(String::from("game isn't played with entries"))
+    }
+
+    /// called for every buy of the agent's runes
+    fn on_buy(&mut self, _buyer: Principal, _bitcoin: u64, _now: u64) {}
+
+    /// ends the round if it's due
+    fn on_tick(&mut self, _now: u64) {}
+
+    fn start_round(&mut self, seed: RoundSeed, now: u64);
+
+    /// recorded along with the winner of the round
+    fn round_note(&self) -> String {
+        String::new()
+    }
+
+    fn info(&self) -> GameInfo;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum GameInstance {
+    LuckyDraw(LuckyDraw),
+    Riddle(Riddle),
+    LastBuyer(LastBuyer),
+}
+
+impl GameInstance {
+    pub fn new(settings: GameSettings, seed: RoundSeed, config: &GuessConfig, now: u64) -> Self {
+        let mut instance = match settings {
+            GameSettings::LuckyDraw { mode } => {
+                Self::LuckyDraw(LuckyDraw::new(mode, config.initial_rune_prize, now))
+            }
+            GameSettings::Riddle => Self::Riddle(Riddle::new(now)),
+            GameSettings::LastBuyer {
+                duration_secs,
+                min_buy,
+                rune_prize,
+            } => Self::LastBuyer(LastBuyer::new(duration_secs, min_buy, rune_prize, now)),
+        };
+        instance.game_mut().start_round(seed, now);
+        instance
+    }
+
+    pub fn game(&self) -> &dyn Game {
+        match self {
+            Self::LuckyDraw(game) => game,
+            Self::Riddle(game) => game,
+            Self::LastBuyer(game) => game,
+        }
+    }
+
+    pub fn game_mut(&mut self) -> &mut dyn Game {
+        match self {
+            Self::LuckyDraw(game) => game,
+            Self::Riddle(game) => game,
+            Self::LastBuyer(game) => game,
+        }
+    }
+
+    pub fn view(&self, config: &GuessConfig) -> GameView {
+        let game = self.game();
+        let round = game.round();
+        GameView {
+            kind: game.kind(),
+            prize_pool: round.prize_pool,
+            winner: round.winner,
+            entries: round.entries,
+            entry_fee: game.entry_fee(config),
+            info: game.info(),
+        }
+    }
+}
+
+/// checks a guess against the round's secret, the round ends if it matches
+fn guess_secret(
+    round: &mut Round,
+    secret: &mut Option<SecretCommitment>,
+    revealed: &mut Vec<RevealedSecret>,
+    player: Principal,
+    guess: &str,
+) -> Result<String, String> {
+    let Some(commitment) = secret else {
+        return Err(String::from("Contest not started"));
+    };
+    if !commitment.matches(guess) {
+        return Ok(String::from("Better luck next time"));
+    }
+    // publishing the salt lets anyone check the commitment
+    revealed.push(secret.take().unwrap().reveal(guess));
+    round.winner.replace(player);
+    Ok(String::from("Congratulation"))
+}
@@ -0,0 +1,129 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use super::{Game, GameInfo, GameKind, Round, RoundSeed};
+
+// bounds on the settings picked by the creator
+pub const MIN_DURATION_SECS: u64 = 60;
+pub const MAX_DURATION_SECS: u64 = 7 * 24 * 60 * 60;
+// a round pays at most 1/RUNE_PRIZE_SHARE of the runes the agent holds
+pub const RUNE_PRIZE_SHARE: u128 = 100;
+
+/// Every buy above `min_buy` restarts the clock, the last buyer before it runs out wins
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LastBuyer {
+    pub round: Round,
+    pub duration_secs: u64,
+    pub min_buy: u64,
+    pub rune_prize: u128,
+    pub last_buyer: Option<Principal>,
+    pub deadline: Option<u64>,
+}
+
+impl LastBuyer {
+    /// checks the settings against the runes held by the agent, every round takes
+    /// `rune_prize` out of them
+    pub fn validate(duration_secs: u64, rune_prize: u128, agent_rune: u128) -> Result<(), String> {
+        if !(MIN_DURATION_SECS..=MAX_DURATION_SECS).contains(&duration_secs) {
+            return Err(format!(
+                "duration should be between {MIN_DURATION_SECS} and {MAX_DURATION_SECS} seconds"
+            ));
+        }
+        let max_prize = agent_rune / RUNE_PRIZE_SHARE;
+        if rune_prize > max_prize {
+            return Err(format!("rune prize can't be more than {max_prize}"));
+        }
+        Ok(())
+    }
+
+    pub fn new(duration_secs: u64, min_buy: u64, rune_prize: u128, now: u64) -> Self {
+        Self {
+            round: Round::new(now),
+            duration_secs,
+            min_buy,
+            rune_prize,
+            last_buyer: None,
+            deadline: None,
+        }
+    }
+}
+
+impl Game for LastBuyer {
+    fn kind(&self) -> GameKind {
+        GameKind::LastBuyer
+    }
+
+    fn round(&self) -> &Round {
+        &self.round
+    }
+
+    fn round_mut(&mut self) -> &mut Round {
+        &mut self.round
+    }
+
+    fn on_buy(&mut self, buyer: Principal, bitcoin: u64, now: u64) {
+        // a buy after the deadline doesn't steal the round from the last buyer
+        self.on_tick(now);
+        if self.round.winner.is_some() || bitcoin < self.min_buy {
+            return;
+        }
+        self.last_buyer.replace(buyer);
+        self.deadline
+            .replace(now.saturating_add(self.duration_secs.saturating_mul(1_000_000_000)));
+        self.round.entries += 1;
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        if self.round.winner.is_some() {
+            return;
+        }
+        if let (Some(buyer), Some(deadline)) = (self.last_buyer, self.deadline) {
+            if now >= deadline {
+                self.round.winner.replace(buyer);
+            }
+        }
+    }
+
+    fn start_round(&mut self, _seed: RoundSeed, now: u64) {
+        self.round.restart(now);
+        self.round.prize_pool.1 = self.round.prize_pool.1.saturating_add(self.rune_prize);
+        self.last_buyer = None;
+        self.deadline = None;
+    }
+
+    fn info(&self) -> GameInfo {
+        GameInfo::LastBuyer {
+            last_buyer: self.last_buyer,
+            deadline: self.deadline,
+            duration_secs: self.duration_secs,
+            min_buy: self.min_buy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn last_buyer_before_the_deadline_wins() {
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut game = LastBuyer::new(60, 1_000, 0, 0);
+        game.on_buy(alice, 1_000, 0);
+        game.on_buy(bob, 999, 30 * SECOND); // too small to count
+        game.on_tick(59 * SECOND);
+        assert!(game.round.winner.is_none());
+        game.on_buy(bob, 5_000, 60 * SECOND); // too late
+        assert_eq!(game.round.winner, Some(alice));
+        assert_eq!(game.last_buyer, Some(alice));
+    }
+
+    #[test]
+    fn settings_are_bounded() {
+        assert!(LastBuyer::validate(60, 10, 1_000).is_ok());
+        assert!(LastBuyer::validate(59, 0, 1_000).is_err());
+        assert!(LastBuyer::validate(u64::MAX, 0, 1_000).is_err());
+        assert!(LastBuyer::validate(60, 11, 1_000).is_err());
+    }
+}
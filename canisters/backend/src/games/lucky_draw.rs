@@ -0,0 +1,230 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use super::{Game, GameInfo, GameKind, Round, RoundSeed, guess_secret};
+use crate::commitment::{RevealedSecret, SecretCommitment, normalise};
+use crate::state::GuessConfig;
+
+const MAX_BPS: u128 = 10_000;
+
+/*
+ * whether the text says the secret in whole words
+ * "Rune-Dog", "rune dog" and "runedog" all say "rune dog", while "concatenate" doesn't say "cat"
+*/
+fn says_secret(text: &str, secret: &str) -> bool {
+    let secret = normalise(secret);
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(normalise)
+        .collect::<Vec<_>>();
+    !secret.is_empty()
+        && (0..words.len()).any(|start| {
+            let mut said = String::new();
+            for word in words[start..].iter() {
+                said.push_str(word);
+                if said.len() >= secret.len() {
+                    break;
+                }
+            }
+            said == secret
+        })
+}
+
+/// How a round of bait the bot is won
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum GameMode {
+    Guess,     // paid guesses through `lucky_draw`
+    Jailbreak, // making the agent reveal the secret it guards in chat
+}
+
+/// Bait the bot: players either guess the agent's secret word or make the agent say it in chat
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LuckyDraw {
+    pub round: Round,
+    pub mode: GameMode,
+    pub commitment: Option<SecretCommitment>, // none once the round is won
+    pub secret: Option<String>, // plaintext, only kept while a jailbreak round is running
+    pub revealed_secrets: Vec<RevealedSecret>,
+}
+
+impl LuckyDraw {
+    pub fn new(mode: GameMode, rune_prize: u128, now: u64) -> Self {
+        let mut round = Round::new(now);
+        round.prize_pool.1 = rune_prize;
+        Self {
+            round,
+            mode,
+            commitment: None,
+            secret: None,
+            revealed_secrets: vec![],
+        }
+    }
+
+    /// the secret the agent has to guard in chat
+    pub fn guarded_secret(&self) -> Option<&str> {
+        match (self.mode, self.round.winner) {
+            (GameMode::Jailbreak, None) => self.secret.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// whether a reply of the agent gives away the secret of a running jailbreak round
+    pub fn leaks_secret(&self, reply: &str) -> bool {
+        self.guarded_secret()
+            .is_some_and(|secret| says_secret(reply, secret))
+    }
+
+    /// ends the jailbreak round, returns the index of the revealed secret and its commitment
+    pub fn record_jailbreak(&mut self, winner: Principal) -> (u32, String) {
+        let secret = self
+            .secret
+            .take()
+            .expect("jailbreak round should have a secret");
+        let commitment = self
+            .commitment
+            .take()
+            .expect("jailbreak round should have a commitment");
+        let round = self.revealed_secrets.len() as u32;
+        let published = commitment.commitment.clone();
+        self.revealed_secrets.push(commitment.reveal(&secret));
+        self.round.winner.replace(winner);
+        (round, published)
+    }
+
+    /*
+     * ends a round nobody won so that a new one can be started
+     * returns the commitment of the round and whether its secret was revealed
+     * only jailbreak rounds hold the plaintext, the secret of a guess round is dropped unrevealed
+     */
+    pub fn abandon_round(&mut self) -> Result<(String, bool), String> {
+        if self.round.winner.is_some() {
+            return Err(String::from(
+                "round is won, the winner has to claim the prize first",
+            ));
+        }
+        let Some(commitment) = self.commitment.take() else {
+            return Ok((String::new(), false));
+        };
+        let published = commitment.commitment.clone();
+        match self.secret.take() {
+            Some(secret) => {
+                self.revealed_secrets.push(commitment.reveal(&secret));
+                Ok((published, true))
+            }
+            None => Ok((published, false)),
+        }
+    }
+}
+
+impl Game for LuckyDraw {
+    fn kind(&self) -> GameKind {
+        GameKind::LuckyDraw
+    }
+
+    fn round(&self) -> &Round {
+        &self.round
+    }
+
+    fn round_mut(&mut self) -> &mut Round {
+        &mut self.round
+    }
+
+    /// the price rises with every guess made in the round
+    fn entry_fee(&self, config: &GuessConfig) -> Option<(u64, u128)> {
+        if self.mode == GameMode::Jailbreak {
+            return None;
+        }
+        let step = self.round.entries as u128 * config.price_step_bps as u128;
+        let bitcoin = config.bitcoin_price as u128 * (MAX_BPS + step) / MAX_BPS;
+        let rune = config.rune_price * (MAX_BPS + step) / MAX_BPS;
+        Some((bitcoin as u64, rune))
+    }
+
+    fn play(&mut self, player: Principal, input: &str) -> Result<String, String> {
+        if self.mode == GameMode::Jailbreak {
+            return Err(String::from(
+                "Secret can only be won by making the agent reveal it in chat",
+            ));
+        }
+        guess_secret(
+            &mut self.round,
+            &mut self.commitment,
+            &mut self.revealed_secrets,
+            player,
+            input,
+        )
+    }
+
+    fn start_round(&mut self, seed: RoundSeed, now: u64) {
+        self.round.restart(now);
+        if let RoundSeed::Secret { secret, commitment } = seed {
+            self.commitment.replace(commitment);
+            // the agent only needs to know its secret when it has to guard it in chat
+            self.secret = (self.mode == GameMode::Jailbreak).then_some(secret);
+        }
+    }
+
+    fn round_note(&self) -> String {
+        self.revealed_secrets
+            .last()
+            .map(|revealed| revealed.secret.clone())
+            .unwrap_or_default()
+    }
+
+    fn info(&self) -> GameInfo {
+        GameInfo::LuckyDraw {
+            mode: self.mode,
+            commitment: self
+                .commitment
+                .as_ref()
+                .map(|secret| secret.commitment.clone()),
+            revealed_secrets: self.revealed_secrets.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_jailbreak_secrets_are_guarded_in_chat() {
+        let mut game = LuckyDraw::new(GameMode::Guess, 0, 0);
+        game.secret.replace(String::from("rune dog"));
+        assert!(game.guarded_secret().is_none());
+        assert!(!game.leaks_secret("it's rune dog"));
+        game.mode = GameMode::Jailbreak;
+        assert_eq!(game.guarded_secret(), Some("rune dog"));
+        game.round.winner.replace(Principal::anonymous());
+        assert!(game.guarded_secret().is_none());
+    }
+
+    #[test]
+    fn guess_rounds_keep_no_plaintext() {
+        let mut game = LuckyDraw::new(GameMode::Guess, 0, 0);
+        let commitment = SecretCommitment::new(vec![1; 32], "rune dog");
+        let published = commitment.commitment.clone();
+        game.start_round(
+            RoundSeed::Secret {
+                secret: String::from("rune dog"),
+                commitment,
+            },
+            1,
+        );
+        assert!(game.secret.is_none());
+        // abandoning the round drops its secret unrevealed
+        assert_eq!(game.abandon_round(), Ok((published, false)));
+        assert!(game.commitment.is_none());
+        assert!(game.revealed_secrets.is_empty());
+    }
+
+    #[test]
+    fn secret_is_only_said_in_whole_words() {
+        assert!(says_secret("The word is CAT!", "cat"));
+        assert!(says_secret("it's rune-dog, promise", "Rune Dog"));
+        assert!(says_secret("runedog", "rune dog"));
+        assert!(!says_secret("let me concatenate these", "cat"));
+        assert!(!says_secret("cats and dogs", "cat"));
+        assert!(!says_secret("anything", ""));
+    }
+}
@@ -0,0 +1,81 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use super::{Game, GameInfo, GameKind, Round, RoundSeed, guess_secret};
+use crate::commitment::{RevealedSecret, SecretCommitment};
+use crate::state::GuessConfig;
+
+/// A riddle written by the agent, the first correct answer wins the pool
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Riddle {
+    pub round: Round,
+    pub question: Option<String>,
+    pub answer: Option<SecretCommitment>, // none once the round is won
+    pub revealed_answers: Vec<RevealedSecret>,
+}
+
+impl Riddle {
+    pub fn new(now: u64) -> Self {
+        Self {
+            round: Round::new(now),
+            question: None,
+            answer: None,
+            revealed_answers: vec![],
+        }
+    }
+}
+
+impl Game for Riddle {
+    fn kind(&self) -> GameKind {
+        GameKind::Riddle
+    }
+
+    fn round(&self) -> &Round {
+        &self.round
+    }
+
+    fn round_mut(&mut self) -> &mut Round {
+        &mut self.round
+    }
+
+    /// every answer costs the base price of a guess
+    fn entry_fee(&self, config: &GuessConfig) -> Option<(u64, u128)> {
+        Some((config.bitcoin_price, config.rune_price))
+    }
+
+    fn play(&mut self, player: Principal, input: &str) -> Result<String, String> {
+        guess_secret(
+            &mut self.round,
+            &mut self.answer,
+            &mut self.revealed_answers,
+            player,
+            input,
+        )
+    }
+
+    fn start_round(&mut self, seed: RoundSeed, now: u64) {
+        self.round.restart(now);
+        if let RoundSeed::Riddle {
+            question,
+            commitment,
+        } = seed
+        {
+            self.question.replace(question);
+            self.answer.replace(commitment);
+        }
+    }
+
+    fn round_note(&self) -> String {
+        self.revealed_answers
+            .last()
+            .map(|revealed| revealed.secret.clone())
+            .unwrap_or_default()
+    }
+
+    fn info(&self) -> GameInfo {
+        GameInfo::Riddle {
+            question: self.question.clone(),
+            commitment: self.answer.as_ref().map(|answer| answer.commitment.clone()),
+            revealed_answers: self.revealed_answers.clone(),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::bitcoin::wallet::Wallet;
 use crate::bitcoin_lib::hashes::Hash;
 use crate::state::{read_config, read_utxo_manager, utxo_manager::RunicUtxo, write_utxo_manager};
 use candid::{CandidType, Decode, Deserialize, Encode};
@@ -102,6 +103,17 @@ pub enum TargetType {
     Runic { runeid: RuneId, target: u128 },
 }
 
+/// fetches the bitcoin utxos of every address of the wallet
+pub async fn fetch_wallet_utxos(wallet: &Wallet) {
+    for (_, address) in wallet.addresses.iter() {
+        fetch_utxos_and_update(
+            &address.to_string(),
+            TargetType::Bitcoin { target: u64::MAX },
+        )
+        .await;
+    }
+}
+
 pub async fn fetch_utxos_and_update(addr: &str, target: TargetType) {
     let network = read_config(|config| config.bitcoin_network());
     let mut arg = GetUtxosRequest {
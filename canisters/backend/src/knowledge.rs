@@ -0,0 +1,175 @@
+use candid::{CandidType, Deserialize};
+
+use crate::commitment::normalise;
+
+const MAX_TONE_LEN: usize = 500;
+const MAX_RULES: usize = 20;
+const MAX_RULE_LEN: usize = 300;
+const MAX_BANNED_TOPICS: usize = 50;
+pub const MAX_DOCS: usize = 20; // knowledge documents per agent
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DOC_LEN: usize = 16 * 1024;
+const SNIPPET_LEN: usize = 600; // documents are retrieved in chunks of about this many bytes
+
+/// How the agent talks, set by its creator
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Persona {
+    pub tone: String,
+    pub rules: Vec<String>,
+    pub banned_topics: Vec<String>,
+}
+
+impl Persona {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tone.len() > MAX_TONE_LEN {
+            return Err(format!("tone can't be longer than {MAX_TONE_LEN} bytes"));
+        }
+        if self.rules.len() > MAX_RULES || self.rules.iter().any(|rule| rule.len() > MAX_RULE_LEN) {
+            return Err(format!(
+                "up to {MAX_RULES} rules of at most {MAX_RULE_LEN} bytes are allowed"
+            ));
+        }
+        if self.banned_topics.len() > MAX_BANNED_TOPICS
+            || self
+                .banned_topics
+                .iter()
+                .any(|topic| topic.len() > MAX_RULE_LEN)
+        {
+            return Err(format!(
+                "up to {MAX_BANNED_TOPICS} banned topics are allowed"
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn to_prompt(&self) -> String {
+        let mut prompt = String::from("\n    PERSONA set by your creator:\n");
+        if !self.tone.trim().is_empty() {
+            prompt.push_str(&format!("    tone: {}\n", self.tone.trim()));
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            prompt.push_str(&format!("    {}: {}\n", i + 1, rule.trim()));
+        }
+        if !self.banned_topics.is_empty() {
+            prompt.push_str(&format!(
+                "    never talk about these topics, politely refuse instead: {}\n",
+                self.banned_topics.join(", ")
+            ));
+        }
+        prompt
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct KnowledgeDoc {
+    pub title: String,
+    pub content: String,
+    pub updated_at: u64,
+}
+
+pub fn validate_doc(title: &str, content: &str) -> Result<(), String> {
+    if title.trim().is_empty() || title.len() > MAX_TITLE_LEN {
+        return Err(format!("title should be 1 to {MAX_TITLE_LEN} bytes"));
+    }
+    if content.trim().is_empty() || content.len() > MAX_DOC_LEN {
+        return Err(format!("content should be 1 to {MAX_DOC_LEN} bytes"));
+    }
+    Ok(())
+}
+
+// words too common to tell snippets apart
+const STOPWORDS: [&str; 24] = [
+    "the", "and", "for", "are", "but", "not", "you", "your", "with", "this", "that", "what", "how",
+    "who", "why", "when", "where", "can", "does", "about", "from", "have", "has", "was",
+];
+
+fn keywords(text: &str) -> Vec<String> {
+    let mut words = text
+        .split_whitespace()
+        .map(normalise)
+        .filter(|word| word.len() > 2 && !STOPWORDS.contains(&word.as_str()))
+        .collect::<Vec<_>>();
+    words.sort();
+    words.dedup();
+    words
+}
+
+// paragraphs, merged or split so that a snippet is about `SNIPPET_LEN` bytes
+fn snippets(content: &str) -> Vec<String> {
+    let mut snippets = Vec::new();
+    let mut current = String::new();
+    for word in content.split_whitespace() {
+        if current.len() + word.len() > SNIPPET_LEN && !current.is_empty() {
+            snippets.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    if !current.is_empty() {
+        snippets.push(current);
+    }
+    snippets
+}
+
+/// Snippets of the documents sharing the most keywords with the query, best match first.
+/// Matching is done on keywords as the canister has no embedding model to call.
+pub fn retrieve(docs: &[KnowledgeDoc], query: &str, limit: usize) -> Vec<String> {
+    let query = keywords(query);
+    if query.is_empty() {
+        return Vec::new();
+    }
+    let mut scored = docs
+        .iter()
+        .flat_map(|doc| {
+            snippets(&doc.content)
+                .into_iter()
+                .map(move |snippet| (doc.title.as_str(), snippet))
+        })
+        .filter_map(|(title, snippet)| {
+            let words = keywords(&format!("{title} {snippet}"));
+            let score = query
+                .iter()
+                .filter(|word| words.binary_search(word).is_ok())
+                .count();
+            (score > 0).then(|| (score, format!("[{title}] {snippet}")))
+        })
+        .collect::<Vec<_>>();
+    // stable, so equally good snippets keep the order of the documents
+    scored.sort_by(|a, b| b.0.cmp(&a.0));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, snippet)| snippet)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retrieves_snippets_sharing_keywords_with_the_query() {
+        let doc = |title: &str, content: &str| KnowledgeDoc {
+            title: title.to_string(),
+            content: content.to_string(),
+            updated_at: 0,
+        };
+        let docs = vec![
+            doc(
+                "roadmap",
+                "Staking launches next month, followed by a mobile app.",
+            ),
+            doc("team", "The token was created by two bitcoin developers."),
+        ];
+        let found = retrieve(&docs, "When does staking launch?", 3);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("[roadmap]"));
+        assert!(retrieve(&docs, "what is the weather", 3).is_empty());
+        assert_eq!(
+            retrieve(&docs, "who created the token and the staking?", 1).len(),
+            1
+        );
+    }
+}
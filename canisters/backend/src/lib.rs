@@ -1,7 +1,15 @@
 // modules
+mod autopilot;
 mod bitcoin;
+mod broadcast;
+mod commitment;
+mod games;
 mod indexer;
+mod knowledge;
 mod llm;
+mod migration;
+mod rate_limiter;
+mod settlement;
 mod state;
 mod tools;
 mod txn_handler;
@@ -10,6 +18,7 @@ mod utils;
 use bitcoin::runestone::etch::EtchingArgs;
 use state::*;
 use std::collections::HashMap;
+use std::str::FromStr;
 
 use candid::{CandidType, define_function};
 
@@ -21,7 +30,7 @@ use ic_cdk::api::management_canister::ecdsa::{EcdsaPublicKeyArgument, ecdsa_publ
 use ic_cdk::api::management_canister::schnorr::{
     SchnorrPublicKeyArgument, SchnorrPublicKeyResponse as SchnorrPublicKey, schnorr_public_key,
 };
-use ic_cdk::{init, query, update};
+use ic_cdk::{init, post_upgrade, query, update};
 use serde::Deserialize;
 
 async fn lazy_ecdsa_schnorr_setup() {
@@ -83,17 +92,31 @@ pub fn init(
         temp.bitcoin_network = bitcoin_network;
         temp.commission_receiver = commission_receiver;
         temp.allowed_agent_count = max_allowed_agent;
+        temp.auth = Some(caller);
+        temp.version = Some(migration::CURRENT_VERSION);
         config.set(temp).expect("failed to set config");
     });
     ic_cdk_timers::set_timer(std::time::Duration::from_secs(5), || {
         ic_cdk::spawn(lazy_ecdsa_schnorr_setup())
     });
+    settlement::start_settlement_timer();
+    broadcast::start_broadcast_timer();
+    start_session_sweeper();
+    start_lease_sweeper();
+    autopilot::start_autopilot_timer();
 }
 
-/* canister upgrade hooks
-pub fn pre_upgrade() {}
-pub fn post_upgrade() {}
-*/
+// every state lives in stable memory, only the timers have to be restored
+#[post_upgrade]
+pub fn post_upgrade() {
+    migration::migrate();
+    settlement::start_settlement_timer();
+    broadcast::start_broadcast_timer();
+    txn_handler::rearm_timers();
+    start_session_sweeper();
+    start_lease_sweeper();
+    autopilot::start_autopilot_timer();
+}
 
 #[update]
 pub fn increase_allowed_agent_count(by: u128) {
@@ -108,28 +131,178 @@ pub fn increase_allowed_agent_count(by: u128) {
     })
 }
 
+#[update]
+pub fn set_graduation_config(graduation_market_cap: u64, lp_fee_bps: u16) {
+    let caller = ic_cdk::caller();
+    if lp_fee_bps >= 10_000 {
+        ic_cdk::trap("invalid lp fee")
+    }
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        if temp.auth != Some(caller) {
+            ic_cdk::trap("Unauthorized")
+        }
+        temp.graduation_market_cap.replace(graduation_market_cap);
+        temp.lp_fee_bps.replace(lp_fee_bps);
+        let _ = config.set(temp);
+    })
+}
+
+#[update]
+pub fn set_fee_policy(fee_policy: FeePolicy) {
+    let caller = ic_cdk::caller();
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        if temp.auth != Some(caller) {
+            ic_cdk::trap("Unauthorized")
+        }
+        if let Err(err) = fee_policy.validate() {
+            ic_cdk::trap(&err)
+        }
+        temp.fee_policy.replace(fee_policy);
+        let _ = config.set(temp);
+    });
+    bitcoin::fee::clear_cache();
+}
+
+#[update]
+pub fn set_rbf_config(rbf: RbfConfig) {
+    let caller = ic_cdk::caller();
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        if temp.auth != Some(caller) {
+            ic_cdk::trap("Unauthorized")
+        }
+        if let Err(err) = rbf.validate() {
+            ic_cdk::trap(&err)
+        }
+        temp.rbf.replace(rbf);
+        let _ = config.set(temp);
+    })
+}
+
+#[update]
+pub fn set_autopilot_config(autopilot: AutopilotConfig) {
+    let caller = ic_cdk::caller();
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        if temp.auth != Some(caller) {
+            ic_cdk::trap("Unauthorized")
+        }
+        if let Err(err) = autopilot.validate() {
+            ic_cdk::trap(&err)
+        }
+        temp.autopilot.replace(autopilot);
+        let _ = config.set(temp);
+    })
+}
+
+#[update]
+pub fn set_llm_config(llm: LlmConfig) {
+    let caller = ic_cdk::caller();
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        if temp.auth != Some(caller) {
+            ic_cdk::trap("Unauthorized")
+        }
+        if let Err(err) = llm.validate() {
+            ic_cdk::trap(&err)
+        }
+        temp.llm.replace(llm);
+        let _ = config.set(temp);
+    })
+}
+
+/// none clears the overrides, the agent then uses the canister's llm config
+#[update]
+pub fn set_agent_llm_config(agent: AgentBy, overrides: Option<LlmOverrides>) {
+    let caller = ic_cdk::caller();
+    if read_config(|config| config.auth) != Some(caller) {
+        ic_cdk::trap("Unauthorized")
+    }
+    if let Some(ref overrides) = overrides {
+        let llm = read_config(|config| config.llm_config()).with_overrides(overrides);
+        if let Err(err) = llm.validate() {
+            ic_cdk::trap(&err)
+        }
+    }
+    write_agents(|agents| {
+        let id = agents.find_agent_id(agent).expect("agent doesn't exist");
+        let mut agent = agents.mapping.get(&id).unwrap();
+        agent.llm = overrides;
+        agents.mapping.insert(id, agent);
+    })
+}
+
+#[query]
+pub fn get_llm_config(agent: Option<AgentBy>) -> LlmConfig {
+    match agent {
+        None => read_config(|config| config.llm_config()),
+        Some(agent) => read_agents(|agents| {
+            let id = agents.find_agent_id(agent).expect("agent doesn't exist");
+            agents.mapping.get(&id).unwrap().llm_config()
+        }),
+    }
+}
+
+#[update]
+pub fn set_guess_config(guess: GuessConfig) {
+    let caller = ic_cdk::caller();
+    if guess.prize_share_bps > 10_000 {
+        ic_cdk::trap("invalid prize share")
+    }
+    write_config(|config| {
+        let mut temp = config.get().clone();
+        if temp.auth != Some(caller) {
+            ic_cdk::trap("Unauthorized")
+        }
+        temp.guess.replace(guess);
+        let _ = config.set(temp);
+    })
+}
+
 #[query]
 pub fn get_deposit_address() -> String {
     let caller = ic_cdk::caller();
-    let account = utils::get_account_for(&caller);
-    bitcoin::account_to_p2pkh_address(&account)
+    utils::get_wallet_for(&caller).address().to_string()
+}
+
+/// switches the caller's deposit address to the given type and returns it
+/// bitcoin left on the previous addresses is still counted and spent
+#[update]
+pub fn set_address_type(address_type: bitcoin::AddressType) -> String {
+    let caller = ic_cdk::caller();
+    write_ledger_entries(|entries| {
+        let mut entry = entries.get(&caller).unwrap_or_default();
+        entry.prefer_address_type(address_type);
+        entries.insert(caller, entry);
+    });
+    utils::get_wallet_for(&caller).address().to_string()
+}
+
+// confirmed and unconfirmed balance of every address of the wallet
+async fn wallet_balance(wallet: &bitcoin::wallet::Wallet) -> u64 {
+    let network = read_config(|config| config.bitcoin_network());
+    let mut balance = 0;
+    for (_, address) in wallet.addresses.iter() {
+        balance += ic_cdk::api::management_canister::bitcoin::bitcoin_get_balance(
+            ic_cdk::api::management_canister::bitcoin::GetBalanceRequest {
+                address: address.to_string(),
+                network,
+                min_confirmations: None,
+            },
+        )
+        .await
+        .unwrap()
+        .0;
+    }
+    balance
 }
 
 #[update]
 pub async fn get_bitcoin_balance() -> u64 {
     let caller = ic_cdk::caller();
-    let account = utils::get_account_for(&caller);
-    let bitcoin_address = bitcoin::account_to_p2pkh_address(&account);
-    let bitcoin_balance = ic_cdk::api::management_canister::bitcoin::bitcoin_get_balance(
-        ic_cdk::api::management_canister::bitcoin::GetBalanceRequest {
-            address: bitcoin_address,
-            network: read_config(|config| config.bitcoin_network()),
-            min_confirmations: None,
-        },
-    )
-    .await
-    .unwrap()
-    .0;
+    let bitcoin_balance = wallet_balance(&utils::get_wallet_for(&caller)).await;
     read_ledger_entries(|entries| {
         let entry = entries.get(&caller).unwrap_or_default();
         bitcoin_balance - entry.restricted_bitcoin_balance
@@ -139,18 +312,7 @@ pub async fn get_bitcoin_balance() -> u64 {
 #[update]
 pub async fn get_balances() -> HashMap<String, u128> {
     let caller = ic_cdk::caller();
-    let account = utils::get_account_for(&caller);
-    let bitcoin_address = bitcoin::account_to_p2pkh_address(&account);
-    let bitcoin_balance = ic_cdk::api::management_canister::bitcoin::bitcoin_get_balance(
-        ic_cdk::api::management_canister::bitcoin::GetBalanceRequest {
-            address: bitcoin_address,
-            network: read_config(|config| config.bitcoin_network()),
-            min_confirmations: None,
-        },
-    )
-    .await
-    .unwrap()
-    .0;
+    let bitcoin_balance = wallet_balance(&utils::get_wallet_for(&caller)).await;
     read_ledger_entries(|entries| {
         let entry = entries.get(&caller).unwrap_or_default();
         let mut map = HashMap::new();
@@ -173,8 +335,129 @@ pub enum WithdrawalType {
 }
 
 #[update]
-pub async fn withdraw(to: String, withdrawal_type: WithdrawalType) -> u128 {
-    todo!()
+pub async fn withdraw(
+    to: String,
+    withdrawal_type: WithdrawalType,
+    fee: Option<bitcoin::fee::FeeRate>,
+) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let receiver = bitcoin::address_validation(&to).unwrap_or_else(|err| ic_cdk::trap(&err));
+    let sender = utils::get_wallet_for(&caller);
+
+    match withdrawal_type {
+        WithdrawalType::Bitcoin { amount } => {
+            if amount < bitcoin::DUST_THRESHOLD {
+                ic_cdk::trap("amount is below dust threshold")
+            }
+            indexer::fetch_wallet_utxos(&sender).await;
+            let fee_per_vbytes = bitcoin::fee::fee_per_vbyte(fee).await;
+
+            let bitcoin_balance = read_ledger_entries(|entries| {
+                let entry = entries.get(&caller).unwrap_or_default();
+                let balance = read_utxo_manager(|manager| sender.bitcoin_balance(manager));
+                balance - entry.restricted_bitcoin_balance
+            });
+            if amount > bitcoin_balance {
+                ic_cdk::trap("not enough balance")
+            }
+
+            // fee is deducted from the withdrawn amount
+            let handler = bitcoin::transfer(bitcoin::transaction::BtcTransferArgs {
+                sender,
+                receiver,
+                amount,
+                paid_by_sender: false,
+                fee_per_vbytes,
+            })
+            .unwrap_or_else(|required_balance| {
+                ic_cdk::println!("required balance: {}", required_balance);
+                ic_cdk::trap("not enough balance")
+            });
+
+            match handler.submit().await? {
+                txn_handler::SubmittedTxidType::Bitcoin { txid } => Ok(txid),
+            }
+        }
+        WithdrawalType::Rune { runeid, amount } => {
+            let (agent_id, runeid, agent_wallet) = read_agents(|agents| {
+                let id = agents.find_agent_id(runeid).expect("agent doesn't exist");
+                let agent = agents.mapping.get(&id).unwrap();
+                let runeid = match agent.runeid {
+                    None => ic_cdk::trap("rune isn't etched yet"),
+                    Some(ref runeid) => indexer::RuneId::from_str(runeid).unwrap(),
+                };
+                (id, runeid, agent.wallet())
+            });
+
+            indexer::fetch_wallet_utxos(&sender).await;
+            indexer::fetch_utxos_and_update(
+                &agent_wallet.address().to_string(),
+                indexer::TargetType::Runic {
+                    runeid,
+                    target: amount,
+                },
+            )
+            .await;
+            let fee_per_vbytes = bitcoin::fee::fee_per_vbyte(fee).await;
+
+            let (rune_balance, bitcoin_balance) = read_ledger_entries(|entries| {
+                let entry = entries.get(&caller).unwrap_or_default();
+                let balance = read_utxo_manager(|manager| sender.bitcoin_balance(manager));
+                let rune_balance = entry
+                    .ledger_entries
+                    .get(&agent_id)
+                    .copied()
+                    .unwrap_or_default()
+                    .1;
+                (rune_balance, balance - entry.restricted_bitcoin_balance)
+            });
+            if amount == 0 || amount > rune_balance {
+                ic_cdk::trap("not enough balance")
+            }
+
+            // debiting before the broadcast so that the same balance can't be withdrawn twice
+            write_ledger_entries(|entries| {
+                let mut entry = entries.get(&caller).unwrap_or_default();
+                entry.deduct_rune_balance(caller, agent_id, amount);
+                entries.insert(caller, entry);
+            });
+
+            // runes are sent from the agent's address, network fee is paid by the user
+            let handler = bitcoin::runestone::transfer::transfer(
+                bitcoin::runestone::transfer::RuneTransferArgs {
+                    runeid,
+                    rune_amount: amount,
+                    rune_sender: agent_wallet,
+                    rune_receiver: receiver,
+                    fee_payer: sender,
+                    postage: None,
+                    fee_per_vbytes,
+                },
+            )
+            .unwrap_or_else(|(required_rune, required_fee)| {
+                ic_cdk::println!("required rune: {}, fee: {}", required_rune, required_fee);
+                ic_cdk::trap("not enough balance")
+            });
+
+            if let txn_handler::TransactionType::Rune { fee, postage, .. } = &handler {
+                if fee + postage.to_sat() * 2 > bitcoin_balance {
+                    ic_cdk::trap("not enough bitcoin balance for paying the fee")
+                }
+            }
+
+            match handler.submit().await {
+                Ok(txn_handler::SubmittedTxidType::Bitcoin { txid }) => Ok(txid),
+                Err(err) => {
+                    write_ledger_entries(|entries| {
+                        let mut entry = entries.get(&caller).unwrap_or_default();
+                        entry.record_rune_balance(caller, agent_id, amount);
+                        entries.insert(caller, entry);
+                    });
+                    Err(err)
+                }
+            }
+        }
+    }
 }
 
 #[derive(CandidType)]
@@ -195,12 +478,38 @@ pub struct AgentDetails {
     pub market_cap: u64,
     pub current_prize_pool: (u64, u128),
     pub current_winner: Option<candid::Principal>,
+    pub games: Vec<games::GameView>,
     pub txns: (Option<String>, Option<String>),
+    pub lifecycle: AgentLifecycle,
+    pub graduated_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub enum AgentSort {
+    CreatedAt,
+    MarketCap,
+    Holders,
+    Volume24h,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAgentsArgs {
+    pub cursor: Option<u64>, // `next_cursor` of the previous page
+    pub limit: u32,
+    pub sort_by: AgentSort,
+    pub created_by: Option<candid::Principal>,
+    pub lifecycle: Option<AgentLifecycle>,
+}
+
+#[derive(CandidType)]
+pub struct AgentsPage {
+    pub agents: Vec<(u128, AgentDetails)>,
+    pub next_cursor: Option<u64>,
 }
 
 #[query]
-pub fn get_agents() -> HashMap<u128, AgentDetails> {
-    read_agents(|agents| agents.get_agents())
+pub fn get_agents(args: GetAgentsArgs) -> AgentsPage {
+    read_agents(|agents| agents.get_agents(args))
 }
 
 #[query]
@@ -211,6 +520,29 @@ pub fn get_agent_of(id: AgentBy) -> Option<AgentDetails> {
     })
 }
 
+#[derive(CandidType)]
+pub struct Holder {
+    pub holder: candid::Principal,
+    pub balance: u128,
+    pub share: f64, // fraction of the total supply
+}
+
+#[query]
+pub fn get_holders(agent: AgentBy, offset: u32, limit: u32) -> Vec<Holder> {
+    let (agent_id, total_supply) = read_agents(|agents| {
+        let id = agents.find_agent_id(agent).expect("agent doesn't exist");
+        (id, agents.mapping.get(&id).unwrap().total_supply)
+    });
+    read_holders(|holders| holders.get_holders(agent_id, offset as usize, limit.min(100) as usize))
+        .into_iter()
+        .map(|(holder, balance)| Holder {
+            holder,
+            balance,
+            share: balance as f64 / total_supply as f64,
+        })
+        .collect()
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct CreateAgentArgs {
     pub name: String,
@@ -221,6 +553,9 @@ pub struct CreateAgentArgs {
     pub twitter: Option<String>,
     pub openchat: Option<String>,
     pub discord: Option<String>,
+    pub games: Option<Vec<games::GameSettings>>, // defaults to a lucky draw with guesses
+    pub address_type: Option<bitcoin::AddressType>, // of the agent's address, defaults to legacy
+    pub fee: Option<bitcoin::fee::FeeRate>,      // of the etching, defaults to the normal tier
 }
 
 #[update]
@@ -234,23 +569,31 @@ pub async fn create_agent(
         twitter,
         openchat,
         discord,
+        games: game_settings,
+        address_type,
+        fee,
     }: CreateAgentArgs,
 ) -> u128 {
     let caller = ic_cdk::caller();
-    let account = utils::get_account_for(&caller);
-    let bitcoin_address = bitcoin::account_to_p2pkh_address(&account);
+    let fee_payer = utils::get_wallet_for(&caller);
+    let game_settings = game_settings.unwrap_or(vec![games::GameSettings::LuckyDraw {
+        mode: games::GameMode::Guess,
+    }]);
+    for (index, settings) in game_settings.iter().enumerate() {
+        if game_settings[..index]
+            .iter()
+            .any(|other| other.kind() == settings.kind())
+        {
+            ic_cdk::trap("a game can only be picked once")
+        }
+    }
 
     //get the balance
-    indexer::fetch_utxos_and_update(
-        &bitcoin_address,
-        indexer::TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
+    indexer::fetch_wallet_utxos(&fee_payer).await;
 
     let bitcoin_balance = read_ledger_entries(|entries| {
         let entry = entries.get(&caller).unwrap_or_default();
-        let bitcoin_balance =
-            read_utxo_manager(|manager| manager.get_bitcoin_balance(&bitcoin_address));
+        let bitcoin_balance = read_utxo_manager(|manager| fee_payer.bitcoin_balance(manager));
         bitcoin_balance - entry.restricted_bitcoin_balance
     });
 
@@ -272,8 +615,20 @@ pub async fn create_agent(
         ic_cdk::trap("Rune already taken")
     }
 
-    let secret = llm::Llm::generate_secret_word(&spaced_rune.to_string(), &description).await;
-    ic_cdk::println!("secret word: {}", secret);
+    // the agent doesn't exist yet, so its rounds are seeded with the canister's llm config
+    let llm = read_config(|config| config.llm_config());
+    let mut seeds = Vec::with_capacity(game_settings.len());
+    for settings in game_settings.iter() {
+        let seed = games::seed_round(
+            settings.kind(),
+            &llm,
+            games::Difficulty::default(),
+            &spaced_rune.to_string(),
+            &description,
+        )
+        .await;
+        seeds.push(seed);
+    }
 
     let (id, (logo_url, agent_address)) = write_agents(|agents| {
         let id = agents.get_agent_id();
@@ -282,7 +637,6 @@ pub async fn create_agent(
             id,
             caller,
             allocated_raw_subaccount,
-            secret,
             spaced_rune.to_string(),
             symbol.unwrap_or('•') as u32,
             logo,
@@ -292,9 +646,17 @@ pub async fn create_agent(
             openchat,
             discord,
             total_supply,
+            address_type.unwrap_or_default(),
         );
         (id, resp)
     });
+    let config = read_config(|config| config.guess_config());
+    write_games(|state| {
+        for (settings, seed) in game_settings.into_iter().zip(seeds) {
+            let instance = games::GameInstance::new(settings, seed, &config, ic_cdk::api::time());
+            state.insert(id, instance);
+        }
+    });
 
     let (content_type, logo) = match logo_url {
         None => (None, None),
@@ -304,10 +666,9 @@ pub async fn create_agent(
         ),
     };
 
-    let fee_payer = bitcoin::address_validation(&bitcoin_address).unwrap();
     let agent_address = bitcoin::address_validation(&agent_address).unwrap();
 
-    let fee_per_vbytes = bitcoin::get_fee_per_vbyte().await;
+    let fee_per_vbytes = bitcoin::fee::fee_per_vbyte(fee).await;
 
     let handler = match bitcoin::runestone::etch::etch(EtchingArgs {
         agent_id: id,
@@ -321,7 +682,6 @@ pub async fn create_agent(
         turbo: true,
         fee_payer,
         fee_per_vbytes,
-        fee_payer_account: account,
     })
     .await
     {
@@ -342,10 +702,36 @@ pub async fn create_agent(
             handler
         }
     };
-    handler.submit().await;
+    if let Err(err) = handler.submit().await {
+        ic_cdk::trap(&err)
+    }
     id
 }
 
+/*
+ * lifts the fee rate of an etching's unconfirmed commit with a child spending the commit's change
+ * callable by the creator of the agent and the admin, the child is paid by the creator
+*/
+#[update]
+pub async fn accelerate_etching(
+    id: AgentBy,
+    fee_rate: bitcoin::fee::FeeRate,
+) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let agent_id = read_agents(|agents| {
+        let id = agents
+            .find_agent_id(id)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
+        let creator = agents.mapping.get(&id).unwrap().created_by;
+        if caller != creator && read_config(|config| config.auth) != Some(caller) {
+            return Err(String::from("Unauthorized"));
+        }
+        Ok(id)
+    })?;
+    let fee_per_vbytes = bitcoin::fee::fee_per_vbyte(Some(fee_rate)).await;
+    txn_handler::accelerate_etching(agent_id, fee_per_vbytes).await
+}
+
 #[derive(CandidType, Deserialize)]
 pub enum AgentBy {
     Id(u128),
@@ -357,49 +743,85 @@ pub struct BuyArgs {
     pub id: AgentBy,
     pub buy_exact_in: u64,
     pub amount_out_min: u128,
+    pub deliver_to: Option<String>, // runes are sent on-chain to this address during settlement
 }
 
 #[update]
-pub async fn buy(
+pub async fn buy(args: BuyArgs) -> u128 {
+    execute_buy(ic_cdk::caller(), args)
+        .await
+        .unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// shared by `buy` and the buy tool of the chat, returns the tokens bought
+async fn execute_buy(
+    caller: candid::Principal,
     BuyArgs {
         id,
         buy_exact_in,
         amount_out_min,
+        deliver_to,
     }: BuyArgs,
-) -> u128 {
-    let caller = ic_cdk::caller();
-    let account = utils::get_account_for(&caller);
-    let bitcoin_address = bitcoin::account_to_p2pkh_address(&account);
-    indexer::fetch_utxos_and_update(
-        &bitcoin_address,
-        indexer::TargetType::Bitcoin { target: u64::MAX },
-    )
-    .await;
-    let bitcoin_balance = read_ledger_entries(|entries| {
-        let entry = entries.get(&caller).unwrap_or_default();
-        let balance = read_utxo_manager(|manager| manager.get_bitcoin_balance(&bitcoin_address));
-        balance - entry.restricted_bitcoin_balance
-    });
+) -> Result<u128, String> {
+    let deliver_to = validate_deliver_to(deliver_to);
+    let bitcoin_balance = spendable_bitcoin_balance(&caller).await;
     if buy_exact_in > bitcoin_balance {
-        ic_cdk::trap("not enough balance")
+        return Err(String::from("not enough balance"));
     }
-    let (id, amount) = write_agents(|agents| {
-        let id = agents.find_agent_id(id).expect("invalid agent id");
+    let (id, quote) = write_agents(|agents| {
+        let id = agents
+            .find_agent_id(id)
+            .ok_or_else(|| String::from("invalid agent id"))?;
         let mut agent = agents.mapping.get(&id).unwrap();
-        let amount = agent
-            .buy_exact_in(buy_exact_in as u128, amount_out_min)
-            .unwrap();
-        agent.balances.insert(caller.to_text());
+        let quote = agent.buy_exact_in(buy_exact_in as u128, amount_out_min)?;
         agents.mapping.insert(id, agent);
-        (id, amount)
-    });
-    write_ledger_entries(|entries| {
-        let mut entry = entries.get(&caller).unwrap_or_default();
-        entry.record_agent_owned_balance(id, buy_exact_in);
-        entry.record_rune_balance(id, amount);
-        entries.insert(caller, entry);
-    });
-    amount
+        Ok::<_, String>((id, quote))
+    })?;
+    record_buy(caller, id, &quote, deliver_to);
+    Ok(quote.amount_out)
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BuyExactOutArgs {
+    pub id: AgentBy,
+    pub token_amount: u128,
+    pub max_collateral: u64,
+    pub deliver_to: Option<String>, // runes are sent on-chain to this address during settlement
+}
+
+#[update]
+pub async fn buy_exact_out(args: BuyExactOutArgs) -> u128 {
+    execute_buy_exact_out(ic_cdk::caller(), args)
+        .await
+        .unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// returns the satoshis paid, the balance is checked against the quote before the trade
+async fn execute_buy_exact_out(
+    caller: candid::Principal,
+    BuyExactOutArgs {
+        id,
+        token_amount,
+        max_collateral,
+        deliver_to,
+    }: BuyExactOutArgs,
+) -> Result<u128, String> {
+    let deliver_to = validate_deliver_to(deliver_to);
+    let bitcoin_balance = spendable_bitcoin_balance(&caller).await;
+    let (id, quote) = write_agents(|agents| {
+        let id = agents
+            .find_agent_id(id)
+            .ok_or_else(|| String::from("invalid agent id"))?;
+        let mut agent = agents.mapping.get(&id).unwrap();
+        if agent.quote_buy_exact_out(token_amount)?.amount_in > bitcoin_balance as u128 {
+            return Err(String::from("not enough balance"));
+        }
+        let quote = agent.buy_exact_out(token_amount, max_collateral as u128)?;
+        agents.mapping.insert(id, agent);
+        Ok::<_, String>((id, quote))
+    })?;
+    record_buy(caller, id, &quote, deliver_to);
+    Ok(quote.amount_in)
 }
 
 #[derive(CandidType, Deserialize)]
@@ -410,69 +832,765 @@ pub struct SellArgs {
 }
 
 #[update]
-pub fn sell(
+pub fn sell(args: SellArgs) -> u128 {
+    execute_sell(ic_cdk::caller(), args).unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// shared by `sell` and the sell tool of the chat, returns the satoshis received
+fn execute_sell(
+    caller: candid::Principal,
     SellArgs {
         id,
         token_amount,
         amount_collateral_min,
     }: SellArgs,
-) -> u128 {
-    let caller = ic_cdk::caller();
-    let id = read_agents(|agents| agents.find_agent_id(id)).expect("invalid agent");
-    let rune_balance = read_ledger_entries(|entries| {
+) -> Result<u128, String> {
+    let id = read_agents(|agents| agents.find_agent_id(id))
+        .ok_or_else(|| String::from("invalid agent"))?;
+    if token_amount > rune_balance_of(&caller, id) {
+        return Err(String::from("not enough balance"));
+    }
+    let quote = write_agents(|agents| {
+        let mut agent = agents.mapping.get(&id).unwrap();
+        let quote = agent.sell_exact_in(token_amount, amount_collateral_min as u128)?;
+        agents.mapping.insert(id, agent);
+        Ok::<_, String>(quote)
+    })?;
+    record_sell(caller, id, &quote);
+    Ok(quote.amount_out)
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SellExactOutArgs {
+    pub id: AgentBy,
+    pub collateral_out: u64,
+    pub max_token_amount: u128,
+}
+
+#[update]
+pub fn sell_exact_out(args: SellExactOutArgs) -> u128 {
+    execute_sell_exact_out(ic_cdk::caller(), args).unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// returns the tokens sold, the balance is checked against the quote before the trade
+fn execute_sell_exact_out(
+    caller: candid::Principal,
+    SellExactOutArgs {
+        id,
+        collateral_out,
+        max_token_amount,
+    }: SellExactOutArgs,
+) -> Result<u128, String> {
+    let id = read_agents(|agents| agents.find_agent_id(id))
+        .ok_or_else(|| String::from("invalid agent"))?;
+    let rune_balance = rune_balance_of(&caller, id);
+    let quote = write_agents(|agents| {
+        let mut agent = agents.mapping.get(&id).unwrap();
+        if agent
+            .quote_sell_exact_out(collateral_out as u128)?
+            .amount_in
+            > rune_balance
+        {
+            return Err(String::from("not enough balance"));
+        }
+        let quote = agent.sell_exact_out(max_token_amount, collateral_out as u128)?;
+        agents.mapping.insert(id, agent);
+        Ok::<_, String>(quote)
+    })?;
+    record_sell(caller, id, &quote);
+    Ok(quote.amount_in)
+}
+
+fn quote_with<F>(id: AgentBy, f: F) -> Result<TradeQuote, String>
+where
+    F: FnOnce(&AgentDetail) -> Result<TradeQuote, &'static str>,
+{
+    read_agents(|agents| {
+        let id = agents
+            .find_agent_id(id)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
+        let agent = agents.mapping.get(&id).unwrap();
+        f(&agent).map_err(String::from)
+    })
+}
+
+#[query]
+pub fn quote_buy(id: AgentBy, buy_exact_in: u64) -> Result<TradeQuote, String> {
+    quote_with(id, |agent| agent.quote_buy_exact_in(buy_exact_in as u128))
+}
+
+#[query]
+pub fn quote_sell(id: AgentBy, token_amount: u128) -> Result<TradeQuote, String> {
+    quote_with(id, |agent| agent.quote_sell_exact_in(token_amount))
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum QuoteExactOut {
+    Buy { token_amount: u128 },
+    Sell { collateral_out: u64 },
+}
+
+#[query]
+pub fn quote_exact_out(id: AgentBy, exact_out: QuoteExactOut) -> Result<TradeQuote, String> {
+    quote_with(id, |agent| match exact_out {
+        QuoteExactOut::Buy { token_amount } => agent.quote_buy_exact_out(token_amount),
+        QuoteExactOut::Sell { collateral_out } => {
+            agent.quote_sell_exact_out(collateral_out as u128)
+        }
+    })
+}
+
+fn validate_deliver_to(deliver_to: Option<String>) -> Option<String> {
+    deliver_to.map(|addr| {
+        bitcoin::address_validation(&addr)
+            .unwrap_or_else(|err| ic_cdk::trap(&err))
+            .to_string()
+    })
+}
+
+// balance of the caller's deposit address that isn't restricted
+async fn spendable_bitcoin_balance(caller: &candid::Principal) -> u64 {
+    let wallet = utils::get_wallet_for(caller);
+    indexer::fetch_wallet_utxos(&wallet).await;
+    read_ledger_entries(|entries| {
+        let entry = entries.get(caller).unwrap_or_default();
+        let balance = read_utxo_manager(|manager| wallet.bitcoin_balance(manager));
+        balance - entry.restricted_bitcoin_balance
+    })
+}
+
+fn rune_balance_of(caller: &candid::Principal, agent_id: u128) -> u128 {
+    read_ledger_entries(|entries| {
         entries
-            .get(&caller)
+            .get(caller)
             .unwrap_or_default()
             .ledger_entries
-            .get(&id)
+            .get(&agent_id)
             .copied()
             .unwrap_or_default()
             .1
-    });
-    if token_amount > rune_balance {
-        ic_cdk::trap("not enough balance")
-    }
-    let bitcoin = write_agents(|agents| {
-        let mut agent = agents.mapping.get(&id).unwrap();
-        let bitcoin = agent
-            .sell_exact_in(token_amount, amount_collateral_min as u128)
-            .unwrap();
-        agents.mapping.insert(id, agent);
-        bitcoin
-    });
+    })
+}
+
+fn record_buy(
+    caller: candid::Principal,
+    agent_id: u128,
+    quote: &TradeQuote,
+    deliver_to: Option<String>,
+) {
+    let (bitcoin, rune) = (quote.amount_in as u64, quote.amount_out);
     write_ledger_entries(|entries| {
         let mut entry = entries.get(&caller).unwrap_or_default();
-        entry.deduct_rune_balance(id, token_amount);
-        entry.deduct_agent_owned_balance(id, bitcoin as u64);
+        entry.record_agent_owned_balance(agent_id, bitcoin);
+        if deliver_to.is_none() {
+            entry.record_rune_balance(caller, agent_id, rune);
+        }
         entries.insert(caller, entry);
     });
-    bitcoin
+    write_games(|games| games.on_buy(agent_id, caller, bitcoin, ic_cdk::api::time()));
+    record_trade(
+        agent_id,
+        trades::Trade {
+            trader: caller,
+            side: trades::TradeSide::Buy,
+            bitcoin,
+            rune,
+            owed_bitcoin: 0,
+            deliver_to,
+            settlement_txid: None,
+            treasury_fee: Some(quote.treasury_fee as u64),
+            dex_fee: Some(quote.dex_fee as u64),
+            lp_fee: Some(quote.lp_fee),
+            reserves: Some((quote.collateral_reserve, quote.token_reserve)),
+        },
+    );
+}
+
+// proceeds are paid from the agent owned satoshis still sitting in the user's deposit address,
+// the rest is owed by the agent and paid out during settlement
+fn record_sell(caller: candid::Principal, agent_id: u128, quote: &TradeQuote) {
+    let (rune, bitcoin) = (quote.amount_in, quote.amount_out as u64);
+    let owed_bitcoin = write_ledger_entries(|entries| {
+        let mut entry = entries.get(&caller).unwrap_or_default();
+        entry.deduct_rune_balance(caller, agent_id, rune);
+        let in_flight = read_settlement_state(|state| state.in_flight_bitcoin(agent_id, &caller));
+        let available = entry
+            .ledger_entries
+            .get(&agent_id)
+            .copied()
+            .unwrap_or_default()
+            .0
+            .saturating_sub(in_flight);
+        let from_deposit = bitcoin.min(available);
+        entry.deduct_agent_owned_balance(agent_id, from_deposit);
+        entries.insert(caller, entry);
+        bitcoin - from_deposit
+    });
+    record_trade(
+        agent_id,
+        trades::Trade {
+            trader: caller,
+            side: trades::TradeSide::Sell,
+            bitcoin,
+            rune,
+            owed_bitcoin,
+            deliver_to: None,
+            settlement_txid: None,
+            treasury_fee: Some(quote.treasury_fee as u64),
+            dex_fee: Some(quote.dex_fee as u64),
+            lp_fee: Some(quote.lp_fee),
+            reserves: Some((quote.collateral_reserve, quote.token_reserve)),
+        },
+    );
+}
+
+fn record_trade(agent_id: u128, trade: trades::Trade) {
+    let trader = trade.trader;
+    let needs_settlement = trade.needs_settlement();
+    let key = write_trades(|trades| trades.record_trade(agent_id, trade));
+    if needs_settlement {
+        write_settlement_state(|state| state.queue_trade(key, trader));
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TradesArgs {
+    pub agent: AgentBy,
+    pub before: Option<trades::TradeKey>, // key of the last trade of the previous page
+    pub limit: u32,
+}
+
+#[query]
+pub fn get_trades(
+    TradesArgs {
+        agent,
+        before,
+        limit,
+    }: TradesArgs,
+) -> Vec<(trades::TradeKey, trades::Trade)> {
+    let agent_id = read_agents(|agents| agents.find_agent_id(agent)).expect("agent doesn't exist");
+    read_trades(|trades| trades.get_trades(agent_id, before, limit.min(100) as usize))
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CandlesArgs {
+    pub agent: AgentBy,
+    pub resolution: trades::CandleResolution,
+    pub from: Option<u64>, // defaults to 500 candles before `to`
+    pub to: Option<u64>,   // defaults to now
+}
+
+#[query]
+pub fn get_candles(
+    CandlesArgs {
+        agent,
+        resolution,
+        from,
+        to,
+    }: CandlesArgs,
+) -> Vec<trades::Candle> {
+    let agent_id = read_agents(|agents| agents.find_agent_id(agent)).expect("agent doesn't exist");
+    let to = to.unwrap_or_else(ic_cdk::api::time);
+    let earliest = to.saturating_sub(500 * resolution.nanos());
+    let from = from.unwrap_or(earliest).max(earliest);
+    read_trades(|trades| trades.get_candles(agent_id, resolution, from, to))
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct LuckyDraw {
     pub id: AgentBy,
     pub message: String,
+    pub pay_with: Option<GuessPayment>, // defaults to bitcoin
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum GuessPayment {
+    Bitcoin,
+    Rune,
+}
+
+#[update]
+pub async fn lucky_draw(
+    LuckyDraw {
+        id,
+        message,
+        pay_with,
+    }: LuckyDraw,
+) -> String {
+    play_game(PlayGameArgs {
+        id,
+        game: games::GameKind::LuckyDraw,
+        input: message,
+        pay_with,
+    })
+    .await
+    .unwrap_or_else(|err| err)
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PlayGameArgs {
+    pub id: AgentBy,
+    pub game: games::GameKind,
+    pub input: String,
+    pub pay_with: Option<GuessPayment>, // defaults to bitcoin
 }
 
+/*
+ * every entry is paid, in satoshis or in the agent's runes, at the price set by the game
+ * a share of the price goes into the game's prize pool and the rest is commission
+*/
 #[update]
-pub fn lucky_draw(LuckyDraw { id, message }: LuckyDraw) -> String {
+pub async fn play_game(args: PlayGameArgs) -> Result<String, String> {
     let caller = ic_cdk::caller();
+    let bitcoin_balance = match args.pay_with {
+        None | Some(GuessPayment::Bitcoin) => spendable_bitcoin_balance(&caller).await,
+        Some(GuessPayment::Rune) => 0,
+    };
+    enter_game(caller, args, bitcoin_balance, ic_cdk::api::time())
+}
+
+// the caller's cooldown only starts once the entry is validated and charged
+fn enter_game(
+    caller: candid::Principal,
+    PlayGameArgs {
+        id,
+        game: kind,
+        input,
+        pay_with,
+    }: PlayGameArgs,
+    bitcoin_balance: u64,
+    now: u64,
+) -> Result<String, String> {
+    let config = read_config(|config| config.guess_config());
+    let pay_with = pay_with.unwrap_or(GuessPayment::Bitcoin);
+    if rate_limiter::is_cooling_down(caller, config.cooldown_secs, now) {
+        return Err(String::from("Too many guesses, try again later"));
+    }
     write_agents(|agents| {
-        let id = agents.find_agent_id(id).expect("agent doesn't exist");
+        let id = agents
+            .find_agent_id(id)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
         let mut agent = agents.mapping.get(&id).unwrap();
-        if agent.current_winner.is_some() {
-            return String::from("Contest not started");
+        let mut instance = read_games(|games| games.get(id, kind))
+            .ok_or_else(|| String::from("agent doesn't run this game"))?;
+        let game = instance.game_mut();
+        game.on_tick(now);
+        if game.round().winner.is_some() {
+            return Err(String::from(
+                "Prize is already won, next round starts once it's claimed",
+            ));
         }
-        if Some(message) == agent.secret {
-            agent.current_winner.replace(caller);
-            String::from("Congratulation")
-        } else {
-            String::from("Better luck next time")
+        let Some((bitcoin_price, rune_price)) = game.entry_fee(&config) else {
+            let reply = game.play(caller, &input)?;
+            rate_limiter::start_cooldown(caller, config.cooldown_secs, now);
+            return Ok(reply);
+        };
+        let balance_is_enough = match pay_with {
+            GuessPayment::Bitcoin => bitcoin_price <= bitcoin_balance,
+            GuessPayment::Rune => rune_price <= rune_balance_of(&caller, id),
+        };
+        if !balance_is_enough {
+            return Err(String::from("not enough balance"));
+        }
+        let reply = game.play(caller, &input)?;
+        match pay_with {
+            GuessPayment::Bitcoin => {
+                let to_pool =
+                    (bitcoin_price as u128 * config.prize_share_bps as u128 / 10_000) as u64;
+                write_ledger_entries(|entries| {
+                    let mut entry = entries.get(&caller).unwrap_or_default();
+                    entry.record_agent_owned_balance(id, bitcoin_price);
+                    entries.insert(caller, entry);
+                });
+                write_commission_state(|state| {
+                    let prev = state.get(&id).unwrap_or(0);
+                    state.insert(id, prev + bitcoin_price - to_pool);
+                });
+                // the fee is still in the caller's deposit, the pool grows once it's settled
+                write_settlement_state(|state| state.queue_game_fee(id, caller, kind, to_pool));
+            }
+            GuessPayment::Rune => {
+                let to_pool = rune_price * config.prize_share_bps as u128 / 10_000;
+                let commission_receiver = read_config(|config| config.commission_receiver());
+                write_ledger_entries(|entries| {
+                    let mut entry = entries.get(&caller).unwrap_or_default();
+                    entry.deduct_rune_balance(caller, id, rune_price);
+                    entries.insert(caller, entry);
+                    let mut entry = entries.get(&commission_receiver).unwrap_or_default();
+                    entry.record_rune_balance(commission_receiver, id, rune_price - to_pool);
+                    entries.insert(commission_receiver, entry);
+                });
+                // runes of the pool are back in the agent's custody until they're won
+                game.round_mut().prize_pool.1 += to_pool;
+                agent.prize_runes = Some(agent.prize_runes.unwrap_or(0) + to_pool);
+            }
+        }
+        game.round_mut().entries += 1;
+        write_games(|games| games.insert(id, instance));
+        agents.mapping.insert(id, agent);
+        rate_limiter::start_cooldown(caller, config.cooldown_secs, now);
+        Ok(reply)
+    })
+}
+
+#[query]
+pub fn get_games(agent: AgentBy) -> Vec<games::GameView> {
+    let agent_id = read_agents(|agents| agents.find_agent_id(agent)).expect("agent doesn't exist");
+    let config = read_config(|config| config.guess_config());
+    read_games(|games| games.views_of(agent_id, &config, ic_cdk::api::time()))
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EnableGameArgs {
+    pub agent: AgentBy,
+    pub settings: games::GameSettings,
+}
+
+// id of the agent, if the caller created it
+fn created_agent_id(agent: AgentBy, caller: candid::Principal) -> Result<u128, String> {
+    read_agents(|agents| {
+        let id = agents
+            .find_agent_id(agent)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
+        if agents.mapping.get(&id).unwrap().created_by != caller {
+            return Err(String::from("caller isn't the creator of the agent"));
+        }
+        Ok(id)
+    })
+}
+
+/// none clears the persona
+#[update]
+pub fn set_persona(agent: AgentBy, persona: Option<knowledge::Persona>) -> Result<(), String> {
+    let id = created_agent_id(agent, ic_cdk::caller())?;
+    if let Some(ref persona) = persona {
+        persona.validate()?;
+    }
+    write_agents(|agents| {
+        let mut agent = agents.mapping.get(&id).unwrap();
+        agent.persona = persona;
+        agents.mapping.insert(id, agent);
+    });
+    Ok(())
+}
+
+/// lets the agent act on its own, see `autopilot`
+#[update]
+pub fn set_autopilot(agent: AgentBy, enabled: bool) -> Result<(), String> {
+    let id = created_agent_id(agent, ic_cdk::caller())?;
+    write_agents(|agents| {
+        let mut agent = agents.mapping.get(&id).unwrap();
+        agent.autopilot.replace(enabled);
+        agents.mapping.insert(id, agent);
+    });
+    Ok(())
+}
+
+#[query]
+pub fn get_persona(agent: AgentBy) -> Option<knowledge::Persona> {
+    read_agents(|agents| {
+        let id = agents.find_agent_id(agent)?;
+        agents.mapping.get(&id).unwrap().persona
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpsertKnowledgeArgs {
+    pub agent: AgentBy,
+    pub doc_id: Option<u32>, // replaces the document, a new one is added if none
+    pub title: String,
+    pub content: String,
+}
+
+/// returns the id of the document
+#[update]
+pub fn upsert_knowledge(
+    UpsertKnowledgeArgs {
+        agent,
+        doc_id,
+        title,
+        content,
+    }: UpsertKnowledgeArgs,
+) -> Result<u32, String> {
+    let id = created_agent_id(agent, ic_cdk::caller())?;
+    knowledge::validate_doc(&title, &content)?;
+    let doc = knowledge::KnowledgeDoc {
+        title,
+        content,
+        updated_at: ic_cdk::api::time(),
+    };
+    write_knowledge(|state| state.upsert(id, doc_id, doc))
+}
+
+#[update]
+pub fn remove_knowledge(agent: AgentBy, doc_id: u32) -> Result<(), String> {
+    let id = created_agent_id(agent, ic_cdk::caller())?;
+    write_knowledge(|state| state.remove(id, doc_id))
+}
+
+#[query]
+pub fn get_knowledge(agent: AgentBy) -> Vec<(u32, knowledge::KnowledgeDoc)> {
+    let Some(id) = read_agents(|agents| agents.find_agent_id(agent)) else {
+        return Vec::new();
+    };
+    read_knowledge(|state| state.docs_of(id))
+}
+
+/// announcements of the agent, newest first
+#[query]
+pub fn get_feed(
+    agent: AgentBy,
+    before: Option<u64>,
+    limit: u32,
+) -> Vec<(u64, state::autopilot::FeedPost)> {
+    let Some(id) = read_agents(|agents| agents.find_agent_id(agent)) else {
+        return Vec::new();
+    };
+    read_autopilot(|state| state.feed_of(id, before, limit.min(100) as usize))
+}
+
+/// actions the agent took on its own along with its reasoning, newest first
+#[query]
+pub fn get_action_log(
+    agent: AgentBy,
+    before: Option<u64>,
+    limit: u32,
+) -> Vec<(u64, state::autopilot::ActionRecord)> {
+    let Some(id) = read_agents(|agents| agents.find_agent_id(agent)) else {
+        return Vec::new();
+    };
+    read_autopilot(|state| state.actions_of(id, before, limit.min(100) as usize))
+}
+
+/// lets the creator of an agent add a game to it
+#[update]
+pub async fn enable_game(EnableGameArgs { agent, settings }: EnableGameArgs) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let kind = settings.kind();
+    let (id, llm, name, description) = read_agents(|agents| {
+        let id = agents
+            .find_agent_id(agent)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
+        let agent = agents.mapping.get(&id).unwrap();
+        if agent.created_by != caller {
+            return Err(String::from("caller isn't the creator of the agent"));
         }
+        settings.validate(agent.free_runes()?)?;
+        Ok((id, agent.llm_config(), agent.name, agent.description))
+    })?;
+    if read_games(|games| games.get(id, kind)).is_some() {
+        return Err(String::from("game is already enabled"));
+    }
+    let seed = games::seed_round(
+        kind,
+        &llm,
+        games::Difficulty::default(),
+        &name,
+        &description,
+    )
+    .await;
+    let config = read_config(|config| config.guess_config());
+    write_games(|games| {
+        if games.get(id, kind).is_some() {
+            return Err(String::from("game is already enabled"));
+        }
+        games.insert(
+            id,
+            games::GameInstance::new(settings, seed, &config, ic_cdk::api::time()),
+        );
+        Ok(())
     })
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct ClaimPrizeArgs {
+    pub id: AgentBy,
+    pub to: String,
+    pub game: Option<games::GameKind>, // defaults to the lucky draw
+    pub fee: Option<bitcoin::fee::FeeTier>, // paid by the agent, so only a tier can be picked
+}
+
+/*
+ * pays the prize pool of the game's current round to the winner from the agent's address
+ * network fee is paid by the agent
+ * the next round is seeded and opens once the payout is broadcasted
+*/
+#[update]
+pub async fn claim_prize(
+    ClaimPrizeArgs { id, to, game, fee }: ClaimPrizeArgs,
+) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let kind = game.unwrap_or(games::GameKind::LuckyDraw);
+    let receiver = bitcoin::address_validation(&to)?;
+    let is_winner = |id: u128| {
+        read_games(|games| games.get(id, kind)).is_some_and(|mut instance| {
+            instance.game_mut().on_tick(ic_cdk::api::time());
+            instance.game().round().winner == Some(caller)
+        })
+    };
+    let (id, llm, name, description, runeid, agent_wallet) = read_agents(|agents| {
+        let id = agents
+            .find_agent_id(id)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
+        let agent = agents.mapping.get(&id).unwrap();
+        if !is_winner(id) {
+            return Err(String::from("caller isn't the winner of the current round"));
+        }
+        let runeid = match agent.runeid {
+            None => return Err(String::from("rune isn't etched yet")),
+            Some(ref runeid) => indexer::RuneId::from_str(runeid)?,
+        };
+        Ok((
+            id,
+            agent.llm_config(),
+            agent.name.clone(),
+            agent.description.clone(),
+            runeid,
+            agent.wallet(),
+        ))
+    })?;
+
+    let difficulty = read_games(|games| games.get(id, kind).unwrap().game().round().difficulty());
+    let seed = games::seed_round(kind, &llm, difficulty, &name, &description).await;
+    indexer::fetch_wallet_utxos(&agent_wallet).await;
+    let prize = read_games(|games| games.get(id, kind).unwrap().game().round().prize_pool);
+    indexer::fetch_utxos_and_update(
+        &agent_wallet.address().to_string(),
+        indexer::TargetType::Runic {
+            runeid,
+            target: prize.1,
+        },
+    )
+    .await;
+    let fee_per_vbytes = bitcoin::fee::fee_per_vbyte(fee.map(bitcoin::fee::FeeRate::Tier)).await;
+
+    // taking the prize out of the pool before the broadcast so that it can't be claimed twice
+    let (bitcoin_prize, rune_prize) = write_games(|games| {
+        let mut instance = games.get(id, kind).unwrap();
+        let game = instance.game_mut();
+        game.on_tick(ic_cdk::api::time());
+        if game.round().winner != Some(caller) {
+            return Err(String::from("caller isn't the winner of the current round"));
+        }
+        let (bitcoin_prize, rune_prize) = game.round().prize_pool;
+        if rune_prize == 0 && bitcoin_prize < bitcoin::DUST_THRESHOLD {
+            return Err(String::from("prize is already claimed"));
+        }
+        // satoshis below dust stay in the pool for the next round
+        let bitcoin_prize = if bitcoin_prize < bitcoin::DUST_THRESHOLD {
+            0
+        } else {
+            bitcoin_prize
+        };
+        let bitcoin_left = game.round().prize_pool.0 - bitcoin_prize;
+        game.round_mut().prize_pool = (bitcoin_left, 0);
+        games.insert(id, instance);
+        Ok((bitcoin_prize, rune_prize))
+    })?;
+    let restore_prize = || {
+        write_games(|games| {
+            games.update(id, kind, |game| {
+                let prize_pool = &mut game.round_mut().prize_pool;
+                prize_pool.0 += bitcoin_prize;
+                prize_pool.1 += rune_prize;
+            })
+        })
+    };
+    // the runes of the prize are checked against what the agent can pay out on every claim
+    let rune_split = write_agents(|agents| {
+        let mut agent = agents.mapping.get(&id).unwrap();
+        let split = agent.take_prize_runes(rune_prize)?;
+        agents.mapping.insert(id, agent);
+        Ok::<_, String>(split)
+    });
+    let rune_split = match rune_split {
+        Ok(split) => split,
+        Err(err) => {
+            restore_prize();
+            return Err(err);
+        }
+    };
+    let restore_prize = || {
+        restore_prize();
+        write_agents(|agents| {
+            let mut agent = agents.mapping.get(&id).unwrap();
+            agent.return_prize_runes(rune_split);
+            agents.mapping.insert(id, agent);
+        });
+    };
+
+    let handler = if rune_prize > 0 && bitcoin_prize > 0 {
+        bitcoin::transaction::combined::transfer(
+            bitcoin::transaction::combined::CombinedTransferArgs {
+                runeid,
+                rune_amount: rune_prize,
+                rune_sender: agent_wallet.clone(),
+                rune_receiver: receiver.clone(),
+                bitcoin_amount: bitcoin_prize,
+                bitcoin_sender: agent_wallet.clone(),
+                bitcoin_receiver: receiver,
+                fee_payer: agent_wallet,
+                postage: None,
+                fee_per_vbytes,
+            },
+        )
+        .map_err(|(rune, bitcoin, fee)| {
+            format!("not enough balance. rune: {rune}, bitcoin: {bitcoin}, fee: {fee}")
+        })
+    } else if rune_prize > 0 {
+        bitcoin::runestone::transfer::transfer(bitcoin::runestone::transfer::RuneTransferArgs {
+            runeid,
+            rune_amount: rune_prize,
+            rune_sender: agent_wallet.clone(),
+            rune_receiver: receiver,
+            fee_payer: agent_wallet,
+            postage: None,
+            fee_per_vbytes,
+        })
+        .map_err(|(rune, fee)| format!("not enough balance. rune: {rune}, fee: {fee}"))
+    } else {
+        bitcoin::transfer(bitcoin::transaction::BtcTransferArgs {
+            sender: agent_wallet,
+            receiver,
+            amount: bitcoin_prize,
+            paid_by_sender: true,
+            fee_per_vbytes,
+        })
+        .map_err(|required| format!("not enough balance. required: {required}"))
+    };
+    let handler = match handler {
+        Ok(handler) => handler,
+        Err(err) => {
+            restore_prize();
+            return Err(err);
+        }
+    };
+
+    let txid = match handler.submit().await {
+        Ok(txn_handler::SubmittedTxidType::Bitcoin { txid }) => txid,
+        Err(err) => {
+            restore_prize();
+            return Err(err);
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    write_games(|games| {
+        games.update(id, kind, |game| {
+            let note = game.round_note();
+            game.round_mut().past_winners.push(games::PastWinner {
+                time: now,
+                bitcoin: bitcoin_prize,
+                rune: rune_prize,
+                winner: caller,
+                note,
+            });
+            game.start_round(seed, now);
+        })
+    });
+    Ok(txid)
+}
+
 #[update]
 pub fn create_chat_session(agent: AgentBy) -> u128 {
     let caller = ic_cdk::caller();
@@ -480,6 +1598,47 @@ pub fn create_chat_session(agent: AgentBy) -> u128 {
     write_chat_session(|session| session.start_new_session(agent_id, caller))
 }
 
+#[query]
+pub fn list_my_sessions() -> Vec<SessionSummary> {
+    let caller = ic_cdk::caller();
+    read_chat_session(|sessions| sessions.sessions_of(caller, ic_cdk::api::time()))
+}
+
+#[query]
+pub fn get_session_history(session_id: u128) -> Result<Vec<llm::ChatMessage>, String> {
+    let caller = ic_cdk::caller();
+    read_chat_session(|sessions| {
+        sessions.session_of(session_id, caller, ic_cdk::api::time())?;
+        Ok(sessions.history(session_id))
+    })
+}
+
+const SESSION_SWEEP_INTERVAL: u64 = 5 * 60; // seconds
+
+fn start_session_sweeper() {
+    ic_cdk_timers::set_timer_interval(
+        std::time::Duration::from_secs(SESSION_SWEEP_INTERVAL),
+        || {
+            let evicted = write_chat_session(|sessions| sessions.evict_idle(ic_cdk::api::time()));
+            if evicted > 0 {
+                ic_cdk::println!("evicted {} idle chat sessions", evicted);
+            }
+        },
+    );
+}
+
+const LEASE_SWEEP_INTERVAL: u64 = 5 * 60; // seconds
+
+// utxos leased to operations that never broadcasted return to the pool
+fn start_lease_sweeper() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(LEASE_SWEEP_INTERVAL), || {
+        let expired = write_utxo_manager(|manager| manager.expire_leases(ic_cdk::api::time()));
+        if expired > 0 {
+            ic_cdk::println!("expired {} utxo leases", expired);
+        }
+    });
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct ChatArgs {
     pub agent: AgentBy,
@@ -497,12 +1656,16 @@ pub async fn chat(
 ) -> String {
     let caller = ic_cdk::caller();
     let agent_id = read_agents(|agents| agents.find_agent_id(agent)).expect("agent doesn't exist");
-    let account = utils::get_account_for(&caller);
-    let user_bitcoin_address = bitcoin::account_to_p2pkh_address(&account);
+    // touching the session up front keeps the sweeper away from it while the llm replies
+    write_chat_session(|sessions| {
+        sessions.touch(session_id, caller, agent_id, ic_cdk::api::time())
+    })
+    .unwrap_or_else(|err| ic_cdk::trap(&err));
+    let wallet = utils::get_wallet_for(&caller);
+    let user_bitcoin_address = wallet.address().to_string();
     let (bitcoin, rune) = read_ledger_entries(|entries| {
         let entry = entries.get(&caller).unwrap_or_default();
-        let balance =
-            read_utxo_manager(|manager| manager.get_bitcoin_balance(&user_bitcoin_address));
+        let balance = read_utxo_manager(|manager| wallet.bitcoin_balance(manager));
         let bitcoin = balance - entry.restricted_bitcoin_balance;
         let rune = entry
             .ledger_entries
@@ -512,7 +1675,7 @@ pub async fn chat(
             .1;
         (bitcoin, rune)
     });
-    llm::Llm::chat(
+    let reply = llm::Llm::chat(
         session_id,
         agent_id,
         user_bitcoin_address,
@@ -520,7 +1683,111 @@ pub async fn chat(
         rune,
         message,
     )
-    .await
+    .await;
+    record_jailbreak(agent_id, session_id, &reply);
+    reply
+}
+
+// the first session that makes the agent reveal its secret wins the round
+fn record_jailbreak(agent_id: u128, session_id: u128, reply: &str) {
+    let Some(games::GameInstance::LuckyDraw(mut game)) =
+        read_games(|games| games.get(agent_id, games::GameKind::LuckyDraw))
+    else {
+        return;
+    };
+    if !game.leaks_secret(reply) {
+        return;
+    }
+    let (session, transcript) = read_chat_session(|sessions| {
+        let session = sessions.session.get(&session_id).unwrap();
+        (session, sessions.history(session_id))
+    });
+    let (round, commitment) = game.record_jailbreak(session.user);
+    write_jailbreak_state(|state| {
+        state.proofs.insert(
+            state::jailbreak::ProofKey { agent_id, round },
+            state::jailbreak::JailbreakProof {
+                session_id,
+                winner: session.user,
+                commitment,
+                transcript,
+                timestamp: ic_cdk::api::time(),
+            },
+        )
+    });
+    write_games(|games| games.insert(agent_id, games::GameInstance::LuckyDraw(game)));
+}
+
+/// executes a value moving action the agent proposed in the chat
+#[update]
+pub async fn confirm_action(id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    // taken before the first await, so that the action can't be confirmed twice
+    let action = tools::take_action(id, caller)?;
+    let agent_id = action.agent_id;
+    match action.call {
+        tools::ToolCall::Buy {
+            bitcoin,
+            min_tokens,
+        } => {
+            let args = BuyArgs {
+                id: AgentBy::Id(agent_id),
+                buy_exact_in: bitcoin,
+                amount_out_min: min_tokens,
+                deliver_to: None,
+            };
+            let tokens = execute_buy(caller, args).await?;
+            Ok(format!("bought {tokens} token units"))
+        }
+        tools::ToolCall::Sell {
+            tokens,
+            min_bitcoin,
+        } => {
+            let args = SellArgs {
+                id: AgentBy::Id(agent_id),
+                token_amount: tokens,
+                amount_collateral_min: min_bitcoin,
+            };
+            let bitcoin = execute_sell(caller, args)?;
+            Ok(format!("sold for {bitcoin} satoshis"))
+        }
+        tools::ToolCall::Withdraw { to, asset, amount } => {
+            let withdrawal_type = match asset {
+                tools::Asset::Bitcoin => WithdrawalType::Bitcoin {
+                    amount: amount
+                        .try_into()
+                        .map_err(|_| String::from("amount is too large"))?,
+                },
+                tools::Asset::Token => WithdrawalType::Rune {
+                    runeid: AgentBy::Id(agent_id),
+                    amount,
+                },
+            };
+            // the agent proposes no fee, the fee policy picks the rate
+            withdraw(to, withdrawal_type, None).await
+        }
+        _ => Err(String::from("action doesn't need a confirmation")),
+    }
+}
+
+#[update]
+pub fn cancel_action(id: u64) -> Result<(), String> {
+    tools::take_action(id, ic_cdk::caller()).map(|_| ())
+}
+
+#[query]
+pub fn get_pending_actions() -> Vec<tools::PendingAction> {
+    tools::pending_actions_of(ic_cdk::caller())
+}
+
+#[query]
+pub fn get_jailbreak_proof(agent: AgentBy, round: u32) -> Option<state::jailbreak::JailbreakProof> {
+    let agent_id = read_agents(|agents| agents.find_agent_id(agent)).expect("agent doesn't exist");
+    read_jailbreak_state(|state| {
+        state
+            .proofs
+            .get(&state::jailbreak::ProofKey { agent_id, round })
+    })
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -635,3 +1902,25 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
 }
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_entries_start_no_cooldown() {
+        let caller = candid::Principal::anonymous();
+        let args = PlayGameArgs {
+            id: AgentBy::Id(1),
+            game: games::GameKind::LuckyDraw,
+            input: String::from("guess"),
+            pay_with: None,
+        };
+        assert_eq!(
+            enter_game(caller, args, 100_000, 0),
+            Err(String::from("agent doesn't exist"))
+        );
+        let cooldown = read_config(|config| config.guess_config().cooldown_secs);
+        assert!(!rate_limiter::is_cooling_down(caller, cooldown, 0));
+    }
+}
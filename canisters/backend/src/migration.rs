@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use crate::commitment::{SecretCommitment, new_salt};
+use crate::games::{GameInstance, GameKind, GameMode, LuckyDraw, PastWinner, Round};
+use crate::state::{
+    read_agents, read_chat_session, read_config, read_games, read_ledger_entries,
+    read_scheduled_state, write_agents, write_chat_session, write_config, write_counters,
+    write_games, write_holders,
+};
+
+/// Version of the stored state written by this build.
+/// Bump it together with a new step in `migrate` whenever stored data, such as `AgentDetail` or
+/// `BalanceEntries`, has to be rewritten on upgrade.
+pub const CURRENT_VERSION: u32 = 5;
+
+pub fn migrate() {
+    let mut version = read_config(|config| config.version());
+    while version < CURRENT_VERSION {
+        match version {
+            0 => seed_counters_and_holders(),
+            1 => commit_plaintext_secrets(),
+            2 => move_lucky_draws_into_games(),
+            3 => write_chat_session(|sessions| sessions.index_sessions()),
+            4 => write_chat_session(|sessions| sessions.move_history_into_log()),
+            _ => ic_cdk::trap(&format!("no migration from version {version}")),
+        }
+        version += 1;
+        write_config(|config| {
+            let mut temp = config.get().clone();
+            temp.version.replace(version);
+            config.set(temp).expect("failed to set config");
+        });
+        ic_cdk::println!("state migrated to version {}", version);
+    }
+}
+
+// id counters used to live on the heap and the holder index wasn't populated from the ledger
+fn seed_counters_and_holders() {
+    let agents = read_agents(|agents| agents.mapping.last_key_value().map_or(0, |(id, _)| id + 1));
+    let chat_sessions = read_chat_session(|sessions| {
+        sessions
+            .session
+            .last_key_value()
+            .map_or(0, |(id, _)| id + 1)
+    });
+    let scheduled_txns = read_scheduled_state(|state| state.last_id().map_or(0, |id| id + 1));
+    write_counters(|counters| {
+        let mut temp = counters.get().clone();
+        temp.agents = temp.agents.max(agents);
+        temp.chat_sessions = temp.chat_sessions.max(chat_sessions);
+        temp.scheduled_txns = temp.scheduled_txns.max(scheduled_txns);
+        counters.set(temp).expect("failed to set counters");
+    });
+
+    let balances = read_ledger_entries(|entries| {
+        entries
+            .iter()
+            .flat_map(|(holder, entry)| {
+                entry
+                    .ledger_entries
+                    .into_iter()
+                    .map(move |(agent_id, (_, rune))| (agent_id, holder, rune))
+            })
+            .collect::<Vec<_>>()
+    });
+    write_holders(|holders| {
+        for (agent_id, holder, balance) in balances {
+            holders.update_balance(agent_id, holder, balance);
+        }
+    });
+}
+
+// secrets used to be stored in plaintext. randomness can't be awaited during an upgrade, so the
+// later steps move the plaintext secrets along and they're committed right after the upgrade
+fn commit_plaintext_secrets() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(commit_moved_secrets()));
+}
+
+// lucky draws with a plaintext secret but no commitment are the ones left by the migration
+async fn commit_moved_secrets() {
+    let ids = read_games(|games| {
+        games
+            .instances
+            .iter()
+            .filter_map(|(key, instance)| match instance {
+                GameInstance::LuckyDraw(game)
+                    if game.commitment.is_none() && game.secret.is_some() =>
+                {
+                    Some(key.agent_id)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    });
+    for id in ids {
+        let salt = new_salt().await;
+        write_games(|games| {
+            let Some(GameInstance::LuckyDraw(mut game)) = games.get(id, GameKind::LuckyDraw) else {
+                return;
+            };
+            if game.commitment.is_some() {
+                return;
+            }
+            let Some(secret) = game.secret.take() else {
+                return;
+            };
+            let commitment = SecretCommitment::new(salt, &secret);
+            if game.round.winner.is_some() {
+                // the round is already won, so its secret is revealed right away
+                game.revealed_secrets.push(commitment.reveal(&secret));
+            } else {
+                game.commitment.replace(commitment);
+                // the agent only keeps the plaintext when it guards it in chat
+                if game.mode == GameMode::Jailbreak {
+                    game.secret.replace(secret);
+                }
+            }
+            games.insert(id, GameInstance::LuckyDraw(game));
+        });
+    }
+}
+
+// bait the bot used to live on `AgentDetail`, now it's the lucky draw game of the agent
+fn move_lucky_draws_into_games() {
+    write_agents(|agents| {
+        let ids = agents.mapping.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        for id in ids {
+            let mut agent = agents.mapping.get(&id).unwrap();
+            let mut past_winners = agent
+                .past_winners
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(time, bitcoin, rune, winner, note)| PastWinner {
+                    time,
+                    bitcoin,
+                    rune,
+                    winner,
+                    note,
+                })
+                .collect::<Vec<_>>();
+            past_winners.sort_by_key(|past_winner| past_winner.time);
+            let round = Round {
+                prize_pool: agent.current_prize_pool.take().unwrap_or_default(),
+                winner: agent.current_winner.take(),
+                entries: agent.guess_count.take().unwrap_or(0),
+                started_at: agent.created_at,
+                past_winners,
+                difficulty: None,
+            };
+            let game = LuckyDraw {
+                round,
+                mode: agent.game_mode.take().unwrap_or(GameMode::Guess),
+                commitment: agent.commitment.take(),
+                secret: agent.secret.take(),
+                revealed_secrets: agent.revealed_secrets.take().unwrap_or_default(),
+            };
+            write_games(|games| games.insert(id, GameInstance::LuckyDraw(game)));
+            agents.mapping.insert(id, agent);
+        }
+    });
+}
@@ -0,0 +1,44 @@
+use candid::Principal;
+use std::{cell::RefCell, collections::HashMap};
+
+const MAX_TRACKED: usize = 10_000;
+
+thread_local! {
+    // heap only, an upgrade resetting the cooldowns is harmless
+    static LAST_ACTION: RefCell<HashMap<Principal, u64>> = RefCell::default();
+}
+
+/// whether the principal acted within the last `cooldown_secs`
+pub fn is_cooling_down(principal: Principal, cooldown_secs: u64, now: u64) -> bool {
+    let cooldown = cooldown_secs * 1_000_000_000;
+    LAST_ACTION.with_borrow(|last_action| {
+        last_action
+            .get(&principal)
+            .is_some_and(|last| now < last + cooldown)
+    })
+}
+
+/// starts the cooldown of the principal, only once its action went through
+pub fn start_cooldown(principal: Principal, cooldown_secs: u64, now: u64) {
+    let cooldown = cooldown_secs * 1_000_000_000;
+    LAST_ACTION.with_borrow_mut(|last_action| {
+        if last_action.len() >= MAX_TRACKED {
+            last_action.retain(|_, last| now < *last + cooldown);
+        }
+        last_action.insert(principal, now);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown_only_starts_once_started() {
+        let player = Principal::anonymous();
+        assert!(!is_cooling_down(player, 10, 0));
+        start_cooldown(player, 10, 0);
+        assert!(is_cooling_down(player, 10, 9_999_999_999));
+        assert!(!is_cooling_down(player, 10, 10_000_000_000));
+    }
+}
@@ -0,0 +1,334 @@
+use std::cell::Cell;
+use std::str::FromStr;
+use std::time::Duration;
+
+use candid::Principal;
+
+use crate::{
+    bitcoin::{
+        self, DUST_THRESHOLD,
+        fee::FeeRate,
+        transaction::batch::{self, BatchLeg, SettlementBatchArgs},
+        wallet::Wallet,
+    },
+    broadcast,
+    games::GameKind,
+    indexer::{self, RuneId},
+    state::{
+        read_agents, read_config, read_ledger_entries, read_settlement_state, read_trades,
+        read_utxo_manager,
+        settlement::{Settlement, SettlementLeg},
+        trades::{TradeKey, TradeSide},
+        write_games, write_ledger_entries, write_settlement_state, write_trades,
+    },
+    txn_handler::SubmittedTxidType,
+    utils,
+};
+
+thread_local! {
+    static SETTLING: Cell<bool> = const { Cell::new(false) };
+}
+
+// prevents overlapping settlement rounds when a round outlives the timer interval
+struct SettlementGuard;
+
+impl SettlementGuard {
+    fn acquire() -> Option<Self> {
+        SETTLING.with(|settling| {
+            if settling.get() {
+                None
+            } else {
+                settling.set(true);
+                Some(Self)
+            }
+        })
+    }
+}
+
+impl Drop for SettlementGuard {
+    fn drop(&mut self) {
+        SETTLING.with(|settling| settling.set(false));
+    }
+}
+
+pub fn start_settlement_timer() {
+    let interval = read_config(|config| config.get_timer_for_settlement());
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || ic_cdk::spawn(settle()));
+}
+
+pub async fn settle() {
+    let Some(_guard) = SettlementGuard::acquire() else {
+        return;
+    };
+    reconcile_confirmed().await;
+
+    let agents = read_settlement_state(|state| state.pending_agents());
+    if agents.is_empty() {
+        return;
+    }
+    // settlement isn't urgent, so it's paid at the tier picked by the policy
+    let tier = read_config(|config| config.fee_policy().settlement_tier);
+    let fee_per_vbytes = bitcoin::fee::fee_per_vbyte(Some(FeeRate::Tier(tier))).await;
+    for agent_id in agents {
+        if let Err(err) = settle_agent(agent_id, fee_per_vbytes).await {
+            ic_cdk::println!("settlement for agent {} failed: {}", agent_id, err);
+        }
+    }
+}
+
+/*
+ * nets the pending trades of every trader on an agent and settles them in a single transaction
+ * - satoshis owned by the agent move from the traders' deposit addresses to the agent's address
+ * - satoshis owed for sells are paid from the agent's address, netted against the above
+ * - runes of buys that asked for delivery are sent from the agent's address
+ * every trader pays a share of the network fee, see `batch::transfer`
+*/
+async fn settle_agent(agent_id: u128, fee_per_vbytes: u64) -> Result<(), String> {
+    let (agent_wallet, runeid) = read_agents(|agents| {
+        let agent = agents
+            .mapping
+            .get(&agent_id)
+            .ok_or_else(|| String::from("agent doesn't exist"))?;
+        let runeid = agent.runeid.as_deref().map(RuneId::from_str).transpose()?;
+        Ok::<_, String>((agent.wallet(), runeid))
+    })?;
+    indexer::fetch_wallet_utxos(&agent_wallet).await;
+
+    let groups = read_settlement_state(|state| state.pending_for(agent_id));
+    let mut legs = vec![];
+    for (trader, keys) in groups {
+        let trader_wallet = utils::get_wallet_for(&trader);
+        indexer::fetch_wallet_utxos(&trader_wallet).await;
+        if let Some(leg) = net_trader(agent_id, runeid, trader, trader_wallet, &keys)? {
+            legs.push(leg);
+        }
+    }
+
+    // legs that can't be settled now wait for a later batch
+    let handler = loop {
+        if legs.is_empty() {
+            return Ok(());
+        }
+        let result = batch::transfer(SettlementBatchArgs {
+            runeid,
+            agent: agent_wallet.clone(),
+            legs: legs.iter().map(|(_, leg)| leg.clone()).collect(),
+            fee_per_vbytes,
+        });
+        match result {
+            Ok(handler) => break handler,
+            Err((Some(index), err)) => {
+                let (leg, _) = legs.remove(index);
+                ic_cdk::println!("trader {} left out of the settlement: {}", leg.trader, err);
+            }
+            Err((None, err)) => return Err(err),
+        }
+    };
+
+    let Some(watch) = handler.watched_input() else {
+        handler.release();
+        return Err(String::from("settlement has no inputs"));
+    };
+    let (legs, _): (Vec<SettlementLeg>, Vec<BatchLeg>) = legs.into_iter().unzip();
+    let settlement = Settlement {
+        agent_id,
+        legs,
+        watch,
+        txids: vec![],
+        spent_at: None,
+        submitted_at: ic_cdk::api::time(),
+    };
+    let settled_keys = settlement.trades();
+    // claimed before the broadcast so that sells can't pay out the same satoshis
+    let settlement_id = write_settlement_state(|state| state.record_settlement(settlement));
+
+    match handler.submit().await {
+        Ok(SubmittedTxidType::Bitcoin { txid }) => {
+            write_settlement_state(|state| {
+                let mut settlement = state.in_flight.get(&settlement_id).expect("should exist");
+                settlement.txids.push(txid.clone());
+                for leg in settlement.legs.iter() {
+                    state.clear_game_fees(agent_id, leg.trader, &leg.game_fees);
+                }
+                state.in_flight.insert(settlement_id, settlement);
+                state.clear_trades(&settled_keys);
+            });
+            write_trades(|trades| {
+                for key in settled_keys.iter() {
+                    trades.record_settlement_txid(key, &txid);
+                }
+            });
+            Ok(())
+        }
+        Err(err) => {
+            write_settlement_state(|state| state.in_flight.remove(&settlement_id));
+            Err(err)
+        }
+    }
+}
+
+/*
+ * nets the pending trades of a trader into a leg of the agent's settlement
+ * returns None when there's nothing to broadcast for the trader yet
+*/
+fn net_trader(
+    agent_id: u128,
+    runeid: Option<RuneId>,
+    trader: Principal,
+    trader_wallet: Wallet,
+    keys: &[TradeKey],
+) -> Result<Option<(SettlementLeg, BatchLeg)>, String> {
+    let trades = read_trades(|trades| {
+        keys.iter()
+            .filter_map(|key| trades.log.get(key).map(|trade| (*key, trade)))
+            .collect::<Vec<_>>()
+    });
+
+    // runes can only be delivered once the etching is indexed
+    // and a single leg delivers to a single address
+    let deliver_to = runeid.and_then(|_| {
+        trades
+            .iter()
+            .find_map(|(_, trade)| trade.deliver_to.clone())
+    });
+    let settled_trades = trades
+        .iter()
+        .filter(|(_, trade)| trade.deliver_to.is_none() || trade.deliver_to == deliver_to)
+        .collect::<Vec<_>>();
+    let rune_out = settled_trades
+        .iter()
+        .filter(|(_, trade)| trade.deliver_to.is_some())
+        .fold(0, |total, (_, trade)| total + trade.rune);
+    let bitcoin_out = settled_trades
+        .iter()
+        .filter(|(_, trade)| trade.side == TradeSide::Sell)
+        .fold(0, |total, (_, trade)| total + trade.owed_bitcoin);
+    let settled_keys = settled_trades
+        .iter()
+        .map(|(key, _)| *key)
+        .collect::<Vec<TradeKey>>();
+
+    let (bitcoin_in, trader_free_bitcoin) = read_ledger_entries(|entries| {
+        let entry = entries.get(&trader).unwrap_or_default();
+        let in_flight = read_settlement_state(|state| state.in_flight_bitcoin(agent_id, &trader));
+        let agent_owned = entry
+            .ledger_entries
+            .get(&agent_id)
+            .copied()
+            .unwrap_or_default()
+            .0;
+        let balance = read_utxo_manager(|manager| trader_wallet.bitcoin_balance(manager));
+        (
+            agent_owned.saturating_sub(in_flight),
+            balance.saturating_sub(entry.restricted_bitcoin_balance),
+        )
+    });
+
+    let game_fees = read_settlement_state(|state| state.game_fees_of(agent_id, trader));
+
+    let net_in = bitcoin_in.saturating_sub(bitcoin_out);
+    let net_out = bitcoin_out.saturating_sub(bitcoin_in);
+
+    if rune_out == 0 && net_in == 0 && net_out == 0 {
+        // flows cancel out inside the trader's deposit address, nothing to broadcast
+        if bitcoin_in > 0 {
+            write_ledger_entries(|entries| {
+                let mut entry = entries.get(&trader).unwrap_or_default();
+                entry.deduct_agent_owned_balance(agent_id, bitcoin_in);
+                entries.insert(trader, entry);
+            });
+        }
+        write_settlement_state(|state| {
+            state.clear_trades(&settled_keys);
+            state.clear_game_fees(agent_id, trader, &game_fees);
+        });
+        credit_prize_pools(agent_id, &game_fees);
+        return Ok(None);
+    }
+    if (net_in > 0 && net_in < DUST_THRESHOLD) || (net_out > 0 && net_out < DUST_THRESHOLD) {
+        // waiting for the flows to grow above dust
+        return Ok(None);
+    }
+
+    let rune_receiver = deliver_to
+        .as_deref()
+        .map(bitcoin::address_validation)
+        .transpose()?;
+
+    Ok(Some((
+        SettlementLeg {
+            trader,
+            bitcoin_in,
+            bitcoin_out,
+            rune_out,
+            trades: settled_keys,
+            game_fees,
+        },
+        BatchLeg {
+            trader: trader_wallet,
+            bitcoin_in,
+            bitcoin_out,
+            rune_out: rune_receiver.map(|address| (rune_out, address)),
+            free_bitcoin: trader_free_bitcoin,
+        },
+    )))
+}
+
+/*
+ * releases the agent owned satoshis from the trader's ledger once the settlement confirms
+ * any version of the settlement spends its watched input, the confirmations are counted
+ * from the tip the input was first seen spent at
+*/
+async fn reconcile_confirmed() {
+    let confirmations = read_config(|config| config.settlement_confirmations());
+    let submitted = read_settlement_state(|state| {
+        state
+            .in_flight
+            .iter()
+            .filter(|(_, settlement)| !settlement.txids.is_empty())
+            .collect::<Vec<_>>()
+    });
+    for (id, mut settlement) in submitted {
+        let seen_unspent = settlement.watch.seen_unspent;
+        let spent = broadcast::spent_at_tip(&mut settlement.watch).await;
+        if settlement.watch.seen_unspent != seen_unspent {
+            write_settlement_state(|state| state.update_watch(id, &settlement.watch));
+        }
+        let Some(tip) = spent else {
+            continue;
+        };
+        if settlement.spent_at.is_none() {
+            settlement.spent_at.replace(tip);
+            write_settlement_state(|state| {
+                if let Some(mut stored) = state.in_flight.get(&id) {
+                    stored.spent_at.replace(tip);
+                    state.in_flight.insert(id, stored);
+                }
+            });
+        }
+        if settlement.confirmations(tip) < confirmations {
+            continue;
+        }
+        write_ledger_entries(|entries| {
+            for leg in settlement.legs.iter().filter(|leg| leg.bitcoin_in > 0) {
+                let mut entry = entries.get(&leg.trader).unwrap_or_default();
+                entry.deduct_agent_owned_balance(settlement.agent_id, leg.bitcoin_in);
+                entries.insert(leg.trader, entry);
+            }
+        });
+        for leg in settlement.legs.iter() {
+            credit_prize_pools(settlement.agent_id, &leg.game_fees);
+        }
+        write_settlement_state(|state| state.in_flight.remove(&id));
+        ic_cdk::println!("settlement {} confirmed", settlement.txids.join(" or "));
+    }
+}
+
+// entry fees grow the prize pools once the agent holds the satoshis
+fn credit_prize_pools(agent_id: u128, fees: &[(GameKind, u64)]) {
+    write_games(|games| {
+        for (kind, fee) in fees {
+            games.update(agent_id, *kind, |game| game.round_mut().prize_pool.0 += fee);
+        }
+    });
+}
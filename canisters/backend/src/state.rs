@@ -7,19 +7,41 @@ use ic_stable_structures::{
 };
 
 mod agent;
+pub mod autopilot;
+pub mod broadcasts;
 mod chat_session;
 mod commission;
 mod config;
+pub mod counters;
+pub mod games;
+pub mod holders;
+pub mod jailbreak;
+mod keys;
+mod knowledge;
 mod ledger_entries;
 pub mod queue;
+pub mod settlement;
+pub mod trades;
 pub mod utxo_manager;
 
 use agent::AgentState;
+pub use agent::{AgentDetail, AgentLifecycle, TradeQuote};
+use autopilot::AutopilotState;
+use broadcasts::BroadcastState;
 use chat_session::ChatSession;
+pub use chat_session::SessionSummary;
 use commission::{Commission, init_commission};
 use config::Config;
+pub use config::{AutopilotConfig, FeePolicy, GuessConfig, LlmConfig, LlmOverrides, RbfConfig};
+use counters::{Counter, StableCounters, init_counters};
+use games::GameState;
+use holders::HolderState;
+use jailbreak::JailbreakState;
+use knowledge::KnowledgeState;
 use ledger_entries::{LedgerEntries, init_ledger_entries};
 use queue::ScheduledState;
+use settlement::SettlementState;
+use trades::TradeState;
 use utxo_manager::UtxoManager;
 
 type CanisterMemory = VirtualMemory<DefaultMemoryImpl>;
@@ -34,6 +56,26 @@ pub enum CanisterMemoryIds {
     Runic = 6,
     Queue = 7,
     Commission = 8,
+    Trades = 9,
+    PendingTrades = 10,
+    Settlement = 11,
+    Holders = 12,
+    HolderRanking = 13,
+    HolderCount = 14,
+    Counters = 15,
+    JailbreakProofs = 16,
+    Games = 17,
+    UserSessions = 18,
+    ChatMessages = 19,
+    Knowledge = 20,
+    Feed = 21,
+    AgentActions = 22,
+    AutopilotRuns = 23,
+    Broadcasts = 24,
+    UtxoLeases = 25,
+    GameFees = 26,
+    SessionActivity = 27,
+    KnowledgeDocIds = 28,
 }
 
 impl From<CanisterMemoryIds> for MemoryId {
@@ -52,6 +94,15 @@ thread_local! {
     pub static UTXO_MANAGER: RefCell<UtxoManager> = RefCell::default();
     pub static SCHEDULED_STATE: RefCell<ScheduledState> = RefCell::default();
     pub static COMMISSION: RefCell<Commission> = RefCell::new(init_commission());
+    pub static TRADES: RefCell<TradeState> = RefCell::default();
+    pub static SETTLEMENT: RefCell<SettlementState> = RefCell::default();
+    pub static HOLDERS: RefCell<HolderState> = RefCell::default();
+    pub static COUNTERS: RefCell<StableCounters> = RefCell::new(init_counters());
+    pub static JAILBREAK: RefCell<JailbreakState> = RefCell::default();
+    pub static GAMES: RefCell<GameState> = RefCell::default();
+    pub static KNOWLEDGE: RefCell<KnowledgeState> = RefCell::default();
+    pub static AUTOPILOT: RefCell<AutopilotState> = RefCell::default();
+    pub static BROADCASTS: RefCell<BroadcastState> = RefCell::default();
 }
 
 // helper functions
@@ -160,3 +211,136 @@ where
 {
     COMMISSION.with_borrow_mut(|commission| f(commission))
 }
+
+pub fn read_trades<F, R>(f: F) -> R
+where
+    F: FnOnce(&TradeState) -> R,
+{
+    TRADES.with_borrow(|trades| f(trades))
+}
+
+pub fn write_trades<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut TradeState) -> R,
+{
+    TRADES.with_borrow_mut(|trades| f(trades))
+}
+
+pub fn read_settlement_state<F, R>(f: F) -> R
+where
+    F: FnOnce(&SettlementState) -> R,
+{
+    SETTLEMENT.with_borrow(|state| f(state))
+}
+
+pub fn write_settlement_state<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut SettlementState) -> R,
+{
+    SETTLEMENT.with_borrow_mut(|state| f(state))
+}
+
+pub fn read_holders<F, R>(f: F) -> R
+where
+    F: FnOnce(&HolderState) -> R,
+{
+    HOLDERS.with_borrow(|holders| f(holders))
+}
+
+pub fn write_holders<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut HolderState) -> R,
+{
+    HOLDERS.with_borrow_mut(|holders| f(holders))
+}
+
+pub fn read_jailbreak_state<F, R>(f: F) -> R
+where
+    F: FnOnce(&JailbreakState) -> R,
+{
+    JAILBREAK.with_borrow(|state| f(state))
+}
+
+pub fn write_jailbreak_state<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut JailbreakState) -> R,
+{
+    JAILBREAK.with_borrow_mut(|state| f(state))
+}
+
+pub fn read_games<F, R>(f: F) -> R
+where
+    F: FnOnce(&GameState) -> R,
+{
+    GAMES.with_borrow(|games| f(games))
+}
+
+pub fn write_games<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut GameState) -> R,
+{
+    GAMES.with_borrow_mut(|games| f(games))
+}
+
+pub fn read_knowledge<F, R>(f: F) -> R
+where
+    F: FnOnce(&KnowledgeState) -> R,
+{
+    KNOWLEDGE.with_borrow(|state| f(state))
+}
+
+pub fn write_knowledge<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut KnowledgeState) -> R,
+{
+    KNOWLEDGE.with_borrow_mut(|state| f(state))
+}
+
+pub fn read_autopilot<F, R>(f: F) -> R
+where
+    F: FnOnce(&AutopilotState) -> R,
+{
+    AUTOPILOT.with_borrow(|state| f(state))
+}
+
+pub fn write_autopilot<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut AutopilotState) -> R,
+{
+    AUTOPILOT.with_borrow_mut(|state| f(state))
+}
+
+pub fn read_broadcasts<F, R>(f: F) -> R
+where
+    F: FnOnce(&BroadcastState) -> R,
+{
+    BROADCASTS.with_borrow(|state| f(state))
+}
+
+pub fn write_broadcasts<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BroadcastState) -> R,
+{
+    BROADCASTS.with_borrow_mut(|state| f(state))
+}
+
+pub fn read_counter(counter: Counter) -> u128 {
+    COUNTERS.with_borrow(|counters| counters.get().get(counter))
+}
+
+/// returns the next id of the counter, persisted in stable memory
+pub fn next_id(counter: Counter) -> u128 {
+    COUNTERS.with_borrow_mut(|counters| {
+        let mut temp = counters.get().clone();
+        let id = temp.next(counter);
+        counters.set(temp).expect("failed to set counters");
+        id
+    })
+}
+
+pub fn write_counters<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut StableCounters) -> R,
+{
+    COUNTERS.with_borrow_mut(|counters| f(counters))
+}
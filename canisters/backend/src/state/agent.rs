@@ -1,12 +1,231 @@
+use crate::bitcoin::{AddressType, wallet::Wallet};
+use crate::commitment::{RevealedSecret, SecretCommitment};
+use crate::games::{GameKind, GameMode};
+use crate::knowledge::Persona;
 use candid::{CandidType, Decode, Encode, Principal};
 use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 
+use super::config::{LlmConfig, LlmOverrides};
 use super::{
-    CanisterMemory, CanisterMemoryIds, read_config, read_memory_manager, write_commission_state,
+    CanisterMemory, CanisterMemoryIds, counters::Counter, next_id, read_commission_state,
+    read_config, read_counter, read_games, read_holders, read_memory_manager, read_trades,
+    write_commission_state,
 };
 
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AgentLifecycle {
+    EtchingPending,
+    Live,      // trading on the bonding curve
+    Graduated, // trading on the liquidity pool
+}
+
+const MAX_BPS: u128 = 10_000;
+const RUNE_UNIT: f64 = 1_000.0; // runes are etched with a divisibility of 3
+
+/// Outcome of a trade, computed without touching any state
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TradeQuote {
+    pub amount_in: u128,
+    pub amount_out: u128,
+    pub treasury_fee: u128, // satoshis, charged on the curve
+    pub dex_fee: u128,      // satoshis, charged on the curve
+    pub lp_fee: u128,       // in the input asset, charged on the pool
+    pub price_impact_bps: u64,
+    pub spot_price: f64, // satoshis per whole token after the trade
+    // reserves of the market the trade runs on, after the trade
+    pub collateral_reserve: u128,
+    pub token_reserve: u128,
+}
+
+impl TradeQuote {
+    fn new(
+        amount_in: u128,
+        amount_out: u128,
+        (treasury_fee, dex_fee): (u128, u128),
+        lp_fee: u128,
+        (collateral_before, token_before): (u128, u128),
+        (collateral_reserve, token_reserve): (u128, u128),
+    ) -> Self {
+        let price_before = spot_price(collateral_before, token_before);
+        let spot_price = spot_price(collateral_reserve, token_reserve);
+        let price_impact_bps = if price_before == 0.0 {
+            0
+        } else {
+            ((spot_price - price_before).abs() / price_before * MAX_BPS as f64) as u64
+        };
+        Self {
+            amount_in,
+            amount_out,
+            treasury_fee,
+            dex_fee,
+            lp_fee,
+            price_impact_bps,
+            spot_price,
+            collateral_reserve,
+            token_reserve,
+        }
+    }
+}
+
+pub(super) fn spot_price(collateral_reserve: u128, token_reserve: u128) -> f64 {
+    if token_reserve == 0 {
+        return 0.0;
+    }
+    collateral_reserve as f64 * RUNE_UNIT / token_reserve as f64
+}
+
+/// Constant product (x * y = k) pool an agent trades through once it graduates from the curve.
+/// Reserves are a part of the agent's actual `bitcoin` and `rune` balances and the LP fee is
+/// kept in the reserves.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LiquidityPool {
+    pub bitcoin_reserve: u128,
+    pub rune_reserve: u128,
+    pub lp_fee_bps: u16,
+}
+
+impl LiquidityPool {
+    /// Output for an exact input, fee is charged on the input
+    fn amount_out(
+        &self,
+        amount_in: u128,
+        reserve_in: u128,
+        reserve_out: u128,
+    ) -> Result<u128, &'static str> {
+        let amount_in_with_fee = amount_in
+            .checked_mul(MAX_BPS - self.lp_fee_bps as u128)
+            .ok_or("Calculation overflow")?;
+        let numerator = amount_in_with_fee
+            .checked_mul(reserve_out)
+            .ok_or("Calculation overflow")?;
+        let denominator = reserve_in
+            .checked_mul(MAX_BPS)
+            .and_then(|result| result.checked_add(amount_in_with_fee))
+            .ok_or("Calculation overflow")?;
+        numerator.checked_div(denominator).ok_or("Division by zero")
+    }
+
+    /// Input required for an exact output, rounded up in favour of the pool
+    fn amount_in(
+        &self,
+        amount_out: u128,
+        reserve_in: u128,
+        reserve_out: u128,
+    ) -> Result<u128, &'static str> {
+        if amount_out >= reserve_out {
+            return Err("Insufficient pool reserves");
+        }
+        let numerator = reserve_in
+            .checked_mul(amount_out)
+            .and_then(|result| result.checked_mul(MAX_BPS))
+            .ok_or("Calculation overflow")?;
+        let denominator = (reserve_out - amount_out)
+            .checked_mul(MAX_BPS - self.lp_fee_bps as u128)
+            .ok_or("Calculation overflow")?;
+        numerator
+            .checked_div(denominator)
+            .map(|result| result + 1)
+            .ok_or("Division by zero")
+    }
+
+    fn lp_fee(&self, amount_in: u128) -> u128 {
+        amount_in * self.lp_fee_bps as u128 / MAX_BPS
+    }
+
+    fn reserves(&self) -> (u128, u128) {
+        (self.bitcoin_reserve, self.rune_reserve)
+    }
+
+    fn reserves_after_buy(
+        &self,
+        bitcoin_in: u128,
+        rune_out: u128,
+    ) -> Result<(u128, u128), &'static str> {
+        Ok((
+            self.bitcoin_reserve
+                .checked_add(bitcoin_in)
+                .ok_or("Bitcoin reserve overflow")?,
+            self.rune_reserve
+                .checked_sub(rune_out)
+                .ok_or("Insufficient rune reserve")?,
+        ))
+    }
+
+    fn reserves_after_sell(
+        &self,
+        rune_in: u128,
+        bitcoin_out: u128,
+    ) -> Result<(u128, u128), &'static str> {
+        Ok((
+            self.bitcoin_reserve
+                .checked_sub(bitcoin_out)
+                .ok_or("Insufficient bitcoin reserve")?,
+            self.rune_reserve
+                .checked_add(rune_in)
+                .ok_or("Rune reserve overflow")?,
+        ))
+    }
+
+    pub fn quote_buy_exact_in(&self, collateral_in: u128) -> Result<TradeQuote, &'static str> {
+        let tokens_out = self.amount_out(collateral_in, self.bitcoin_reserve, self.rune_reserve)?;
+        Ok(TradeQuote::new(
+            collateral_in,
+            tokens_out,
+            (0, 0),
+            self.lp_fee(collateral_in),
+            self.reserves(),
+            self.reserves_after_buy(collateral_in, tokens_out)?,
+        ))
+    }
+
+    pub fn quote_buy_exact_out(&self, token_amount: u128) -> Result<TradeQuote, &'static str> {
+        let collateral_in =
+            self.amount_in(token_amount, self.bitcoin_reserve, self.rune_reserve)?;
+        Ok(TradeQuote::new(
+            collateral_in,
+            token_amount,
+            (0, 0),
+            self.lp_fee(collateral_in),
+            self.reserves(),
+            self.reserves_after_buy(collateral_in, token_amount)?,
+        ))
+    }
+
+    pub fn quote_sell_exact_in(&self, token_amount: u128) -> Result<TradeQuote, &'static str> {
+        let collateral_out =
+            self.amount_out(token_amount, self.rune_reserve, self.bitcoin_reserve)?;
+        Ok(TradeQuote::new(
+            token_amount,
+            collateral_out,
+            (0, 0),
+            self.lp_fee(token_amount),
+            self.reserves(),
+            self.reserves_after_sell(token_amount, collateral_out)?,
+        ))
+    }
+
+    pub fn quote_sell_exact_out(&self, collateral_out: u128) -> Result<TradeQuote, &'static str> {
+        let tokens_in = self.amount_in(collateral_out, self.rune_reserve, self.bitcoin_reserve)?;
+        Ok(TradeQuote::new(
+            tokens_in,
+            collateral_out,
+            (0, 0),
+            self.lp_fee(tokens_in),
+            self.reserves(),
+            self.reserves_after_sell(tokens_in, collateral_out)?,
+        ))
+    }
+
+    pub fn market_cap(&self, total_supply: u128) -> u128 {
+        self.bitcoin_reserve
+            .checked_mul(total_supply)
+            .and_then(|result| result.checked_div(self.rune_reserve))
+            .unwrap_or(0)
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct AgentDetail {
     pub agent_id: u128,
@@ -14,6 +233,7 @@ pub struct AgentDetail {
     pub created_by: Principal,
     pub allocated_raw_subaccount: [u8; 32],
     pub txns: (Option<String>, Option<String>),
+    pub replaced_reveals: Option<Vec<String>>, // earlier versions of the reveal, any can confirm
     pub runeid: Option<String>,
     pub name: String,
     pub ticker: u32,
@@ -24,11 +244,15 @@ pub struct AgentDetail {
     pub openchat: Option<String>,
     pub discord: Option<String>,
 
-    // bait the bot
-    pub past_winners: HashSet<(u64, u64, u128, Principal, String)>, // data -> (time, amount_in_bitcoin, amount_in_rune, winner, secret)
-    pub current_prize_pool: (u64, u128),
+    // bait the bot, moved into `games::LuckyDraw` by the version 3 migration
+    pub past_winners: Option<HashSet<(u64, u64, u128, Principal, String)>>, // data -> (time, amount_in_bitcoin, amount_in_rune, winner, secret)
+    pub current_prize_pool: Option<(u64, u128)>,
     pub secret: Option<String>,
+    pub game_mode: Option<GameMode>,
+    pub commitment: Option<SecretCommitment>,
+    pub revealed_secrets: Option<Vec<RevealedSecret>>,
     pub current_winner: Option<Principal>,
+    pub guess_count: Option<u64>,
 
     // market maker
     pub total_supply: u128,
@@ -41,9 +265,16 @@ pub struct AgentDetail {
     // balances
     pub bitcoin: u128,
     pub rune: u128,
+    pub prize_runes: Option<u128>, // rune entry fees held for prize pools, apart from `rune`
+
+    // graduation
+    pub pool: Option<LiquidityPool>,
+    pub graduated_at: Option<u64>,
 
-    // user balances
-    pub balances: HashSet<String>,
+    pub llm: Option<LlmOverrides>, // set by the admin, see `LlmConfig`
+    pub persona: Option<Persona>,  // set by the creator
+    pub autopilot: Option<bool>,   // opted in by the creator, off for older agents
+    pub address_type: Option<AddressType>, // picked at creation, legacy for older agents
 }
 
 impl Storable for AgentDetail {
@@ -79,21 +310,68 @@ impl AgentDetail {
         Some(url)
     }
 
-    pub fn get_bitcoin_address(&self) -> String {
-        crate::bitcoin::account_to_p2pkh_address(&icrc_ledger_types::icrc1::account::Account {
+    pub fn get_account(&self) -> icrc_ledger_types::icrc1::account::Account {
+        icrc_ledger_types::icrc1::account::Account {
             owner: ic_cdk::id(),
             subaccount: Some(self.allocated_raw_subaccount),
-        })
+        }
+    }
+
+    pub fn address_type(&self) -> AddressType {
+        self.address_type.unwrap_or_default()
+    }
+
+    pub fn get_bitcoin_address(&self) -> String {
+        crate::bitcoin::account_to_address(&self.get_account(), self.address_type()).to_string()
+    }
+
+    /// agents only ever use the address type they were created with
+    pub fn wallet(&self) -> Wallet {
+        Wallet::new(self.get_account(), &[self.address_type()])
+    }
+
+    pub fn lifecycle(&self) -> AgentLifecycle {
+        if self.pool.is_some() {
+            AgentLifecycle::Graduated
+        } else if self.runeid.is_none() {
+            AgentLifecycle::EtchingPending
+        } else {
+            AgentLifecycle::Live
+        }
     }
 
     pub fn market_cap(&self) -> u128 {
+        if let Some(ref pool) = self.pool {
+            return pool.market_cap(self.total_supply);
+        }
         self.virtual_collateral_reserves
             .checked_mul(self.total_supply)
             .and_then(|result| result.checked_div(self.virtual_token_reserves))
             .unwrap_or(0)
     }
 
+    /// the canister's llm config with the overrides of the agent applied
+    pub fn llm_config(&self) -> LlmConfig {
+        let config = read_config(|config| config.llm_config());
+        match self.llm {
+            Some(ref overrides) => config.with_overrides(overrides),
+            None => config,
+        }
+    }
+
+    /// satoshis per whole token on the market the agent currently trades on
+    pub fn price(&self) -> f64 {
+        let (collateral_reserve, token_reserve) = match self.pool {
+            Some(ref pool) => pool.reserves(),
+            None => self.virtual_reserves(),
+        };
+        spot_price(collateral_reserve, token_reserve)
+    }
+
     pub fn agent_query(&self) -> crate::AgentDetails {
+        let config = read_config(|config| config.guess_config());
+        let games = read_games(|games| games.views_of(self.agent_id, &config, ic_cdk::api::time()));
+        let lucky_draw = games.iter().find(|game| game.kind == GameKind::LuckyDraw);
         crate::AgentDetails {
             created_at: self.created_at,
             created_by: self.created_by.to_text(),
@@ -107,14 +385,79 @@ impl AgentDetail {
             openchat: self.openchat.clone(),
             discord: self.discord.clone(),
             total_supply: self.total_supply,
-            holders: self.balances.len() as u32,
+            holders: read_holders(|holders| holders.holder_count(self.agent_id)),
             market_cap: self.market_cap() as u64,
-            current_prize_pool: self.current_prize_pool,
-            current_winner: self.current_winner.clone(),
+            current_prize_pool: lucky_draw.map_or((0, 0), |game| game.prize_pool),
+            current_winner: lucky_draw.and_then(|game| game.winner),
+            games,
             txns: self.txns.clone(),
+            lifecycle: self.lifecycle(),
+            graduated_at: self.graduated_at,
         }
     }
 
+    /// runes of the agent that don't back the liquidity pool, they pay the configured rune prizes
+    pub fn free_runes(&self) -> Result<u128, String> {
+        let reserve = self.pool.as_ref().map_or(0, |pool| pool.rune_reserve);
+        self.rune
+            .checked_sub(reserve)
+            .ok_or_else(|| String::from("agent holds less runes than its pool"))
+    }
+
+    /// takes a rune prize out of the entry fees first, then out of the free runes
+    /// returns (from the entry fees, from the free runes), see `return_prize_runes`
+    pub fn take_prize_runes(&mut self, prize: u128) -> Result<(u128, u128), String> {
+        if prize == 0 {
+            return Ok((0, 0));
+        }
+        let (from_fees, from_free) =
+            split_rune_prize(prize, self.prize_runes.unwrap_or(0), self.free_runes()?)?;
+        self.prize_runes = Some(self.prize_runes.unwrap_or(0) - from_fees);
+        self.rune -= from_free;
+        Ok((from_fees, from_free))
+    }
+
+    /// hands back the runes of a prize that wasn't paid out
+    pub fn return_prize_runes(&mut self, (from_fees, from_free): (u128, u128)) {
+        self.prize_runes = Some(self.prize_runes.unwrap_or(0) + from_fees);
+        self.rune += from_free;
+    }
+
+    /// Moves trading from the curve to the liquidity pool once the market cap crosses the
+    /// graduation threshold. The pool is seeded with the agent's bitcoin, minus the commission
+    /// accrued on the curve, and the runes matching the curve's closing price so that the price
+    /// carries over. Runes left out of the pool stay with the agent.
+    fn graduate_if_needed(&mut self) {
+        let (threshold, lp_fee_bps) =
+            read_config(|config| (config.graduation_market_cap(), config.lp_fee_bps()));
+        if self.pool.is_some() || self.market_cap() < threshold as u128 {
+            return;
+        }
+        let commission = read_commission_state(|state| state.get(&self.agent_id).unwrap_or(0));
+        let bitcoin_reserve = self.bitcoin.saturating_sub(commission as u128);
+        let rune_reserve = bitcoin_reserve
+            .checked_mul(self.virtual_token_reserves)
+            .and_then(|result| result.checked_div(self.virtual_collateral_reserves))
+            .unwrap_or(0)
+            .min(self.rune);
+        if bitcoin_reserve == 0 || rune_reserve == 0 {
+            return;
+        }
+        self.pool.replace(LiquidityPool {
+            bitcoin_reserve,
+            rune_reserve,
+            lp_fee_bps,
+        });
+        self.graduated_at.replace(ic_cdk::api::time());
+    }
+
+    fn virtual_reserves(&self) -> (u128, u128) {
+        (
+            self.virtual_collateral_reserves,
+            self.virtual_token_reserves,
+        )
+    }
+
     /// Calculate the fees based on an input amount.
     /// Returns a tuple: (treasury_fee minus DEX fee, dex_fee).
     fn calculate_fee(&self, amount: u128) -> (u128, u128) {
@@ -123,26 +466,19 @@ impl AgentDetail {
         (treasury_fee - dex_fee, dex_fee)
     }
 
-    /// Buy tokens with exact collateral in (BTC -> RUNE)
-    pub fn buy_exact_in(
-        &mut self,
-        collateral_in: u128,
-        min_tokens_out: u128,
-    ) -> Result<u128, &'static str> {
+    /// Quote buying tokens with exact collateral in (BTC -> RUNE)
+    pub fn quote_buy_exact_in(&self, collateral_in: u128) -> Result<TradeQuote, &'static str> {
+        if let Some(ref pool) = self.pool {
+            return pool.quote_buy_exact_in(collateral_in);
+        }
         // Calculate fees
-        let (treasury_fee, dex_fee) = self.calculate_fee(collateral_in);
+        let fees @ (treasury_fee, dex_fee) = self.calculate_fee(collateral_in);
         let collateral_to_spend = collateral_in
             .checked_sub(treasury_fee)
             .ok_or("Fee subtraction underflow")?
             .checked_sub(dex_fee)
             .ok_or("Fee subtraction underflow")?;
 
-        // Record commission fees in balances (fees are in BTC, so update the bitcoin balance in the tuple)
-        write_commission_state(|state| {
-            let mut prev = state.get(&self.agent_id).unwrap_or(0);
-            prev += treasury_fee as u64 + dex_fee as u64;
-            state.insert(self.agent_id, prev);
-        });
         // Calculate tokens to receive
         let tokens_out = (collateral_to_spend * self.virtual_token_reserves)
             .checked_div(
@@ -152,39 +488,29 @@ impl AgentDetail {
             )
             .ok_or("Calculation overflow")?;
 
-        // Slippage check
-        if tokens_out < min_tokens_out {
-            return Err("Slippage check failed");
-        }
-
-        self.virtual_token_reserves = self
-            .virtual_token_reserves
-            .checked_sub(tokens_out)
-            .ok_or("Insufficient token reserves")?;
-        self.virtual_collateral_reserves = self
-            .virtual_collateral_reserves
-            .checked_add(collateral_to_spend)
-            .ok_or("Collateral reserve overflow")?;
-
-        // Update actual balances
-        self.bitcoin = self
-            .bitcoin
-            .checked_add(collateral_in)
-            .ok_or("Bitcoin balance overflow")?;
-        self.rune = self
-            .rune
-            .checked_sub(tokens_out)
-            .ok_or("Insufficient rune balance")?;
-        Ok(tokens_out)
+        let reserves = (
+            self.virtual_collateral_reserves
+                .checked_add(collateral_to_spend)
+                .ok_or("Collateral reserve overflow")?,
+            self.virtual_token_reserves
+                .checked_sub(tokens_out)
+                .ok_or("Insufficient token reserves")?,
+        );
+        Ok(TradeQuote::new(
+            collateral_in,
+            tokens_out,
+            fees,
+            0,
+            self.virtual_reserves(),
+            reserves,
+        ))
     }
 
-    pub fn buy_exact_out(
-        &mut self,
-        token_amount: u128,
-        max_collateral: u128,
-    ) -> Result<u128, &'static str> {
-        // let commission_receiver = read_config(|config| config.commission_receiver());
-
+    /// Quote buying exact tokens out (BTC -> RUNE)
+    pub fn quote_buy_exact_out(&self, token_amount: u128) -> Result<TradeQuote, &'static str> {
+        if let Some(ref pool) = self.pool {
+            return pool.quote_buy_exact_out(token_amount);
+        }
         // Calculate collateral needed for token_amount
         let collateral_to_spend = token_amount
             .checked_mul(self.virtual_collateral_reserves)
@@ -197,63 +523,35 @@ impl AgentDetail {
             .ok_or("Division by zero")?;
 
         // Calculate fees
-        let (treasury_fee, dex_fee) = self.calculate_fee(collateral_to_spend);
+        let fees @ (treasury_fee, dex_fee) = self.calculate_fee(collateral_to_spend);
         let collateral_with_fee = collateral_to_spend
             .checked_add(treasury_fee)
             .and_then(|sum| sum.checked_add(dex_fee))
             .ok_or("Fee calculation overflow")?;
 
-        // Record commission fees in balances (update BTC)
-        write_commission_state(|state| {
-            let mut prev = state.get(&self.agent_id).unwrap_or(0);
-            prev += treasury_fee as u64 + dex_fee as u64;
-            state.insert(self.agent_id, prev);
-        });
-        /* let entry = self
-            .balances
-            .entry(commission_receiver.clone())
-            .or_insert((0, 0));
-        entry.0 = entry
-            .0
-            .checked_add((treasury_fee + dex_fee) as u64)
-            .ok_or("Commission transfer overflow")?; */
-
-        // Slippage check
-        if collateral_with_fee > max_collateral {
-            return Err("Slippage check failed");
-        }
-
-        // Update virtual reserves
-        self.virtual_token_reserves = self
-            .virtual_token_reserves
-            .checked_sub(token_amount)
-            .ok_or("Insufficient token reserves")?;
-        self.virtual_collateral_reserves = self
-            .virtual_collateral_reserves
-            .checked_add(collateral_to_spend)
-            .ok_or("Collateral reserve overflow")?;
-
-        // Update actual balances
-        self.bitcoin = self
-            .bitcoin
-            .checked_add(collateral_with_fee)
-            .ok_or("Bitcoin balance overflow")?;
-        self.rune = self
-            .rune
-            .checked_sub(token_amount)
-            .ok_or("Insufficient rune balance")?;
-
-        Ok(collateral_with_fee)
+        let reserves = (
+            self.virtual_collateral_reserves
+                .checked_add(collateral_to_spend)
+                .ok_or("Collateral reserve overflow")?,
+            self.virtual_token_reserves
+                .checked_sub(token_amount)
+                .ok_or("Insufficient token reserves")?,
+        );
+        Ok(TradeQuote::new(
+            collateral_with_fee,
+            token_amount,
+            fees,
+            0,
+            self.virtual_reserves(),
+            reserves,
+        ))
     }
 
-    /// Sell exact tokens in (RUNE -> BTC)
-    pub fn sell_exact_in(
-        &mut self,
-        token_amount: u128,
-        min_collateral_out: u128,
-    ) -> Result<u128, &'static str> {
-        // let commission_receiver = read_config(|config| config.commission_receiver());
-
+    /// Quote selling exact tokens in (RUNE -> BTC)
+    pub fn quote_sell_exact_in(&self, token_amount: u128) -> Result<TradeQuote, &'static str> {
+        if let Some(ref pool) = self.pool {
+            return pool.quote_sell_exact_in(token_amount);
+        }
         // Calculate collateral to receive
         let collateral_to_receive = (token_amount
             .checked_mul(self.virtual_collateral_reserves)
@@ -266,65 +564,37 @@ impl AgentDetail {
         .ok_or("Division by zero")?;
 
         // Calculate fees
-        let (treasury_fee, dex_fee) = self.calculate_fee(collateral_to_receive);
+        let fees @ (treasury_fee, dex_fee) = self.calculate_fee(collateral_to_receive);
         let collateral_minus_fee = collateral_to_receive
             .checked_sub(treasury_fee)
             .and_then(|diff| diff.checked_sub(dex_fee))
             .ok_or("Fee subtraction underflow")?;
 
-        // Record commission fees in balances (update BTC)
-        write_commission_state(|state| {
-            let mut prev = state.get(&self.agent_id).unwrap_or(0);
-            prev += treasury_fee as u64 + dex_fee as u64;
-            state.insert(self.agent_id, prev);
-        });
-        /* let entry = self
-            .balances
-            .entry(commission_receiver.clone())
-            .or_insert((0, 0));
-        entry.0 = entry
-            .0
-            .checked_add((treasury_fee + dex_fee) as u64)
-            .ok_or("Commission transfer overflow")?; */
-
-        // Slippage check
-        if collateral_minus_fee < min_collateral_out {
-            return Err("Slippage check failed");
-        }
-
-        // Update virtual reserves
-        self.virtual_token_reserves = self
-            .virtual_token_reserves
-            .checked_add(token_amount)
-            .ok_or("Token reserve overflow")?;
-        self.virtual_collateral_reserves = self
-            .virtual_collateral_reserves
-            .checked_sub(collateral_to_receive)
-            .ok_or("Insufficient collateral reserves")?;
-
-        // Update actual balances
-        self.bitcoin = self
-            .bitcoin
-            .checked_sub(collateral_minus_fee)
-            .ok_or("Insufficient bitcoin balance")?;
-        self.rune = self
-            .rune
-            .checked_add(token_amount)
-            .ok_or("Rune balance overflow")?;
-
-        Ok(collateral_minus_fee)
+        let reserves = (
+            self.virtual_collateral_reserves
+                .checked_sub(collateral_to_receive)
+                .ok_or("Insufficient collateral reserves")?,
+            self.virtual_token_reserves
+                .checked_add(token_amount)
+                .ok_or("Token reserve overflow")?,
+        );
+        Ok(TradeQuote::new(
+            token_amount,
+            collateral_minus_fee,
+            fees,
+            0,
+            self.virtual_reserves(),
+            reserves,
+        ))
     }
 
-    /// Sell tokens to receive exact collateral out (RUNE -> BTC)
-    pub fn sell_exact_out(
-        &mut self,
-        max_token_amount: u128,
-        collateral_out: u128,
-    ) -> Result<u128, &'static str> {
-        // let commission_receiver = read_config(|config| config.commission_receiver());
-
+    /// Quote selling tokens to receive exact collateral out (RUNE -> BTC)
+    pub fn quote_sell_exact_out(&self, collateral_out: u128) -> Result<TradeQuote, &'static str> {
+        if let Some(ref pool) = self.pool {
+            return pool.quote_sell_exact_out(collateral_out);
+        }
         // Calculate fees
-        let (treasury_fee, dex_fee) = self.calculate_fee(collateral_out);
+        let fees @ (treasury_fee, dex_fee) = self.calculate_fee(collateral_out);
         let total_collateral_needed = collateral_out
             .checked_add(treasury_fee)
             .and_then(|sum| sum.checked_add(dex_fee))
@@ -341,47 +611,130 @@ impl AgentDetail {
         )
         .ok_or("Division by zero")?;
 
-        // Record commission fees in balances (update BTC)
-        write_commission_state(|state| {
-            let mut prev = state.get(&self.agent_id).unwrap_or(0);
-            prev += treasury_fee as u64 + dex_fee as u64;
-            state.insert(self.agent_id, prev);
-        });
-        /* let entry = self
-            .balances
-            .entry(commission_receiver.clone())
-            .or_insert((0, 0));
-        entry.0 = entry
-            .0
-            .checked_add((treasury_fee + dex_fee) as u64)
-            .ok_or("Commission transfer overflow")?; */
+        let reserves = (
+            self.virtual_collateral_reserves
+                .checked_sub(total_collateral_needed)
+                .ok_or("Insufficient collateral reserves")?,
+            self.virtual_token_reserves
+                .checked_add(tokens_needed)
+                .ok_or("Token reserve overflow")?,
+        );
+        Ok(TradeQuote::new(
+            tokens_needed,
+            collateral_out,
+            fees,
+            0,
+            self.virtual_reserves(),
+            reserves,
+        ))
+    }
 
-        // Slippage check
-        if tokens_needed > max_token_amount {
-            return Err("Slippage check failed");
+    /// Commits a quoted trade: commission, reserves of the active market and actual balances
+    fn apply_quote(&mut self, quote: &TradeQuote, bitcoin: u128, rune: u128) {
+        let commission = quote.treasury_fee + quote.dex_fee;
+        if commission > 0 {
+            write_commission_state(|state| {
+                let mut prev = state.get(&self.agent_id).unwrap_or(0);
+                prev += commission as u64;
+                state.insert(self.agent_id, prev);
+            });
+        }
+        if let Some(ref mut pool) = self.pool {
+            pool.bitcoin_reserve = quote.collateral_reserve;
+            pool.rune_reserve = quote.token_reserve;
+        } else {
+            self.virtual_collateral_reserves = quote.collateral_reserve;
+            self.virtual_token_reserves = quote.token_reserve;
         }
+        self.bitcoin = bitcoin;
+        self.rune = rune;
+    }
+
+    fn apply_buy(&mut self, quote: &TradeQuote) -> Result<(), &'static str> {
+        let bitcoin = self
+            .bitcoin
+            .checked_add(quote.amount_in)
+            .ok_or("Bitcoin balance overflow")?;
+        let rune = self
+            .rune
+            .checked_sub(quote.amount_out)
+            .ok_or("Insufficient rune balance")?;
+        self.apply_quote(quote, bitcoin, rune);
+        self.graduate_if_needed();
+        Ok(())
+    }
 
-        // Update virtual reserves
-        self.virtual_token_reserves = self
-            .virtual_token_reserves
-            .checked_add(tokens_needed)
-            .ok_or("Token reserve overflow")?;
-        self.virtual_collateral_reserves = self
-            .virtual_collateral_reserves
-            .checked_sub(total_collateral_needed)
-            .ok_or("Insufficient collateral reserves")?;
-
-        // Update actual balances
-        self.bitcoin = self
+    fn apply_sell(&mut self, quote: &TradeQuote) -> Result<(), &'static str> {
+        let bitcoin = self
             .bitcoin
-            .checked_sub(collateral_out)
+            .checked_sub(quote.amount_out)
             .ok_or("Insufficient bitcoin balance")?;
-        self.rune = self
+        let rune = self
             .rune
-            .checked_add(tokens_needed)
+            .checked_add(quote.amount_in)
             .ok_or("Rune balance overflow")?;
+        self.apply_quote(quote, bitcoin, rune);
+        Ok(())
+    }
 
-        Ok(tokens_needed)
+    /// Buy tokens with exact collateral in (BTC -> RUNE)
+    pub fn buy_exact_in(
+        &mut self,
+        collateral_in: u128,
+        min_tokens_out: u128,
+    ) -> Result<TradeQuote, &'static str> {
+        let quote = self.quote_buy_exact_in(collateral_in)?;
+        // Slippage check
+        if quote.amount_out < min_tokens_out {
+            return Err("Slippage check failed");
+        }
+        self.apply_buy(&quote)?;
+        Ok(quote)
+    }
+
+    /// Buy exact tokens out (BTC -> RUNE)
+    pub fn buy_exact_out(
+        &mut self,
+        token_amount: u128,
+        max_collateral: u128,
+    ) -> Result<TradeQuote, &'static str> {
+        let quote = self.quote_buy_exact_out(token_amount)?;
+        // Slippage check
+        if quote.amount_in > max_collateral {
+            return Err("Slippage check failed");
+        }
+        self.apply_buy(&quote)?;
+        Ok(quote)
+    }
+
+    /// Sell exact tokens in (RUNE -> BTC)
+    pub fn sell_exact_in(
+        &mut self,
+        token_amount: u128,
+        min_collateral_out: u128,
+    ) -> Result<TradeQuote, &'static str> {
+        let quote = self.quote_sell_exact_in(token_amount)?;
+        // Slippage check
+        if quote.amount_out < min_collateral_out {
+            return Err("Slippage check failed");
+        }
+        self.apply_sell(&quote)?;
+        Ok(quote)
+    }
+
+    /// Sell tokens to receive exact collateral out (RUNE -> BTC)
+    pub fn sell_exact_out(
+        &mut self,
+        max_token_amount: u128,
+        collateral_out: u128,
+    ) -> Result<TradeQuote, &'static str> {
+        let quote = self.quote_sell_exact_out(collateral_out)?;
+        // Slippage check
+        if quote.amount_in > max_token_amount {
+            return Err("Slippage check failed");
+        }
+        self.apply_sell(&quote)?;
+        Ok(quote)
     }
 }
 
@@ -391,7 +744,6 @@ pub type AssociatedAgentSet = StableBTreeMap<String, u128, CanisterMemory>;
 
 pub struct AgentState {
     pub mapping: AgentMapping,
-    _count: u128,
     _associated_set: AssociatedAgentSet,
 }
 
@@ -399,7 +751,6 @@ impl Default for AgentState {
     fn default() -> Self {
         read_memory_manager(|manager| Self {
             mapping: AgentMapping::init(manager.get(CanisterMemoryIds::Agent.into())),
-            _count: 0,
             _associated_set: AssociatedAgentSet::init(
                 manager.get(CanisterMemoryIds::AssociatedAgentSet.into()),
             ),
@@ -410,12 +761,10 @@ impl Default for AgentState {
 impl AgentState {
     pub fn get_agent_id(&mut self) -> u128 {
         let allowed_count = read_config(|config| config.allowed_agent_count);
-        if self._count > allowed_count {
+        if read_counter(Counter::Agent) > allowed_count {
             ic_cdk::trap("Exceeds allowed number of agent")
         }
-        let id = self._count;
-        self._count += 1;
-        id
+        next_id(Counter::Agent)
     }
 
     pub fn find_agent_id(&self, agent_id: crate::AgentBy) -> Option<u128> {
@@ -438,7 +787,6 @@ impl AgentState {
         id: u128,
         created_by: Principal,
         allocated_raw_subaccount: [u8; 32],
-        secret: String,
         name: String,
         ticker: u32,
         logo: Option<String>, // should be in uri format
@@ -448,6 +796,7 @@ impl AgentState {
         openchat: Option<String>,
         discord: Option<String>,
         total_supply: u128,
+        address_type: AddressType,
     ) -> (Option<String>, String) {
         let agent = AgentDetail {
             allocated_raw_subaccount,
@@ -455,6 +804,7 @@ impl AgentState {
             created_at: ic_cdk::api::time(),
             created_by,
             txns: (None, None),
+            replaced_reveals: None,
             runeid: None,
             name: name.clone(),
             ticker,
@@ -472,14 +822,26 @@ impl AgentState {
             dex_fee_bps: 3000,
             max_bps: 10000,
 
-            secret: Some(secret),
-            past_winners: HashSet::new(),
+            past_winners: None,
+            current_prize_pool: None,
+            secret: None,
+            game_mode: None,
+            commitment: None,
+            revealed_secrets: None,
             current_winner: None,
-            current_prize_pool: (0, 500_000_000),
+            guess_count: None,
 
-            balances: HashSet::new(),
             rune: 1000_000_000,
             bitcoin: 0,
+            prize_runes: None,
+
+            pool: None,
+            graduated_at: None,
+
+            llm: None,
+            persona: None,
+            autopilot: None,
+            address_type: Some(address_type),
         };
         let url = agent.logo_url();
         let addr = agent.get_bitcoin_address();
@@ -495,24 +857,127 @@ impl AgentState {
         }
     }
 
-    pub fn get_agents(&self) -> HashMap<u128, crate::AgentDetails> {
-        let mut len = self._count - 1;
-        let mut map = HashMap::new();
-        loop {
-            let agent_query = match self.mapping.get(&len) {
-                None => break,
-                Some(agent) => agent.agent_query(),
-            };
-            map.insert(len, agent_query);
-            len -= 1;
-            if map.len() >= 50 {
-                break;
-            }
+    /// agents matching the filters, sorted in descending order and paginated by offset
+    pub fn get_agents(
+        &self,
+        crate::GetAgentsArgs {
+            cursor,
+            limit,
+            sort_by,
+            created_by,
+            lifecycle,
+        }: crate::GetAgentsArgs,
+    ) -> crate::AgentsPage {
+        let offset = cursor.unwrap_or(0) as usize;
+        let limit = limit.clamp(1, 50) as usize;
+        let day_ago = ic_cdk::api::time().saturating_sub(24 * 60 * 60 * 1_000_000_000);
+        let mut agents = self
+            .mapping
+            .iter()
+            .filter(|(_, agent)| created_by.is_none_or(|creator| agent.created_by == creator))
+            .filter(|(_, agent)| lifecycle.is_none_or(|lifecycle| agent.lifecycle() == lifecycle))
+            .map(|(id, agent)| {
+                let sort_key = match sort_by {
+                    crate::AgentSort::CreatedAt => agent.created_at as u128,
+                    crate::AgentSort::MarketCap => agent.market_cap(),
+                    crate::AgentSort::Holders => {
+                        read_holders(|holders| holders.holder_count(id)) as u128
+                    }
+                    crate::AgentSort::Volume24h => {
+                        read_trades(|trades| trades.volume_since(id, day_ago)) as u128
+                    }
+                };
+                (sort_key, id)
+            })
+            .collect::<Vec<_>>();
+        // ties are broken by the newest agent first
+        agents.sort_unstable_by(|a, b| b.cmp(a));
+        let next_cursor = if agents.len() > offset + limit {
+            Some((offset + limit) as u64)
+        } else {
+            None
+        };
+        let agents = agents
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(_, id)| self.get_agent_of(id).map(|agent| (id, agent)))
+            .collect();
+        crate::AgentsPage {
+            agents,
+            next_cursor,
         }
-        map
     }
 
     pub fn get_agent_of(&self, id: u128) -> Option<crate::AgentDetails> {
         self.mapping.get(&id).map(|detail| detail.agent_query())
     }
 }
+
+/// (from the entry fees, from the free runes) paying a rune prize, errs if the prize doesn't fit
+fn split_rune_prize(
+    prize: u128,
+    prize_runes: u128,
+    free_runes: u128,
+) -> Result<(u128, u128), String> {
+    let from_fees = prize.min(prize_runes);
+    let from_free = prize - from_fees;
+    if from_free > free_runes {
+        return Err(format!(
+            "not enough runes for the prize. required: {from_free}, free: {free_runes}"
+        ));
+    }
+    Ok((from_fees, from_free))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LiquidityPool, split_rune_prize};
+
+    fn pool() -> LiquidityPool {
+        LiquidityPool {
+            bitcoin_reserve: 100_000_000,
+            rune_reserve: 250_000_000,
+            lp_fee_bps: 30,
+        }
+    }
+
+    #[test]
+    fn lp_fee_grows_the_invariant() {
+        let mut pool = pool();
+        let k = pool.bitcoin_reserve * pool.rune_reserve;
+        let buy = pool.quote_buy_exact_in(1_000_000).unwrap();
+        assert!(buy.amount_out < 2_500_000);
+        assert_eq!(buy.lp_fee, 3_000);
+        pool.bitcoin_reserve = buy.collateral_reserve;
+        pool.rune_reserve = buy.token_reserve;
+        let sell = pool.quote_sell_exact_in(buy.amount_out).unwrap();
+        assert!(sell.amount_out < 1_000_000);
+        assert!(sell.collateral_reserve * sell.token_reserve > k);
+    }
+
+    #[test]
+    fn exact_out_matches_exact_in() {
+        let pool = pool();
+        let buy = pool.quote_buy_exact_out(1_000_000).unwrap();
+        assert!(pool.quote_buy_exact_in(buy.amount_in).unwrap().amount_out >= 1_000_000);
+        let sell = pool.quote_sell_exact_out(1_000_000).unwrap();
+        assert!(pool.quote_sell_exact_in(sell.amount_in).unwrap().amount_out >= 1_000_000);
+        assert!(pool.quote_buy_exact_out(pool.rune_reserve).is_err());
+    }
+
+    #[test]
+    fn price_impact_is_measured_from_spot() {
+        let quote = pool().quote_buy_exact_in(1_000_000).unwrap();
+        assert!(quote.spot_price > 400.0);
+        assert!(quote.price_impact_bps > 0 && quote.price_impact_bps < 300);
+    }
+
+    #[test]
+    fn rune_prizes_come_out_of_entry_fees_first() {
+        assert_eq!(split_rune_prize(100, 60, 1_000), Ok((60, 40)));
+        assert_eq!(split_rune_prize(100, 500, 0), Ok((100, 0)));
+        // runes backing the pool are never paid out
+        assert!(split_rune_prize(100, 60, 39).is_err());
+    }
+}
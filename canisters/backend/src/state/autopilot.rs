@@ -0,0 +1,206 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+use std::ops::Bound as RangeBound;
+
+use super::{
+    CanisterMemory, CanisterMemoryIds,
+    keys::{u64_at, u128_at},
+    read_memory_manager,
+};
+use crate::autopilot::AgentAction;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogKey {
+    pub agent_id: u128,
+    pub index: u64,
+}
+
+// agent id then index, so that the entries of an agent are next to each other, oldest first
+impl Storable for LogKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            index: u64_at(&bytes, 16),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 24,
+        is_fixed_size: true,
+    };
+}
+
+/// Announcement an agent posted on its feed
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FeedPost {
+    pub timestamp: u64,
+    pub message: String,
+}
+
+impl Storable for FeedPost {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Audit entry of an action the agent picked on its own
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ActionRecord {
+    pub timestamp: u64,
+    pub action: AgentAction,
+    pub reasoning: String,
+    pub result: Result<String, String>,
+}
+
+impl Storable for ActionRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+type Log<V> = StableBTreeMap<LogKey, V, CanisterMemory>;
+
+pub struct AutopilotState {
+    pub feed: Log<FeedPost>,
+    pub actions: Log<ActionRecord>,
+    pub last_run: StableBTreeMap<u128, u64, CanisterMemory>, // agent id -> time of its last run
+}
+
+impl Default for AutopilotState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            feed: Log::init(manager.get(CanisterMemoryIds::Feed.into())),
+            actions: Log::init(manager.get(CanisterMemoryIds::AgentActions.into())),
+            last_run: StableBTreeMap::init(manager.get(CanisterMemoryIds::AutopilotRuns.into())),
+        })
+    }
+}
+
+fn append<V: Storable>(log: &mut Log<V>, agent_id: u128, value: V) -> u64 {
+    let index = log
+        .range(
+            ..=LogKey {
+                agent_id,
+                index: u64::MAX,
+            },
+        )
+        .next_back()
+        .filter(|(key, _)| key.agent_id == agent_id)
+        .map_or(0, |(key, _)| key.index + 1);
+    log.insert(LogKey { agent_id, index }, value);
+    index
+}
+
+/// entries of the agent, newest first, starting right before `before`
+fn page<V: Storable>(
+    log: &Log<V>,
+    agent_id: u128,
+    before: Option<u64>,
+    limit: usize,
+) -> Vec<(u64, V)> {
+    let start = LogKey { agent_id, index: 0 };
+    let end = match before {
+        Some(index) => RangeBound::Excluded(LogKey { agent_id, index }),
+        None => RangeBound::Included(LogKey {
+            agent_id,
+            index: u64::MAX,
+        }),
+    };
+    log.range((RangeBound::Included(start), end))
+        .rev()
+        .take(limit)
+        .map(|(key, value)| (key.index, value))
+        .collect()
+}
+
+impl AutopilotState {
+    pub fn post(&mut self, agent_id: u128, post: FeedPost) -> u64 {
+        append(&mut self.feed, agent_id, post)
+    }
+
+    pub fn record_action(&mut self, agent_id: u128, record: ActionRecord) -> u64 {
+        append(&mut self.actions, agent_id, record)
+    }
+
+    pub fn feed_of(
+        &self,
+        agent_id: u128,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<(u64, FeedPost)> {
+        page(&self.feed, agent_id, before, limit)
+    }
+
+    pub fn actions_of(
+        &self,
+        agent_id: u128,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<(u64, ActionRecord)> {
+        page(&self.actions, agent_id, before, limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ic_stable_structures::{StableBTreeMap, Storable, VectorMemory};
+
+    use super::LogKey;
+
+    #[test]
+    fn newest_entry_of_an_agent_comes_last() {
+        let mut log: StableBTreeMap<LogKey, (), VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        for agent_id in [1, 256] {
+            for index in 0..300 {
+                let key = LogKey { agent_id, index };
+                assert!(LogKey::from_bytes(key.to_bytes()) == key);
+                log.insert(key, ());
+            }
+        }
+        let last = log
+            .range(
+                ..=LogKey {
+                    agent_id: 1,
+                    index: u64::MAX,
+                },
+            )
+            .next_back()
+            .map(|(key, _)| (key.agent_id, key.index));
+        assert_eq!(last, Some((1, 299)));
+        let page = log
+            .range(
+                LogKey {
+                    agent_id: 256,
+                    index: 0,
+                }..LogKey {
+                    agent_id: 256,
+                    index: 258,
+                },
+            )
+            .rev()
+            .take(3)
+            .map(|(key, _)| key.index)
+            .collect::<Vec<_>>();
+        assert_eq!(page, vec![257, 256, 255]);
+    }
+}
@@ -0,0 +1,119 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+use icrc_ledger_types::icrc1::account::Account;
+
+use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+use crate::bitcoin::AddressType;
+
+/// An input of a broadcasted transaction along with what's needed for signing it again
+#[derive(CandidType, Deserialize, Clone)]
+pub enum TrackedInput {
+    Wallet {
+        account: Account,
+        address_type: AddressType,
+        address: String,
+        value: u64,
+    },
+    // commit output spent by a reveal, the reveal script is kept in the input's witness
+    Reveal {
+        address: String,
+        value: u64,
+    },
+}
+
+impl TrackedInput {
+    pub fn value(&self) -> u64 {
+        match self {
+            Self::Wallet { value, .. } | Self::Reveal { value, .. } => *value,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        match self {
+            Self::Wallet { address, .. } | Self::Reveal { address, .. } => address,
+        }
+    }
+}
+
+/// Output spent by every version of a transaction, the canister only sees mined transactions
+/// so one of the versions confirmed once the output is gone from the utxos of its address.
+/// Unlike the outputs of the transaction, it can't be spent by anything else in the meantime.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WatchedInput {
+    pub address: String,
+    pub txid: String,
+    pub vout: u32,
+    pub seen_unspent: bool, // an output that isn't mined yet is missing from the utxos as well
+}
+
+/// What a broadcasted transaction is for, replacements update the txid kept by the origin
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum BroadcastKind {
+    Transfer,
+    Reveal { agent_id: u128 },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Broadcast {
+    pub kind: BroadcastKind,
+    pub txn: Vec<u8>, // signed and consensus encoded
+    pub txid: String,
+    pub inputs: Vec<TrackedInput>,
+    pub fee: u64,
+    pub change: Option<u32>, // output the fee of a bump is taken from
+    pub fee_payer: (Account, Vec<AddressType>), // adds inputs if the change can't pay for a bump
+    pub watch: WatchedInput, // first input, replacements only add inputs after it
+    pub replaced: Vec<String>, // txids of the earlier versions, oldest first
+    pub attempts: u32,       // bumps tried, failed ones included
+    pub attempted_at: u64,   // time of the broadcast or of the last bump tried
+}
+
+impl Broadcast {
+    /// txids of every version that was broadcasted, any of them can confirm
+    pub fn txids(&self) -> impl Iterator<Item = &String> {
+        self.replaced.iter().chain(std::iter::once(&self.txid))
+    }
+}
+
+impl Storable for Broadcast {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub struct BroadcastState {
+    pub tracked: StableBTreeMap<u128, Broadcast, CanisterMemory>,
+}
+
+impl Default for BroadcastState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            tracked: StableBTreeMap::init(manager.get(CanisterMemoryIds::Broadcasts.into())),
+        })
+    }
+}
+
+impl BroadcastState {
+    pub fn track(&mut self, broadcast: Broadcast) -> u128 {
+        let id = self.tracked.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.tracked.insert(id, broadcast);
+        id
+    }
+
+    pub fn unconfirmed(&self) -> Vec<(u128, Broadcast)> {
+        self.tracked.iter().collect()
+    }
+
+    pub fn update_watch(&mut self, id: u128, watch: &WatchedInput) {
+        if let Some(mut broadcast) = self.tracked.get(&id) {
+            broadcast.watch = watch.clone();
+            self.tracked.insert(id, broadcast);
+        }
+    }
+}
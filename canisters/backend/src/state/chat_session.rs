@@ -1,16 +1,27 @@
-use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+use super::{
+    CanisterMemory, CanisterMemoryIds,
+    counters::Counter,
+    keys::{PRINCIPAL_SIZE, principal_at, put_principal, u32_at, u64_at, u128_at},
+    next_id, read_memory_manager,
+};
 use crate::llm::{ChatMessage, Role};
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
 use std::collections::HashMap;
 
+pub const SESSION_TTL: u64 = 30 * 60 * 1_000_000_000; // idle sessions are evicted after 30 mins
+const MAX_EVICTIONS: usize = 500; // sessions evicted per sweep, the rest wait for the next one
+
 #[derive(CandidType, Deserialize)]
 pub struct Session {
     pub session_id: u128,
     pub agent_id: u128,
-    pub last_interacted: u64, // chat's will be deleted after 30 mins of no action
+    pub last_interacted: u64, // chat's will be deleted after `SESSION_TTL` of no action
     pub user: Principal,
-    pub past: HashMap<u32, (bool, String)>, // true if the message is by agent
+    pub past: Option<HashMap<u32, (bool, String)>>, // moved into the message log by the version 5 migration
+    pub messages: Option<u32>,                      // number of messages in the log
+    pub summary: Option<String>, // rolling summary of the messages that dropped out of the window
+    pub summarised: Option<u32>, // messages before this index are covered by the summary
 }
 
 impl Storable for Session {
@@ -25,6 +36,129 @@ impl Storable for Session {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserSessionKey {
+    pub user: Principal,
+    pub session_id: u128,
+}
+
+// user then session id, so that the sessions of a user are next to each other
+impl Storable for UserSessionKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(PRINCIPAL_SIZE + 16);
+        put_principal(&mut bytes, &self.user);
+        bytes.extend_from_slice(&self.session_id.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            user: principal_at(&bytes, 0),
+            session_id: u128_at(&bytes, PRINCIPAL_SIZE),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: PRINCIPAL_SIZE as u32 + 16,
+        is_fixed_size: true,
+    };
+}
+
+/// Sessions ordered by their last interaction, the idle ones come first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ActivityKey {
+    pub last_interacted: u64,
+    pub session_id: u128,
+}
+
+impl Storable for ActivityKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.extend_from_slice(&self.last_interacted.to_be_bytes());
+        bytes.extend_from_slice(&self.session_id.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            last_interacted: u64_at(&bytes, 0),
+            session_id: u128_at(&bytes, 8),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 24,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageKey {
+    pub session_id: u128,
+    pub index: u32,
+}
+
+impl Storable for MessageKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(20);
+        bytes.extend_from_slice(&self.session_id.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            session_id: u128_at(&bytes, 0),
+            index: u32_at(&bytes, 16),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 20,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StoredMessage {
+    pub by_agent: bool,
+    pub content: String,
+}
+
+impl Storable for StoredMessage {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl From<StoredMessage> for ChatMessage {
+    fn from(message: StoredMessage) -> Self {
+        ChatMessage {
+            role: if message.by_agent {
+                Role::Assistant
+            } else {
+                Role::User
+            },
+            content: message.content,
+        }
+    }
+}
+
+#[derive(CandidType, Clone)]
+pub struct SessionSummary {
+    pub session_id: u128,
+    pub agent_id: u128,
+    pub last_interacted: u64,
+    pub messages: u32,
+    pub expires_at: u64,
+}
+
 impl Session {
     pub fn new(id: u128, agent_id: u128, user: Principal) -> Self {
         Self {
@@ -32,65 +166,343 @@ impl Session {
             agent_id,
             user,
             last_interacted: ic_cdk::api::time(),
-            past: HashMap::new(),
+            past: None,
+            messages: Some(0),
+            summary: None,
+            summarised: None,
         }
     }
 
-    pub fn to_contents(&self) -> Vec<ChatMessage> {
-        let count = self.past.len();
-        let mut messages = Vec::with_capacity(count);
+    pub fn message_count(&self) -> u32 {
+        self.messages.unwrap_or(0)
+    }
 
-        for i in 0..count {
-            let message = self.past.get(&(i as u32)).expect("should exist");
-            messages.push(ChatMessage {
-                content: message.1.clone(),
-                role: if message.0 {
-                    Role::Assistant
-                } else {
-                    Role::User
-                },
-            });
+    pub fn summarised(&self) -> u32 {
+        self.summarised.unwrap_or(0)
+    }
+
+    fn activity_key(&self) -> ActivityKey {
+        ActivityKey {
+            last_interacted: self.last_interacted,
+            session_id: self.session_id,
         }
-        messages
     }
 
-    pub fn record_content(&mut self, contents: Vec<ChatMessage>) {
-        for content in contents {
-            let id = self.past.len() as u32;
-            let by_agent = match content.role {
-                Role::Assistant => true,
-                _ => false,
-            };
-            self.past.insert(id, (by_agent, content.content));
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_interacted) >= SESSION_TTL
+    }
+
+    pub fn to_summary(&self) -> SessionSummary {
+        SessionSummary {
+            session_id: self.session_id,
+            agent_id: self.agent_id,
+            last_interacted: self.last_interacted,
+            messages: self.message_count(),
+            expires_at: self.last_interacted + SESSION_TTL,
         }
     }
 }
 
 pub struct ChatSession {
-    pub count: u128,
     pub session: StableBTreeMap<u128, Session, CanisterMemory>,
+    pub by_user: StableBTreeMap<UserSessionKey, (), CanisterMemory>,
+    pub by_activity: StableBTreeMap<ActivityKey, (), CanisterMemory>,
+    pub log: StableBTreeMap<MessageKey, StoredMessage, CanisterMemory>, // append only history
 }
 
 impl Default for ChatSession {
     fn default() -> Self {
         read_memory_manager(|manager| Self {
-            count: 0,
             session: StableBTreeMap::init(manager.get(CanisterMemoryIds::ChatSession.into())),
+            by_user: StableBTreeMap::init(manager.get(CanisterMemoryIds::UserSessions.into())),
+            by_activity: StableBTreeMap::init(
+                manager.get(CanisterMemoryIds::SessionActivity.into()),
+            ),
+            log: StableBTreeMap::init(manager.get(CanisterMemoryIds::ChatMessages.into())),
         })
     }
 }
 
 impl ChatSession {
-    fn get_session_id(&mut self) -> u128 {
-        let id = self.count;
-        self.count += 1;
-        id
-    }
-
     pub fn start_new_session(&mut self, agent: u128, user: Principal) -> u128 {
-        let id = self.get_session_id();
+        let id = next_id(Counter::ChatSession);
         let session = Session::new(id, agent, user);
+        self.by_activity.insert(session.activity_key(), ());
         self.session.insert(id, session);
+        self.by_user.insert(
+            UserSessionKey {
+                user,
+                session_id: id,
+            },
+            (),
+        );
         id
     }
+
+    /// the session, if it's still alive and the user owns it
+    pub fn session_of(
+        &self,
+        session_id: u128,
+        user: Principal,
+        now: u64,
+    ) -> Result<Session, String> {
+        match self.session.get(&session_id) {
+            Some(session) if session.user == user => {
+                if session.is_expired(now) {
+                    Err(String::from("session expired"))
+                } else {
+                    Ok(session)
+                }
+            }
+            _ => Err(String::from("session doesn't exist")),
+        }
+    }
+
+    /// checks that the user can chat with the agent in the session and keeps the session alive
+    pub fn touch(
+        &mut self,
+        session_id: u128,
+        user: Principal,
+        agent_id: u128,
+        now: u64,
+    ) -> Result<(), String> {
+        let mut session = self.session_of(session_id, user, now)?;
+        if session.agent_id != agent_id {
+            return Err(String::from("session belongs to another agent"));
+        }
+        self.set_last_interacted(&mut session, now);
+        self.session.insert(session_id, session);
+        Ok(())
+    }
+
+    /// moves the session to its new place in the activity index, storing it is up to the caller
+    pub fn set_last_interacted(&mut self, session: &mut Session, now: u64) {
+        self.by_activity.remove(&session.activity_key());
+        session.last_interacted = now;
+        self.by_activity.insert(session.activity_key(), ());
+    }
+
+    pub fn sessions_of(&self, user: Principal, now: u64) -> Vec<SessionSummary> {
+        let start = UserSessionKey {
+            user,
+            session_id: 0,
+        };
+        self.by_user
+            .range(start..)
+            .take_while(|(key, _)| key.user == user)
+            .filter_map(|(key, _)| self.session.get(&key.session_id))
+            .filter(|session| !session.is_expired(now))
+            .map(|session| session.to_summary())
+            .collect()
+    }
+
+    pub fn append(&mut self, session_id: u128, contents: Vec<ChatMessage>) {
+        let Some(mut session) = self.session.get(&session_id) else {
+            return;
+        };
+        let mut index = session.message_count();
+        for content in contents {
+            let by_agent = matches!(content.role, Role::Assistant);
+            self.log.insert(
+                MessageKey { session_id, index },
+                StoredMessage {
+                    by_agent,
+                    content: content.content,
+                },
+            );
+            index += 1;
+        }
+        session.messages.replace(index);
+        self.session.insert(session_id, session);
+    }
+
+    /// messages of the session in `[from, to)`
+    pub fn messages(&self, session_id: u128, from: u32, to: u32) -> Vec<ChatMessage> {
+        let start = MessageKey {
+            session_id,
+            index: from,
+        };
+        let end = MessageKey {
+            session_id,
+            index: to,
+        };
+        self.log
+            .range(start..end)
+            .map(|(_, message)| message.into())
+            .collect()
+    }
+
+    pub fn history(&self, session_id: u128) -> Vec<ChatMessage> {
+        self.messages(session_id, 0, u32::MAX)
+    }
+
+    /// moves the history kept on the session itself into the log
+    pub fn move_history_into_log(&mut self) {
+        let ids = self
+            .session
+            .iter()
+            .filter(|(_, session)| session.past.is_some())
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for session_id in ids {
+            let mut session = self.session.get(&session_id).unwrap();
+            let past = session.past.take().unwrap();
+            let count = past.len() as u32;
+            for (index, (by_agent, content)) in past {
+                self.log.insert(
+                    MessageKey { session_id, index },
+                    StoredMessage { by_agent, content },
+                );
+            }
+            session.messages.replace(count);
+            self.session.insert(session_id, session);
+        }
+    }
+
+    /// rebuilds the indexes of sessions per user and by their last interaction
+    pub fn index_sessions(&mut self) {
+        let keys = self
+            .session
+            .iter()
+            .map(|(session_id, session)| {
+                let user = UserSessionKey {
+                    user: session.user,
+                    session_id,
+                };
+                (user, session.activity_key())
+            })
+            .collect::<Vec<_>>();
+        for (user, activity) in keys {
+            self.by_user.insert(user, ());
+            self.by_activity.insert(activity, ());
+        }
+    }
+
+    /// removes sessions that have been idle for longer than `SESSION_TTL`
+    /// returns the number of evicted sessions
+    pub fn evict_idle(&mut self, now: u64) -> usize {
+        let Some(cutoff) = now.checked_sub(SESSION_TTL) else {
+            return 0;
+        };
+        let end = ActivityKey {
+            last_interacted: cutoff,
+            session_id: u128::MAX,
+        };
+        let idle = self
+            .by_activity
+            .range(..=end)
+            .take(MAX_EVICTIONS)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for activity in idle.iter() {
+            self.by_activity.remove(activity);
+            let session_id = activity.session_id;
+            let Some(session) = self.session.remove(&session_id) else {
+                continue;
+            };
+            let indexes = self
+                .log
+                .range(
+                    MessageKey {
+                        session_id,
+                        index: 0,
+                    }..,
+                )
+                .take_while(|(key, _)| key.session_id == session_id)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+            for key in indexes {
+                self.log.remove(&key);
+            }
+            self.by_user.remove(&UserSessionKey {
+                user: session.user,
+                session_id,
+            });
+        }
+        idle.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use candid::Principal;
+    use ic_stable_structures::{StableBTreeMap, Storable, VectorMemory};
+
+    use super::{ActivityKey, MessageKey, UserSessionKey};
+
+    #[test]
+    fn sessions_of_a_user_are_next_to_each_other() {
+        let mut by_user: StableBTreeMap<UserSessionKey, (), VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        let users = [Principal::anonymous(), Principal::from_slice(&[9; 29])];
+        for session_id in [1, 255, 256, 70_000] {
+            for user in users {
+                let key = UserSessionKey { user, session_id };
+                assert!(UserSessionKey::from_bytes(key.to_bytes()) == key);
+                by_user.insert(key, ());
+            }
+        }
+        for user in users {
+            let start = UserSessionKey {
+                user,
+                session_id: 0,
+            };
+            let sessions = by_user
+                .range(start..)
+                .take_while(|(key, _)| key.user == user)
+                .map(|(key, _)| key.session_id)
+                .collect::<Vec<_>>();
+            assert_eq!(sessions, vec![1, 255, 256, 70_000]);
+        }
+    }
+
+    #[test]
+    fn idle_sessions_come_first() {
+        let mut by_activity: StableBTreeMap<ActivityKey, (), VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        for (last_interacted, session_id) in [(70_000, 1), (256, 2), (1, 3), (255, 256)] {
+            by_activity.insert(
+                ActivityKey {
+                    last_interacted,
+                    session_id,
+                },
+                (),
+            );
+        }
+        let end = ActivityKey {
+            last_interacted: 256,
+            session_id: u128::MAX,
+        };
+        let idle = by_activity
+            .range(..=end)
+            .map(|(key, _)| key.session_id)
+            .collect::<Vec<_>>();
+        assert_eq!(idle, vec![3, 256, 2]);
+    }
+
+    #[test]
+    fn messages_come_in_the_order_they_were_sent() {
+        let mut log: StableBTreeMap<MessageKey, (), VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        for session_id in [1, 256] {
+            for index in 0..300 {
+                let key = MessageKey { session_id, index };
+                assert!(MessageKey::from_bytes(key.to_bytes()) == key);
+                log.insert(key, ());
+            }
+        }
+        let start = MessageKey {
+            session_id: 256,
+            index: 250,
+        };
+        let end = MessageKey {
+            session_id: 256,
+            index: 260,
+        };
+        let window = log
+            .range(start..end)
+            .map(|(key, _)| key.index)
+            .collect::<Vec<_>>();
+        assert_eq!(window, (250..260).collect::<Vec<_>>());
+    }
 }
@@ -1,3 +1,4 @@
+use crate::bitcoin::fee::FeeTier;
 use crate::{EcdsaPublicKey, SchnorrPublicKey};
 use candid::{CandidType, Decode, Encode, Principal};
 use ic_cdk::api::management_canister::{
@@ -10,6 +11,209 @@ use serde::Deserialize;
 
 use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
 
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GuessConfig {
+    pub bitcoin_price: u64,   // price of the first guess of a round in satoshis
+    pub rune_price: u128,     // price of the first guess of a round in runes
+    pub price_step_bps: u16,  // price increase per guess already made in the round
+    pub prize_share_bps: u16, // share of the price going to the prize pool, rest is commission
+    pub cooldown_secs: u64,   // minimum time between two guesses of a principal
+    pub initial_rune_prize: u128, // runes in the prize pool of a new agent
+}
+
+impl Default for GuessConfig {
+    fn default() -> Self {
+        Self {
+            bitcoin_price: 1_000,
+            rune_price: 1_000,
+            price_step_bps: 500,
+            prize_share_bps: 8_000,
+            cooldown_secs: 10,
+            initial_rune_prize: 500_000_000,
+        }
+    }
+}
+
+/// LLM canister and model the agents chat through
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LlmConfig {
+    pub canister: Principal, // anything serving `v0_chat`, e.g. a stub canister for local tests
+    pub model: String,       // model name understood by the canister
+    pub temperature: Option<f32>, // left to the canister if none
+    pub max_tokens: Option<u32>, // longest reply, replies are cut off after it
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            canister: Principal::from_text("w36hm-eqaaa-aaaal-qr76a-cai").unwrap(),
+            model: ic_llm::Model::Llama3_1_8B.to_string(),
+            temperature: None,
+            max_tokens: None,
+        }
+    }
+}
+
+/// Per agent overrides of the `LlmConfig`, unset fields fall back to the canister's config
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct LlmOverrides {
+    pub canister: Option<Principal>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl LlmConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.model.trim().is_empty() {
+            return Err(String::from("model name is empty"));
+        }
+        if self.max_tokens == Some(0) {
+            return Err(String::from("max tokens should be above zero"));
+        }
+        if self
+            .temperature
+            .is_some_and(|temperature| !temperature.is_finite() || temperature < 0.0)
+        {
+            return Err(String::from("temperature should be a positive number"));
+        }
+        Ok(())
+    }
+
+    pub fn with_overrides(mut self, overrides: &LlmOverrides) -> Self {
+        if let Some(canister) = overrides.canister {
+            self.canister = canister;
+        }
+        if let Some(ref model) = overrides.model {
+            self.model = model.clone();
+        }
+        if overrides.temperature.is_some() {
+            self.temperature = overrides.temperature;
+        }
+        if overrides.max_tokens.is_some() {
+            self.max_tokens = overrides.max_tokens;
+        }
+        self
+    }
+}
+
+/// Loop that lets every agent pick an action on its own, see `autopilot`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AutopilotConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,      // time between two runs of an agent
+    pub agents_per_tick: u32,    // agents run per tick of the timer, to bound the llm calls
+    pub min_round_age_secs: u64, // a lucky draw round can only be replaced once it's this old
+}
+
+impl Default for AutopilotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 6 * 60 * 60,
+            agents_per_tick: 5,
+            min_round_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl AutopilotConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 {
+            return Err(String::from("interval should be above zero"));
+        }
+        if self.agents_per_tick == 0 {
+            return Err(String::from("agents per tick should be above zero"));
+        }
+        Ok(())
+    }
+}
+
+/// How the fee rate of the canister's transactions is picked from the fee percentiles
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FeePolicy {
+    pub economy_percentile: u8,
+    pub normal_percentile: u8,
+    pub fast_percentile: u8,
+    pub min_msat_per_vbyte: u64, // floor applied to every rate, explicit ones included
+    pub max_msat_per_vbyte: u64, // ceiling applied to every rate, explicit ones included
+    pub cache_ttl_secs: u64,     // fee percentiles are refetched once they're this old
+    pub settlement_tier: FeeTier,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self {
+            economy_percentile: 25,
+            normal_percentile: 50,
+            fast_percentile: 75,
+            min_msat_per_vbyte: 1_000,
+            max_msat_per_vbyte: 500_000,
+            cache_ttl_secs: 5 * 60,
+            settlement_tier: FeeTier::Economy,
+        }
+    }
+}
+
+impl FeePolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_msat_per_vbyte > self.max_msat_per_vbyte {
+            return Err(String::from("minimum fee rate is above the maximum"));
+        }
+        let percentiles = [
+            self.economy_percentile,
+            self.normal_percentile,
+            self.fast_percentile,
+        ];
+        if percentiles.iter().any(|percentile| *percentile > 100) {
+            return Err(String::from("percentiles should be at most 100"));
+        }
+        Ok(())
+    }
+
+    // unlike `u64::clamp` this doesn't panic on a bad policy, the ceiling wins
+    pub fn clamp(&self, msat_per_vbyte: u64) -> u64 {
+        msat_per_vbyte
+            .max(self.min_msat_per_vbyte)
+            .min(self.max_msat_per_vbyte)
+    }
+}
+
+/// Replace by fee for the canister's transactions that don't confirm, see `broadcast`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RbfConfig {
+    pub enabled: bool,
+    pub bump_after_secs: u64, // a transaction is bumped once it's unconfirmed for this long
+    pub max_bumps: u32,       // the transaction is no longer tracked after this many bumps
+    pub tier: FeeTier,        // fee rate a bump aims for, at least the minimum increment is added
+}
+
+impl Default for RbfConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bump_after_secs: 2 * 60 * 60,
+            max_bumps: 3,
+            tier: FeeTier::Fast,
+        }
+    }
+}
+
+impl RbfConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        // without a delay every check would replace the transaction again
+        if self.bump_after_secs == 0 {
+            return Err(String::from("bump delay should be above zero"));
+        }
+        if self.enabled && self.max_bumps == 0 {
+            return Err(String::from(
+                "max bumps should be above zero, disable rbf instead",
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Config {
     pub bitcoin_network: BitcoinNetwork,
@@ -21,6 +225,14 @@ pub struct Config {
     pub schnorr_public_key: Option<SchnorrPublicKey>,
     pub keyname: String,
     pub allowed_agent_count: u128,
+    pub graduation_market_cap: Option<u64>, // defaults to 4 BTC
+    pub lp_fee_bps: Option<u16>,            // defaults to 0.3%
+    pub version: Option<u32>,               // version of the stored state, see `migration`
+    pub guess: Option<GuessConfig>,
+    pub llm: Option<LlmConfig>,
+    pub autopilot: Option<AutopilotConfig>,
+    pub fee_policy: Option<FeePolicy>,
+    pub rbf: Option<RbfConfig>,
 }
 
 impl Default for Config {
@@ -35,6 +247,14 @@ impl Default for Config {
             schnorr_public_key: None,
             keyname: String::from("dfx_test_key"),
             allowed_agent_count: 100,
+            graduation_market_cap: None,
+            lp_fee_bps: None,
+            version: None,
+            guess: None,
+            llm: None,
+            autopilot: None,
+            fee_policy: None,
+            rbf: None,
         }
     }
 }
@@ -77,6 +297,38 @@ impl Config {
         self.commission_receiver
     }
 
+    pub fn version(&self) -> u32 {
+        self.version.unwrap_or(0)
+    }
+
+    pub fn guess_config(&self) -> GuessConfig {
+        self.guess.clone().unwrap_or_default()
+    }
+
+    pub fn llm_config(&self) -> LlmConfig {
+        self.llm.clone().unwrap_or_default()
+    }
+
+    pub fn autopilot_config(&self) -> AutopilotConfig {
+        self.autopilot.clone().unwrap_or_default()
+    }
+
+    pub fn fee_policy(&self) -> FeePolicy {
+        self.fee_policy.clone().unwrap_or_default()
+    }
+
+    pub fn rbf_config(&self) -> RbfConfig {
+        self.rbf.clone().unwrap_or_default()
+    }
+
+    pub fn graduation_market_cap(&self) -> u64 {
+        self.graduation_market_cap.unwrap_or(400_000_000)
+    }
+
+    pub fn lp_fee_bps(&self) -> u16 {
+        self.lp_fee_bps.unwrap_or(30)
+    }
+
     pub fn keyname(&self) -> String {
         self.keyname.clone()
     }
@@ -111,4 +363,82 @@ impl Config {
             _ => 60 * 60,
         }
     }
+
+    pub fn get_timer_for_settlement(&self) -> u64 {
+        match self.bitcoin_network() {
+            BitcoinNetwork::Regtest => 2 * 60,
+            _ => 60 * 60,
+        }
+    }
+
+    pub fn get_timer_for_broadcasts(&self) -> u64 {
+        match self.bitcoin_network() {
+            BitcoinNetwork::Regtest => 2 * 60,
+            _ => 10 * 60,
+        }
+    }
+
+    pub fn settlement_confirmations(&self) -> u32 {
+        match self.bitcoin_network() {
+            BitcoinNetwork::Regtest => 1,
+            _ => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_autopilot_and_llm_configs_are_rejected() {
+        assert!(AutopilotConfig::default().validate().is_ok());
+        let no_interval = AutopilotConfig {
+            interval_secs: 0,
+            ..Default::default()
+        };
+        assert!(no_interval.validate().is_err());
+        let no_agents = AutopilotConfig {
+            agents_per_tick: 0,
+            ..Default::default()
+        };
+        assert!(no_agents.validate().is_err());
+
+        assert!(LlmConfig::default().validate().is_ok());
+        let no_model = LlmOverrides {
+            model: Some(String::from(" ")),
+            ..Default::default()
+        };
+        assert!(
+            LlmConfig::default()
+                .with_overrides(&no_model)
+                .validate()
+                .is_err()
+        );
+        let no_tokens = LlmOverrides {
+            max_tokens: Some(0),
+            ..Default::default()
+        };
+        assert!(
+            LlmConfig::default()
+                .with_overrides(&no_tokens)
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn bad_rbf_configs_are_rejected() {
+        assert!(RbfConfig::default().validate().is_ok());
+        let no_delay = RbfConfig {
+            bump_after_secs: 0,
+            ..Default::default()
+        };
+        assert!(no_delay.validate().is_err());
+        let no_bumps = RbfConfig {
+            max_bumps: 0,
+            ..Default::default()
+        };
+        assert!(no_bumps.validate().is_err());
+    }
 }
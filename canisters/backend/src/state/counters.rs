@@ -0,0 +1,69 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{StableCell, Storable, storable::Bound};
+
+use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+
+/// Id counters that have to survive upgrades
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Counters {
+    pub agents: u128,
+    pub chat_sessions: u128,
+    pub scheduled_txns: u128,
+    pub utxo_operations: Option<u128>,
+}
+
+impl Storable for Counters {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type StableCounters = StableCell<Counters, CanisterMemory>;
+
+pub fn init_counters() -> StableCounters {
+    read_memory_manager(|manager| {
+        let memory = manager.get(CanisterMemoryIds::Counters.into());
+        StableCounters::init(memory, Counters::default()).expect("should initialize counters")
+    })
+}
+
+pub enum Counter {
+    Agent,
+    ChatSession,
+    ScheduledTxn,
+    UtxoOperation,
+}
+
+impl Counters {
+    pub fn get(&self, counter: Counter) -> u128 {
+        match counter {
+            Counter::Agent => self.agents,
+            Counter::ChatSession => self.chat_sessions,
+            Counter::ScheduledTxn => self.scheduled_txns,
+            Counter::UtxoOperation => self.utxo_operations.unwrap_or_default(),
+        }
+    }
+
+    fn get_mut(&mut self, counter: Counter) -> &mut u128 {
+        match counter {
+            Counter::Agent => &mut self.agents,
+            Counter::ChatSession => &mut self.chat_sessions,
+            Counter::ScheduledTxn => &mut self.scheduled_txns,
+            Counter::UtxoOperation => self.utxo_operations.get_or_insert(0),
+        }
+    }
+
+    /// returns the current value and increments the counter
+    pub fn next(&mut self, counter: Counter) -> u128 {
+        let value = self.get_mut(counter);
+        let id = *value;
+        *value += 1;
+        id
+    }
+}
@@ -0,0 +1,107 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+
+use super::GuessConfig;
+use super::keys::u128_at;
+use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+use crate::games::{Game, GameInstance, GameKind, GameView};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GameKey {
+    pub agent_id: u128,
+    pub kind: GameKind,
+}
+
+// agent id then the kind, in the declaration order of `GameKind`
+impl Storable for GameKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(17);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        bytes.push(self.kind as u8);
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            kind: GameKind::ALL[bytes[16] as usize],
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 17,
+        is_fixed_size: true,
+    };
+}
+
+impl Storable for GameInstance {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Games run by each agent
+pub struct GameState {
+    pub instances: StableBTreeMap<GameKey, GameInstance, CanisterMemory>,
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            instances: StableBTreeMap::init(manager.get(CanisterMemoryIds::Games.into())),
+        })
+    }
+}
+
+impl GameState {
+    pub fn get(&self, agent_id: u128, kind: GameKind) -> Option<GameInstance> {
+        self.instances.get(&GameKey { agent_id, kind })
+    }
+
+    pub fn insert(&mut self, agent_id: u128, instance: GameInstance) {
+        let kind = instance.game().kind();
+        self.instances.insert(GameKey { agent_id, kind }, instance);
+    }
+
+    pub fn games_of(&self, agent_id: u128) -> Vec<GameInstance> {
+        GameKind::ALL
+            .into_iter()
+            .filter_map(|kind| self.get(agent_id, kind))
+            .collect()
+    }
+
+    /// views of the agent's games, as they'd be after a tick at `now`
+    pub fn views_of(&self, agent_id: u128, config: &GuessConfig, now: u64) -> Vec<GameView> {
+        self.games_of(agent_id)
+            .into_iter()
+            .map(|mut instance| {
+                instance.game_mut().on_tick(now);
+                instance.view(config)
+            })
+            .collect()
+    }
+
+    /// runs `f` on the game and stores the result, none if the agent doesn't run the game
+    pub fn update<F, R>(&mut self, agent_id: u128, kind: GameKind, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut dyn Game) -> R,
+    {
+        let mut instance = self.get(agent_id, kind)?;
+        let result = f(instance.game_mut());
+        self.insert(agent_id, instance);
+        Some(result)
+    }
+
+    /// forwards a buy of the agent's runes to every game of the agent
+    pub fn on_buy(&mut self, agent_id: u128, buyer: Principal, bitcoin: u64, now: u64) {
+        for kind in GameKind::ALL {
+            self.update(agent_id, kind, |game| game.on_buy(buyer, bitcoin, now));
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+
+use super::{
+    CanisterMemory, CanisterMemoryIds,
+    keys::{PRINCIPAL_SIZE, principal_at, put_principal, u128_at},
+    read_memory_manager,
+};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HolderKey {
+    pub agent_id: u128,
+    pub holder: Principal,
+}
+
+impl Storable for HolderKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16 + PRINCIPAL_SIZE);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        put_principal(&mut bytes, &self.holder);
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            holder: principal_at(&bytes, 16),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16 + PRINCIPAL_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RankKey {
+    pub agent_id: u128,
+    pub rank: u128, // u128::MAX - balance, so that larger balances come first
+    pub holder: Principal,
+}
+
+impl Storable for RankKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(32 + PRINCIPAL_SIZE);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        bytes.extend_from_slice(&self.rank.to_be_bytes());
+        put_principal(&mut bytes, &self.holder);
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            rank: u128_at(&bytes, 16),
+            holder: principal_at(&bytes, 32),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32 + PRINCIPAL_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+pub type HolderBalances = StableBTreeMap<HolderKey, u128, CanisterMemory>;
+pub type HolderRanking = StableBTreeMap<RankKey, (), CanisterMemory>;
+pub type HolderCount = StableBTreeMap<u128, u32, CanisterMemory>;
+
+/// Index of the rune balances held on the ledger, per agent
+pub struct HolderState {
+    pub balances: HolderBalances,
+    pub ranking: HolderRanking,
+    pub count: HolderCount,
+}
+
+impl Default for HolderState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            balances: HolderBalances::init(manager.get(CanisterMemoryIds::Holders.into())),
+            ranking: HolderRanking::init(manager.get(CanisterMemoryIds::HolderRanking.into())),
+            count: HolderCount::init(manager.get(CanisterMemoryIds::HolderCount.into())),
+        })
+    }
+}
+
+impl HolderState {
+    /// records the new balance of the holder, zero balances are dropped from the index
+    pub fn update_balance(&mut self, agent_id: u128, holder: Principal, balance: u128) {
+        let key = HolderKey { agent_id, holder };
+        let count = self.count.get(&agent_id).unwrap_or(0);
+        match self.balances.get(&key) {
+            Some(prev) => {
+                self.ranking.remove(&RankKey {
+                    agent_id,
+                    rank: u128::MAX - prev,
+                    holder,
+                });
+                if balance == 0 {
+                    self.balances.remove(&key);
+                    self.count.insert(agent_id, count - 1);
+                    return;
+                }
+            }
+            None if balance == 0 => return,
+            None => {
+                self.count.insert(agent_id, count + 1);
+            }
+        }
+        self.balances.insert(key, balance);
+        self.ranking.insert(
+            RankKey {
+                agent_id,
+                rank: u128::MAX - balance,
+                holder,
+            },
+            (),
+        );
+    }
+
+    pub fn holder_count(&self, agent_id: u128) -> u32 {
+        self.count.get(&agent_id).unwrap_or(0)
+    }
+
+    /// holders sorted by balance, largest first
+    pub fn get_holders(
+        &self,
+        agent_id: u128,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<(Principal, u128)> {
+        let start = RankKey {
+            agent_id,
+            rank: 0,
+            holder: Principal::management_canister(),
+        };
+        self.ranking
+            .range(start..)
+            .take_while(|(key, _)| key.agent_id == agent_id)
+            .skip(offset)
+            .take(limit)
+            .map(|(key, _)| (key.holder, u128::MAX - key.rank))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use candid::Principal;
+    use ic_stable_structures::{StableBTreeMap, Storable, VectorMemory};
+
+    use super::RankKey;
+
+    #[test]
+    fn larger_balances_come_first() {
+        let mut ranking: StableBTreeMap<RankKey, (), VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        let holders = [
+            Principal::anonymous(),
+            Principal::management_canister(),
+            Principal::from_slice(&[9; 29]),
+        ];
+        let mut expected = vec![];
+        for agent_id in [1, 256] {
+            for balance in [70_000, 256, 255, 1] {
+                for holder in holders {
+                    let key = RankKey {
+                        agent_id,
+                        rank: u128::MAX - balance,
+                        holder,
+                    };
+                    assert!(RankKey::from_bytes(key.to_bytes()) == key);
+                    ranking.insert(key, ());
+                    expected.push((agent_id, balance));
+                }
+            }
+        }
+        let iterated = ranking
+            .iter()
+            .map(|(key, _)| (key.agent_id, u128::MAX - key.rank))
+            .collect::<Vec<_>>();
+        assert_eq!(iterated, expected);
+    }
+}
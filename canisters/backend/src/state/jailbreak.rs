@@ -0,0 +1,70 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+
+use super::{
+    CanisterMemory, CanisterMemoryIds,
+    keys::{u32_at, u128_at},
+    read_memory_manager,
+};
+use crate::llm::ChatMessage;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProofKey {
+    pub agent_id: u128,
+    pub round: u32, // index of the round's secret in the agent's revealed secrets
+}
+
+impl Storable for ProofKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(20);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        bytes.extend_from_slice(&self.round.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            round: u32_at(&bytes, 16),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 20,
+        is_fixed_size: true,
+    };
+}
+
+/// Transcript of the chat session that made the agent reveal its secret
+#[derive(CandidType, Deserialize, Clone)]
+pub struct JailbreakProof {
+    pub session_id: u128,
+    pub winner: Principal,
+    pub commitment: String,
+    pub transcript: Vec<ChatMessage>,
+    pub timestamp: u64,
+}
+
+impl Storable for JailbreakProof {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub struct JailbreakState {
+    pub proofs: StableBTreeMap<ProofKey, JailbreakProof, CanisterMemory>,
+}
+
+impl Default for JailbreakState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            proofs: StableBTreeMap::init(manager.get(CanisterMemoryIds::JailbreakProofs.into())),
+        })
+    }
+}
@@ -0,0 +1,31 @@
+use candid::Principal;
+
+// encodings shared by the composite keys of the stable maps. The maps order keys by their
+// bytes, so fields are fixed width, big-endian and written in the order they're compared in
+
+// length prefixed and zero padded, which keeps the order of `Principal` itself
+pub const PRINCIPAL_SIZE: usize = 30;
+
+pub fn put_principal(bytes: &mut Vec<u8>, principal: &Principal) {
+    let slice = principal.as_slice();
+    bytes.push(slice.len() as u8);
+    bytes.extend_from_slice(slice);
+    bytes.extend(std::iter::repeat_n(0, PRINCIPAL_SIZE - 1 - slice.len()));
+}
+
+pub fn principal_at(bytes: &[u8], offset: usize) -> Principal {
+    let len = bytes[offset] as usize;
+    Principal::from_slice(&bytes[offset + 1..offset + 1 + len])
+}
+
+pub fn u128_at(bytes: &[u8], offset: usize) -> u128 {
+    u128::from_be_bytes(bytes[offset..offset + 16].try_into().unwrap())
+}
+
+pub fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
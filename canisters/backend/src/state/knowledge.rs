@@ -0,0 +1,146 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+
+use super::{
+    CanisterMemory, CanisterMemoryIds,
+    keys::{u32_at, u128_at},
+    read_memory_manager,
+};
+use crate::knowledge::{KnowledgeDoc, MAX_DOCS};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DocKey {
+    pub agent_id: u128,
+    pub doc_id: u32,
+}
+
+// agent id then doc id, so that the documents of an agent are next to each other
+impl Storable for DocKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(20);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        bytes.extend_from_slice(&self.doc_id.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            doc_id: u32_at(&bytes, 16),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 20,
+        is_fixed_size: true,
+    };
+}
+
+impl Storable for KnowledgeDoc {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Knowledge documents uploaded by the creators of the agents
+pub struct KnowledgeState {
+    pub docs: StableBTreeMap<DocKey, KnowledgeDoc, CanisterMemory>,
+    pub next_doc_id: StableBTreeMap<u128, u32, CanisterMemory>, // ids of removed documents aren't reused
+}
+
+impl Default for KnowledgeState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            docs: StableBTreeMap::init(manager.get(CanisterMemoryIds::Knowledge.into())),
+            next_doc_id: StableBTreeMap::init(
+                manager.get(CanisterMemoryIds::KnowledgeDocIds.into()),
+            ),
+        })
+    }
+}
+
+impl KnowledgeState {
+    pub fn docs_of(&self, agent_id: u128) -> Vec<(u32, KnowledgeDoc)> {
+        self.docs
+            .range(
+                DocKey {
+                    agent_id,
+                    doc_id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.agent_id == agent_id)
+            .map(|(key, doc)| (key.doc_id, doc))
+            .collect()
+    }
+
+    /// replaces the document if `doc_id` is given, returns the id of the document
+    pub fn upsert(
+        &mut self,
+        agent_id: u128,
+        doc_id: Option<u32>,
+        doc: KnowledgeDoc,
+    ) -> Result<u32, String> {
+        let docs = self.docs_of(agent_id);
+        let doc_id = match doc_id {
+            Some(doc_id) if docs.iter().any(|(id, _)| *id == doc_id) => doc_id,
+            Some(_) => return Err(String::from("document doesn't exist")),
+            None if docs.len() >= MAX_DOCS => {
+                return Err(format!("an agent can have up to {MAX_DOCS} documents"));
+            }
+            None => {
+                // agents that had documents before the counter start after the last one
+                let doc_id = self
+                    .next_doc_id
+                    .get(&agent_id)
+                    .unwrap_or_else(|| docs.last().map_or(0, |(id, _)| id + 1));
+                self.next_doc_id.insert(agent_id, doc_id + 1);
+                doc_id
+            }
+        };
+        self.docs.insert(DocKey { agent_id, doc_id }, doc);
+        Ok(doc_id)
+    }
+
+    pub fn remove(&mut self, agent_id: u128, doc_id: u32) -> Result<(), String> {
+        self.docs
+            .remove(&DocKey { agent_id, doc_id })
+            .map(|_| ())
+            .ok_or_else(|| String::from("document doesn't exist"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ic_stable_structures::{StableBTreeMap, Storable, VectorMemory};
+
+    use super::DocKey;
+
+    #[test]
+    fn docs_of_an_agent_are_next_to_each_other() {
+        let mut docs: StableBTreeMap<DocKey, (), VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        for agent_id in [1, 256] {
+            for doc_id in [0, 255, 256, 70_000] {
+                let key = DocKey { agent_id, doc_id };
+                assert!(DocKey::from_bytes(key.to_bytes()) == key);
+                docs.insert(key, ());
+            }
+        }
+        let start = DocKey {
+            agent_id: 256,
+            doc_id: 0,
+        };
+        let of_agent = docs
+            .range(start..)
+            .take_while(|(key, _)| key.agent_id == 256)
+            .map(|(key, _)| key.doc_id)
+            .collect::<Vec<_>>();
+        assert_eq!(of_agent, vec![0, 255, 256, 70_000]);
+    }
+}
@@ -1,7 +1,8 @@
 use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
 use std::collections::HashMap;
 
-use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager, write_holders};
+use crate::bitcoin::AddressType;
 use crate::indexer::RuneId;
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 
@@ -32,6 +33,7 @@ impl BalanceEntries {
 pub struct BalanceEntries {
     pub restricted_bitcoin_balance: u64,
     pub ledger_entries: HashMap<u128, (u64, u128)>, // mapping of agent_id to (bitcoin owned by ageint, rune balance of user)
+    pub address_types: Option<Vec<AddressType>>, // every type the user picked, the preferred one first
 }
 
 impl Storable for BalanceEntries {
@@ -47,9 +49,26 @@ impl Storable for BalanceEntries {
 }
 
 impl BalanceEntries {
-    pub fn record_rune_balance(&mut self, agent: u128, rune: u128) {
+    /// users that never picked a type keep the legacy address
+    pub fn address_types(&self) -> Vec<AddressType> {
+        self.address_types
+            .clone()
+            .unwrap_or_else(|| vec![AddressType::P2pkh])
+    }
+
+    /// older types are kept, so that bitcoin left on their addresses can still be spent
+    pub fn prefer_address_type(&mut self, address_type: AddressType) {
+        let mut address_types = self.address_types();
+        address_types.retain(|other| *other != address_type);
+        address_types.insert(0, address_type);
+        self.address_types.replace(address_types);
+    }
+
+    pub fn record_rune_balance(&mut self, holder: Principal, agent: u128, rune: u128) {
         let entry = self.ledger_entries.entry(agent).or_default();
         entry.1 += rune;
+        let balance = entry.1;
+        write_holders(|holders| holders.update_balance(agent, holder, balance));
     }
 
     pub fn record_agent_owned_balance(&mut self, agent: u128, bitcoin: u64) {
@@ -58,9 +77,11 @@ impl BalanceEntries {
         entry.0 += bitcoin;
     }
 
-    pub fn deduct_rune_balance(&mut self, agent: u128, rune: u128) {
+    pub fn deduct_rune_balance(&mut self, holder: Principal, agent: u128, rune: u128) {
         let entry = self.ledger_entries.get_mut(&agent).expect("should exist");
         entry.1 -= rune;
+        let balance = entry.1;
+        write_holders(|holders| holders.update_balance(agent, holder, balance));
     }
 
     pub fn deduct_agent_owned_balance(&mut self, agent: u128, bitcoin: u64) {
@@ -3,7 +3,7 @@ use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
 use serde::{Deserialize, Serialize};
 use slotmap::KeyData;
 
-use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+use super::{CanisterMemory, CanisterMemoryIds, counters::Counter, next_id, read_memory_manager};
 
 #[derive(Serialize, Deserialize)]
 pub struct ScheduledTransaction {
@@ -11,6 +11,13 @@ pub struct ScheduledTransaction {
     pub commit_tx_address: String,
     pub txn: Transaction,
     pub timer_id: KeyData,
+    // commit is kept for accelerating it while it's unconfirmed, see `accelerate_etching`
+    #[serde(default)]
+    pub commit: Option<Transaction>,
+    #[serde(default)]
+    pub commit_fee: Option<u64>,
+    #[serde(default)]
+    pub cpfp_fee: Option<u64>, // fee of the last child paying for the commit
 }
 
 impl Storable for ScheduledTransaction {
@@ -38,7 +45,6 @@ fn init_mapping() -> ScheduledTransactionMap {
 
 #[derive(Serialize, Deserialize)]
 pub struct ScheduledState {
-    txn_count: u128,
     #[serde(skip, default = "init_mapping")]
     mapping: ScheduledTransactionMap,
 }
@@ -46,7 +52,6 @@ pub struct ScheduledState {
 impl Default for ScheduledState {
     fn default() -> Self {
         Self {
-            txn_count: 0,
             mapping: init_mapping(),
         }
     }
@@ -54,10 +59,38 @@ impl Default for ScheduledState {
 
 impl ScheduledState {
     pub fn get_id(&mut self) -> u128 {
-        let id = self.txn_count;
-        self.txn_count += 1;
-        id
+        next_id(Counter::ScheduledTxn)
     }
+
+    pub fn last_id(&self) -> Option<u128> {
+        self.mapping.last_key_value().map(|(id, _)| id)
+    }
+
+    pub fn get_txn(&self, id: u128) -> Option<ScheduledTransaction> {
+        self.mapping.get(&id)
+    }
+
+    /// (id, agent_id) of the reveal transactions waiting for submission
+    pub fn pending_txns(&self) -> Vec<(u128, u128)> {
+        self.mapping
+            .iter()
+            .map(|(id, txn)| (id, txn.agent_id))
+            .collect()
+    }
+
+    pub fn update_timer(&mut self, id: u128, timer_id: KeyData) {
+        if let Some(mut txn) = self.mapping.get(&id) {
+            txn.timer_id = timer_id;
+            self.mapping.insert(id, txn);
+        }
+    }
+    pub fn record_cpfp_fee(&mut self, id: u128, fee: u64) {
+        if let Some(mut txn) = self.mapping.get(&id) {
+            txn.cpfp_fee.replace(fee);
+            self.mapping.insert(id, txn);
+        }
+    }
+
     pub fn record_txn(&mut self, id: u128, txn: ScheduledTransaction) {
         self.mapping.insert(id, txn);
     }
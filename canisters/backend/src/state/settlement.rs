@@ -0,0 +1,255 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    CanisterMemory, CanisterMemoryIds,
+    broadcasts::WatchedInput,
+    keys::{PRINCIPAL_SIZE, principal_at, put_principal, u128_at},
+    read_memory_manager,
+    trades::TradeKey,
+};
+use crate::games::GameKind;
+
+/// What a single trader gets out of an agent's settlement
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SettlementLeg {
+    pub trader: Principal,
+    pub bitcoin_in: u64, // agent owned satoshis claimed from the trader's deposit address
+    pub bitcoin_out: u64, // satoshis owed to the trader by the agent
+    pub rune_out: u128,  // runes delivered on-chain
+    pub trades: Vec<TradeKey>,
+    pub game_fees: Vec<(GameKind, u64)>, // credited to the prize pools once collected
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Settlement {
+    pub agent_id: u128,
+    pub legs: Vec<SettlementLeg>,
+    pub watch: WatchedInput,
+    pub txids: Vec<String>, // every version that was broadcasted, oldest first
+    pub spent_at: Option<u32>, // tip height the watched input was first seen spent at
+    pub submitted_at: u64,
+}
+
+impl Settlement {
+    /// confirmations of the settlement at `tip`, at least the ones since it was seen spent
+    pub fn confirmations(&self, tip: u32) -> u32 {
+        self.spent_at
+            .map_or(0, |spent_at| tip.saturating_sub(spent_at) + 1)
+    }
+
+    pub fn trades(&self) -> Vec<TradeKey> {
+        self.legs
+            .iter()
+            .flat_map(|leg| leg.trades.iter().copied())
+            .collect()
+    }
+}
+
+impl Storable for Settlement {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeeKey {
+    pub agent_id: u128,
+    pub trader: Principal,
+}
+
+impl Storable for FeeKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16 + PRINCIPAL_SIZE);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        put_principal(&mut bytes, &self.trader);
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128_at(&bytes, 0),
+            trader: principal_at(&bytes, 16),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16 + PRINCIPAL_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+/// Shares of game entry fees still held by the trader's deposit address, per game
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct GameFees(pub Vec<(GameKind, u64)>);
+
+impl Storable for GameFees {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type PendingTrades = StableBTreeMap<TradeKey, Principal, CanisterMemory>;
+pub type SettlementMapping = StableBTreeMap<u128, Settlement, CanisterMemory>;
+pub type PendingGameFees = StableBTreeMap<FeeKey, GameFees, CanisterMemory>;
+
+pub struct SettlementState {
+    pub pending: PendingTrades,
+    pub in_flight: SettlementMapping,
+    pub game_fees: PendingGameFees,
+}
+
+impl Default for SettlementState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            pending: PendingTrades::init(manager.get(CanisterMemoryIds::PendingTrades.into())),
+            in_flight: SettlementMapping::init(manager.get(CanisterMemoryIds::Settlement.into())),
+            game_fees: PendingGameFees::init(manager.get(CanisterMemoryIds::GameFees.into())),
+        })
+    }
+}
+
+impl SettlementState {
+    pub fn queue_trade(&mut self, key: TradeKey, trader: Principal) {
+        self.pending.insert(key, trader);
+    }
+
+    /// agents with trades or game fees waiting for settlement
+    pub fn pending_agents(&self) -> BTreeSet<u128> {
+        self.pending
+            .iter()
+            .map(|(key, _)| key.agent_id)
+            .chain(self.game_fees.iter().map(|(key, _)| key.agent_id))
+            .collect()
+    }
+
+    /// the share of an entry fee going to the prize pool, credited once the fee is collected
+    pub fn queue_game_fee(&mut self, agent_id: u128, trader: Principal, kind: GameKind, fee: u64) {
+        let key = FeeKey { agent_id, trader };
+        let mut fees = self.game_fees.get(&key).unwrap_or_default();
+        match fees.0.iter_mut().find(|(other, _)| *other == kind) {
+            Some((_, pending)) => *pending += fee,
+            None => fees.0.push((kind, fee)),
+        }
+        self.game_fees.insert(key, fees);
+    }
+
+    pub fn game_fees_of(&self, agent_id: u128, trader: Principal) -> Vec<(GameKind, u64)> {
+        self.game_fees
+            .get(&FeeKey { agent_id, trader })
+            .unwrap_or_default()
+            .0
+    }
+
+    /// removes the fees collected by a settlement, fees queued in the meantime stay
+    pub fn clear_game_fees(
+        &mut self,
+        agent_id: u128,
+        trader: Principal,
+        collected: &[(GameKind, u64)],
+    ) {
+        let key = FeeKey { agent_id, trader };
+        let Some(mut fees) = self.game_fees.get(&key) else {
+            return;
+        };
+        for (kind, pending) in fees.0.iter_mut() {
+            if let Some((_, amount)) = collected.iter().find(|(other, _)| other == kind) {
+                *pending = pending.saturating_sub(*amount);
+            }
+        }
+        fees.0.retain(|(_, pending)| *pending > 0);
+        if fees.0.is_empty() {
+            self.game_fees.remove(&key);
+        } else {
+            self.game_fees.insert(key, fees);
+        }
+    }
+
+    /// trades of an agent waiting for settlement, grouped by trader
+    /// traders with only game fees to collect come with no trades
+    pub fn pending_for(&self, agent_id: u128) -> BTreeMap<Principal, Vec<TradeKey>> {
+        let start = TradeKey {
+            agent_id,
+            timestamp: 0,
+            index: 0,
+        };
+        let end = TradeKey {
+            agent_id,
+            timestamp: u64::MAX,
+            index: u32::MAX,
+        };
+        let mut groups: BTreeMap<Principal, Vec<TradeKey>> = BTreeMap::new();
+        for (key, trader) in self.pending.range(start..=end) {
+            groups.entry(trader).or_default().push(key);
+        }
+        // traders only owing game fees are collected as well
+        let first = FeeKey {
+            agent_id,
+            trader: Principal::management_canister(),
+        };
+        for (key, _) in self
+            .game_fees
+            .range(first..)
+            .take_while(|(key, _)| key.agent_id == agent_id)
+        {
+            groups.entry(key.trader).or_default();
+        }
+        groups
+    }
+
+    pub fn clear_trades(&mut self, keys: &[TradeKey]) {
+        for key in keys {
+            self.pending.remove(key);
+        }
+    }
+
+    pub fn record_settlement(&mut self, settlement: Settlement) -> u128 {
+        let id = self.in_flight.last_key_value().map_or(0, |(id, _)| id + 1);
+        self.in_flight.insert(id, settlement);
+        id
+    }
+
+    pub fn update_watch(&mut self, id: u128, watch: &WatchedInput) {
+        if let Some(mut settlement) = self.in_flight.get(&id) {
+            settlement.watch = watch.clone();
+            self.in_flight.insert(id, settlement);
+        }
+    }
+
+    /// adds the replacement of `txid` to the versions of its settlement, returns its trades
+    pub fn replace_txid(&mut self, txid: &str, replacement: &str) -> Vec<TradeKey> {
+        let found = self
+            .in_flight
+            .iter()
+            .find(|(_, settlement)| settlement.txids.iter().any(|other| other == txid));
+        let Some((id, mut settlement)) = found else {
+            return vec![];
+        };
+        settlement.txids.push(replacement.to_string());
+        let trades = settlement.trades();
+        self.in_flight.insert(id, settlement);
+        trades
+    }
+
+    pub fn in_flight_bitcoin(&self, agent_id: u128, trader: &Principal) -> u64 {
+        self.in_flight
+            .iter()
+            .filter(|(_, settlement)| settlement.agent_id == agent_id)
+            .flat_map(|(_, settlement)| settlement.legs)
+            .filter(|leg| leg.trader == *trader)
+            .fold(0, |total, leg| total + leg.bitcoin_in)
+    }
+}
@@ -0,0 +1,300 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
+use std::ops::Bound as RangeBound;
+
+use super::{CanisterMemory, CanisterMemoryIds, agent::spot_price, read_memory_manager};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TradeKey {
+    pub agent_id: u128,
+    pub timestamp: u64,
+    pub index: u32, // trades of the same agent within the same round share the timestamp
+}
+
+// big-endian fields in the order they're compared in, so that ranges follow time
+impl Storable for TradeKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(28);
+        bytes.extend_from_slice(&self.agent_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            agent_id: u128::from_be_bytes(bytes[0..16].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(bytes[16..24].try_into().unwrap()),
+            index: u32::from_be_bytes(bytes[24..28].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 28,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TradeSide {
+    Buy,
+    Sell,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Trade {
+    pub trader: Principal,
+    pub side: TradeSide,
+    pub bitcoin: u64,               // satoshis paid for a buy, received for a sell
+    pub rune: u128,                 // runes received for a buy, paid for a sell
+    pub owed_bitcoin: u64,          // part of a sell that has to be paid from the agent's address
+    pub deliver_to: Option<String>, // runes of a buy are sent on-chain to this address
+    pub settlement_txid: Option<String>,
+    pub treasury_fee: Option<u64>,
+    pub dex_fee: Option<u64>,
+    pub lp_fee: Option<u128>,           // in the input asset
+    pub reserves: Option<(u128, u128)>, // (collateral, token) reserves after the trade
+}
+
+impl Storable for Trade {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Trade {
+    pub fn needs_settlement(&self) -> bool {
+        match self.side {
+            TradeSide::Buy => true,
+            TradeSide::Sell => self.owed_bitcoin > 0,
+        }
+    }
+
+    /// satoshis per whole token after the trade
+    pub fn price(&self) -> Option<f64> {
+        self.reserves
+            .map(|(collateral, token)| spot_price(collateral, token))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub enum CandleResolution {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl CandleResolution {
+    pub fn nanos(&self) -> u64 {
+        const MINUTE: u64 = 60 * 1_000_000_000;
+        match self {
+            Self::OneMinute => MINUTE,
+            Self::FiveMinutes => 5 * MINUTE,
+            Self::OneHour => 60 * MINUTE,
+            Self::OneDay => 24 * 60 * MINUTE,
+        }
+    }
+}
+
+#[derive(CandidType, Clone)]
+pub struct Candle {
+    pub timestamp: u64, // start of the bucket
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume_bitcoin: u64,
+    pub volume_rune: u128,
+    pub trades: u32,
+}
+
+pub type TradeLog = StableBTreeMap<TradeKey, Trade, CanisterMemory>;
+
+pub struct TradeState {
+    pub log: TradeLog,
+}
+
+impl Default for TradeState {
+    fn default() -> Self {
+        read_memory_manager(|manager| Self {
+            log: TradeLog::init(manager.get(CanisterMemoryIds::Trades.into())),
+        })
+    }
+}
+
+impl TradeState {
+    pub fn record_trade(&mut self, agent_id: u128, trade: Trade) -> TradeKey {
+        let timestamp = ic_cdk::api::time();
+        let start = TradeKey {
+            agent_id,
+            timestamp,
+            index: 0,
+        };
+        let end = TradeKey {
+            agent_id,
+            timestamp,
+            index: u32::MAX,
+        };
+        let index = self.log.range(start..=end).count() as u32;
+        let key = TradeKey {
+            agent_id,
+            timestamp,
+            index,
+        };
+        self.log.insert(key, trade);
+        key
+    }
+
+    pub fn record_settlement_txid(&mut self, key: &TradeKey, txid: &str) {
+        if let Some(mut trade) = self.log.get(key) {
+            trade.settlement_txid.replace(txid.to_string());
+            self.log.insert(*key, trade);
+        }
+    }
+
+    /// trades of an agent, newest first, starting right before `before`
+    pub fn get_trades(
+        &self,
+        agent_id: u128,
+        before: Option<TradeKey>,
+        limit: usize,
+    ) -> Vec<(TradeKey, Trade)> {
+        let start = TradeKey {
+            agent_id,
+            timestamp: 0,
+            index: 0,
+        };
+        let end = match before {
+            Some(before) if before.agent_id == agent_id => RangeBound::Excluded(before),
+            _ => RangeBound::Included(TradeKey {
+                agent_id,
+                timestamp: u64::MAX,
+                index: u32::MAX,
+            }),
+        };
+        self.log
+            .range((RangeBound::Included(start), end))
+            .rev()
+            .take(limit)
+            .collect()
+    }
+
+    /// candles of the price implied by the reserves after each trade, oldest first
+    pub fn get_candles(
+        &self,
+        agent_id: u128,
+        resolution: CandleResolution,
+        from: u64,
+        to: u64,
+    ) -> Vec<Candle> {
+        if from > to {
+            return vec![];
+        }
+        let bucket_size = resolution.nanos();
+        let start = TradeKey {
+            agent_id,
+            timestamp: from - from % bucket_size,
+            index: 0,
+        };
+        let end = TradeKey {
+            agent_id,
+            timestamp: to,
+            index: u32::MAX,
+        };
+        let mut candles: Vec<Candle> = vec![];
+        for (key, trade) in self.log.range(start..=end) {
+            let Some(price) = trade.price() else {
+                continue;
+            };
+            let bucket = key.timestamp - key.timestamp % bucket_size;
+            match candles.last_mut() {
+                Some(candle) if candle.timestamp == bucket => {
+                    candle.high = candle.high.max(price);
+                    candle.low = candle.low.min(price);
+                    candle.close = price;
+                    candle.volume_bitcoin += trade.bitcoin;
+                    candle.volume_rune += trade.rune;
+                    candle.trades += 1;
+                }
+                _ => candles.push(Candle {
+                    timestamp: bucket,
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    volume_bitcoin: trade.bitcoin,
+                    volume_rune: trade.rune,
+                    trades: 1,
+                }),
+            }
+        }
+        candles
+    }
+
+    /// satoshis traded on an agent since `since`
+    pub fn volume_since(&self, agent_id: u128, since: u64) -> u64 {
+        let start = TradeKey {
+            agent_id,
+            timestamp: since,
+            index: 0,
+        };
+        let end = TradeKey {
+            agent_id,
+            timestamp: u64::MAX,
+            index: u32::MAX,
+        };
+        self.log
+            .range(start..=end)
+            .fold(0, |total, (_, trade)| total + trade.bitcoin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ic_stable_structures::{StableBTreeMap, Storable, VectorMemory};
+
+    use super::TradeKey;
+
+    #[test]
+    fn trade_keys_iterate_in_time_order() {
+        let mut log: StableBTreeMap<TradeKey, u64, VectorMemory> =
+            StableBTreeMap::init(VectorMemory::default());
+        let mut keys = vec![];
+        for agent_id in [1, 256] {
+            for timestamp in [1, 255, 256, 65_536, 1 << 40] {
+                for index in [0, 1, 255, 256, 70_000] {
+                    keys.push(TradeKey {
+                        agent_id,
+                        timestamp,
+                        index,
+                    });
+                }
+            }
+        }
+        for key in keys.iter().rev() {
+            assert_eq!(TradeKey::from_bytes(key.to_bytes()), *key);
+            log.insert(*key, key.timestamp);
+        }
+        let iterated = log.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(iterated, keys);
+
+        let start = TradeKey {
+            agent_id: 1,
+            timestamp: 256,
+            index: 0,
+        };
+        let end = TradeKey {
+            agent_id: 1,
+            timestamp: u64::MAX,
+            index: u32::MAX,
+        };
+        assert_eq!(log.range(start..=end).count(), 15);
+    }
+}
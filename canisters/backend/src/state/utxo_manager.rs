@@ -5,9 +5,14 @@ use ic_cdk::api::management_canister::bitcoin::Utxo;
 use ic_stable_structures::{StableBTreeMap, Storable, storable::Bound};
 use serde::{Deserialize, Serialize};
 
-use crate::indexer::RuneId;
+use crate::{bitcoin::utils::slice_to_txid, bitcoin_lib::Transaction, indexer::RuneId};
 
-use super::{CanisterMemory, CanisterMemoryIds, read_memory_manager};
+use super::{CanisterMemory, CanisterMemoryIds, counters::Counter, next_id, read_memory_manager};
+
+// a utxo taken for building a transaction returns to the pool if it isn't broadcasted by then
+const PENDING_LEASE_SECS: u64 = 30 * 60;
+// nodes drop unconfirmed transactions from their mempool after two weeks
+const SPENT_LEASE_SECS: u64 = 14 * 24 * 60 * 60;
 
 #[derive(CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct RunicUtxo {
@@ -75,12 +80,89 @@ pub fn init_bitcoin_mapping() -> BitcoinMapping {
     })
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+pub enum LeasedUtxo {
+    Bitcoin(Utxo),
+    Runic { runeid: RuneId, utxo: RunicUtxo },
+}
+
+impl LeasedUtxo {
+    pub fn utxo(&self) -> &Utxo {
+        match self {
+            Self::Bitcoin(utxo)
+            | Self::Runic {
+                utxo: RunicUtxo { utxo, .. },
+                ..
+            } => utxo,
+        }
+    }
+}
+
+/// An operation building a transaction, the utxos it takes are leased to it
+#[derive(Clone, Copy)]
+pub struct Operation {
+    pub id: u128,
+    pub started_at: u64,
+}
+
+impl Operation {
+    pub fn start() -> Self {
+        Self {
+            id: next_id(Counter::UtxoOperation),
+            started_at: ic_cdk::api::time(),
+        }
+    }
+}
+
+/// A utxo taken out of the pool by an operation building a transaction
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Lease {
+    pub operation: u128,
+    pub address: String,
+    pub utxo: LeasedUtxo,
+    pub expires_at: u64,
+    pub spent_by: Option<String>, // txid of the broadcasted transaction spending it
+}
+
+impl Storable for Lease {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(Encode!(self).expect("should encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("should decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// keyed by the outpoint in the `txid:vout` form
+pub type LeaseMapping = StableBTreeMap<String, Lease, CanisterMemory>;
+
+pub fn init_lease_mapping() -> LeaseMapping {
+    read_memory_manager(|manager| {
+        let memory = manager.get(CanisterMemoryIds::UtxoLeases.into());
+        LeaseMapping::init(memory)
+    })
+}
+
+// same form as the outpoints of transaction inputs are displayed in
+fn outpoint_key(utxo: &Utxo) -> String {
+    format!(
+        "{}:{}",
+        slice_to_txid(&utxo.outpoint.txid),
+        utxo.outpoint.vout
+    )
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UtxoManager {
     #[serde(skip, default = "init_runic_mapping")]
     pub runic: RunicMapping,
     #[serde(skip, default = "init_bitcoin_mapping")]
     pub bitcoin: BitcoinMapping,
+    #[serde(skip, default = "init_lease_mapping")]
+    pub leases: LeaseMapping,
 }
 
 impl Default for UtxoManager {
@@ -88,17 +170,19 @@ impl Default for UtxoManager {
         Self {
             runic: init_runic_mapping(),
             bitcoin: init_bitcoin_mapping(),
+            leases: init_lease_mapping(),
         }
     }
 }
 
 impl UtxoManager {
+    /// leased utxos are skipped, they're only handed back through `release_runic_utxos`
     pub fn record_runic_utxos(&mut self, addr: &str, runeid: RuneId, utxos: Vec<RunicUtxo>) {
         let addr = String::from(addr);
         let mut map = self.runic.get(&addr).unwrap_or_default().0;
         let mut current_utxos = map.remove(&runeid).unwrap_or_default();
         for utxo in utxos {
-            if current_utxos.contains(&utxo) {
+            if current_utxos.contains(&utxo) || self.is_leased(&utxo.utxo) {
                 continue;
             }
             current_utxos.insert(utxo);
@@ -107,11 +191,12 @@ impl UtxoManager {
         self.runic.insert(addr, RunicToUtxoMapping(map));
     }
 
+    /// leased utxos are skipped, they're only handed back through `release_bitcoin_utxos`
     pub fn record_bitcoin_utxos(&mut self, addr: &str, utxos: Vec<Utxo>) {
         let addr = String::from(addr);
         let mut current_utxos = self.bitcoin.get(&addr).unwrap_or_default().0;
         for utxo in utxos {
-            if current_utxos.contains(&utxo) {
+            if current_utxos.contains(&utxo) || self.is_leased(&utxo) {
                 continue;
             }
             current_utxos.insert(utxo);
@@ -119,26 +204,161 @@ impl UtxoManager {
         self.bitcoin.insert(addr, Utxos(current_utxos));
     }
 
-    pub fn get_bitcoin_utxo(&mut self, addr: &str) -> Option<Utxo> {
+    /// hands leased utxos back to the pool, e.g. when their transaction isn't broadcasted
+    pub fn release_runic_utxos(&mut self, addr: &str, runeid: RuneId, utxos: Vec<RunicUtxo>) {
+        for utxo in utxos.iter() {
+            self.leases.remove(&outpoint_key(&utxo.utxo));
+        }
+        self.record_runic_utxos(addr, runeid, utxos);
+    }
+
+    pub fn release_bitcoin_utxos(&mut self, addr: &str, utxos: Vec<Utxo>) {
+        for utxo in utxos.iter() {
+            self.leases.remove(&outpoint_key(utxo));
+        }
+        self.record_bitcoin_utxos(addr, utxos);
+    }
+
+    /// takes the smallest utxo out of the pool, leased to `operation`
+    pub fn get_bitcoin_utxo(&mut self, addr: &str, operation: Operation) -> Option<Utxo> {
         let addr = String::from(addr);
         let mut utxos = self.bitcoin.get(&addr)?.0;
         let min_utxo = utxos.iter().min_by_key(|utxo| utxo.value)?.clone();
         utxos.remove(&min_utxo);
-        self.bitcoin.insert(addr, Utxos(utxos));
+        self.bitcoin.insert(addr.clone(), Utxos(utxos));
+        self.lease(operation, addr, LeasedUtxo::Bitcoin(min_utxo.clone()));
         Some(min_utxo)
     }
 
-    pub fn get_runic_utxo(&mut self, addr: &str, runeid: RuneId) -> Option<RunicUtxo> {
+    /// takes the utxo holding the least runes out of the pool, leased to `operation`
+    pub fn get_runic_utxo(
+        &mut self,
+        addr: &str,
+        runeid: RuneId,
+        operation: Operation,
+    ) -> Option<RunicUtxo> {
         let addr = String::from(addr);
         let mut map = self.runic.get(&addr)?.0;
         let mut utxos = map.remove(&runeid).unwrap_or_default();
         let min_utxo = utxos.iter().min_by_key(|utxo| utxo.balance)?.clone();
         utxos.remove(&min_utxo);
         map.insert(runeid, utxos);
-        self.runic.insert(addr, RunicToUtxoMapping(map));
+        self.runic.insert(addr.clone(), RunicToUtxoMapping(map));
+        self.lease(
+            operation,
+            addr,
+            LeasedUtxo::Runic {
+                runeid,
+                utxo: min_utxo.clone(),
+            },
+        );
         Some(min_utxo)
     }
 
+    fn lease(&mut self, operation: Operation, address: String, utxo: LeasedUtxo) {
+        self.leases.insert(
+            outpoint_key(utxo.utxo()),
+            Lease {
+                operation: operation.id,
+                address,
+                utxo,
+                expires_at: operation.started_at + PENDING_LEASE_SECS * 1_000_000_000,
+                spent_by: None,
+            },
+        );
+    }
+
+    /// leases an output of an unconfirmed transaction, it stays out of the pool once that confirms
+    pub fn lease_unconfirmed(&mut self, addr: &str, utxo: Utxo, operation: Operation) {
+        self.lease(operation, String::from(addr), LeasedUtxo::Bitcoin(utxo));
+    }
+
+    /// drops the lease of a utxo that isn't handed back to the pool, e.g. an unconfirmed output
+    pub fn drop_lease(&mut self, utxo: &Utxo) {
+        self.leases.remove(&outpoint_key(utxo));
+    }
+
+    pub fn is_leased(&self, utxo: &Utxo) -> bool {
+        self.leases.contains_key(&outpoint_key(utxo))
+    }
+
+    /*
+     * hands the utxos an operation leased back to the pool, e.g. when it can't build its transaction
+     * the ones spent by a broadcasted transaction stay leased
+     */
+    pub fn release_operation(&mut self, operation: u128) {
+        let leased = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.operation == operation && lease.spent_by.is_none())
+            .collect::<Vec<_>>();
+        for (key, lease) in leased {
+            self.leases.remove(&key);
+            self.restore(lease);
+        }
+    }
+
+    /// keeps the inputs of a broadcasted transaction leased until it confirms or leaves mempools
+    pub fn mark_spent(&mut self, txn: &Transaction, txid: &str, now: u64) {
+        let expires_at = now + SPENT_LEASE_SECS * 1_000_000_000;
+        for input in txn.input.iter() {
+            let key = input.previous_output.to_string();
+            if let Some(mut lease) = self.leases.get(&key) {
+                lease.spent_by.replace(txid.to_string());
+                lease.expires_at = expires_at;
+                self.leases.insert(key, lease);
+            }
+        }
+    }
+
+    /// drops the leases of utxos spent by any of the transactions, they're gone for good
+    pub fn drop_spent_leases(&mut self, txids: &[String]) {
+        let spent = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| {
+                lease
+                    .spent_by
+                    .as_ref()
+                    .is_some_and(|txid| txids.contains(txid))
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for key in spent {
+            self.leases.remove(&key);
+        }
+    }
+
+    /*
+     * utxos of expired leases that were never broadcasted return to the pool
+     * expired spent ones are only dropped, fetching picks them up again if they're still unspent
+     */
+    pub fn expire_leases(&mut self, now: u64) -> usize {
+        let expired = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires_at <= now)
+            .collect::<Vec<_>>();
+        let count = expired.len();
+        for (key, lease) in expired {
+            self.leases.remove(&key);
+            if lease.spent_by.is_none() {
+                self.restore(lease);
+            }
+        }
+        count
+    }
+
+    // puts the utxo of a dropped lease back into the pool of its address
+    fn restore(&mut self, Lease { address, utxo, .. }: Lease) {
+        match utxo {
+            LeasedUtxo::Bitcoin(utxo) => self.record_bitcoin_utxos(&address, vec![utxo]),
+            LeasedUtxo::Runic { runeid, utxo } => {
+                self.record_runic_utxos(&address, runeid, vec![utxo])
+            }
+        }
+    }
+
     pub fn is_recorded_as_runic(&self, addr: &str, utxo: &Utxo) -> bool {
         let addr = String::from(addr);
         let mut flag = false;
@@ -192,3 +412,122 @@ impl UtxoManager {
         self.bitcoin.insert(addr, Utxos(current_utxos));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Operation, PENDING_LEASE_SECS, UtxoManager, outpoint_key, slice_to_txid};
+    use crate::bitcoin_lib::{
+        OutPoint, ScriptBuf, Sequence, Transaction, TxIn, Txid, Witness, absolute::LockTime,
+        hashes::Hash, transaction::Version,
+    };
+    use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
+
+    const ADDR: &str = "address";
+
+    fn utxo(byte: u8, value: u64) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: vec![byte; 32],
+                vout: 0,
+            },
+            value,
+            height: 1,
+        }
+    }
+
+    fn spending(utxo: &Utxo) -> Transaction {
+        Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: slice_to_txid(&utxo.outpoint.txid),
+                    vout: utxo.outpoint.vout,
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+        }
+    }
+
+    fn operation(id: u128) -> Operation {
+        Operation { id, started_at: 0 }
+    }
+
+    #[test]
+    fn pending_leases_expire_back_into_the_pool() {
+        let mut manager = UtxoManager::default();
+        manager.record_bitcoin_utxos(ADDR, vec![utxo(1, 1_000)]);
+        assert!(manager.get_bitcoin_utxo(ADDR, operation(1)).is_some());
+        assert_eq!(manager.get_bitcoin_balance(ADDR), 0);
+
+        let expiry = PENDING_LEASE_SECS * 1_000_000_000;
+        assert_eq!(manager.expire_leases(expiry - 1), 0);
+        assert_eq!(manager.expire_leases(expiry), 1);
+        assert!(!manager.is_leased(&utxo(1, 1_000)));
+        assert_eq!(manager.get_bitcoin_balance(ADDR), 1_000);
+    }
+
+    #[test]
+    fn leased_utxos_are_not_recorded_again() {
+        let mut manager = UtxoManager::default();
+        manager.record_bitcoin_utxos(ADDR, vec![utxo(1, 1_000)]);
+        manager.get_bitcoin_utxo(ADDR, operation(1));
+        // fetching the address again still lists the utxo
+        manager.record_bitcoin_utxos(ADDR, vec![utxo(1, 1_000), utxo(2, 500)]);
+        assert_eq!(manager.get_bitcoin_balance(ADDR), 500);
+    }
+
+    #[test]
+    fn spent_leases_are_only_dropped_once_confirmed() {
+        let mut manager = UtxoManager::default();
+        manager.record_bitcoin_utxos(ADDR, vec![utxo(1, 1_000)]);
+        let leased = manager.get_bitcoin_utxo(ADDR, operation(1)).unwrap();
+        manager.mark_spent(&spending(&leased), "spender", 0);
+
+        // spent leases outlive pending ones and never return to the pool
+        manager.expire_leases(PENDING_LEASE_SECS * 1_000_000_000);
+        assert!(manager.is_leased(&leased));
+        manager.drop_spent_leases(&[String::from("other")]);
+        assert!(manager.is_leased(&leased));
+        manager.drop_spent_leases(&[String::from("spender")]);
+        assert!(!manager.is_leased(&leased));
+        assert_eq!(manager.get_bitcoin_balance(ADDR), 0);
+    }
+
+    #[test]
+    fn operations_release_only_their_unspent_leases() {
+        let mut manager = UtxoManager::default();
+        manager.record_bitcoin_utxos(ADDR, vec![utxo(1, 1_000), utxo(2, 2_000), utxo(3, 3_000)]);
+        let spent = manager.get_bitcoin_utxo(ADDR, operation(1)).unwrap();
+        manager.mark_spent(&spending(&spent), "spender", 0);
+        manager.get_bitcoin_utxo(ADDR, operation(1));
+        manager.get_bitcoin_utxo(ADDR, operation(2));
+
+        manager.release_operation(1);
+        assert!(manager.is_leased(&spent));
+        assert!(!manager.is_leased(&utxo(2, 2_000)));
+        assert!(manager.is_leased(&utxo(3, 3_000)));
+        assert_eq!(manager.get_bitcoin_balance(ADDR), 2_000);
+    }
+
+    #[test]
+    fn lease_keys_match_input_outpoints() {
+        let txid = [7u8; 32];
+        let utxo = Utxo {
+            outpoint: Outpoint {
+                txid: txid.to_vec(),
+                vout: 3,
+            },
+            value: 10_000,
+            height: 0,
+        };
+        let outpoint = OutPoint {
+            txid: Txid::from_byte_array(txid),
+            vout: 3,
+        };
+        assert_eq!(outpoint_key(&utxo), outpoint.to_string());
+    }
+}
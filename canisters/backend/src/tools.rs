@@ -1,6 +1,10 @@
-use crate::{read_config, read_ledger_entries};
-use candid::Principal;
+use crate::{read_agents, read_config, read_holders, read_ledger_entries};
+use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::management_canister::bitcoin::{GetBalanceRequest, bitcoin_get_balance};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+const MAX_BPS: u128 = 10_000;
 
 pub async fn get_bitcoin_balance(address: String) -> u64 {
     let network = read_config(|config| config.bitcoin_network());
@@ -25,3 +29,356 @@ pub fn get_rune_balance(agent: &u128, user: &Principal) -> u128 {
             .1
     })
 }
+
+const TOOL_CALL_PREFIX: &str = "TOOL_CALL:";
+const ACTION_TTL: u64 = 10 * 60 * 1_000_000_000; // unconfirmed actions expire after 10 minutes
+const DEFAULT_SLIPPAGE_BPS: u128 = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Asset {
+    Bitcoin,
+    Token,
+}
+
+/// A tool the llm asked for, parsed and validated
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum ToolCall {
+    GetBalance,
+    GetPrice,
+    GetHolders {
+        limit: u32,
+    },
+    QuoteBuy {
+        bitcoin: u64,
+    },
+    QuoteSell {
+        tokens: u128,
+    },
+    Buy {
+        bitcoin: u64,
+        min_tokens: u128,
+    },
+    Sell {
+        tokens: u128,
+        min_bitcoin: u64,
+    },
+    Withdraw {
+        to: String,
+        asset: Asset,
+        amount: u128,
+    },
+}
+
+impl ToolCall {
+    /// value moving calls are only executed once the user confirms them
+    pub fn moves_value(&self) -> bool {
+        matches!(
+            self,
+            Self::Buy { .. } | Self::Sell { .. } | Self::Withdraw { .. }
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct RawToolCall {
+    name: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+// llms tend to quote numbers, so both forms are accepted
+fn number(arguments: &serde_json::Value, name: &str) -> Result<Option<u128>, String> {
+    match arguments.get(name) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::Number(number)) => number
+            .as_u64()
+            .map(|number| Some(number as u128))
+            .ok_or_else(|| format!("`{name}` should be a positive integer")),
+        Some(serde_json::Value::String(number)) => number
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("`{name}` should be a positive integer")),
+        Some(_) => Err(format!("`{name}` should be a positive integer")),
+    }
+}
+
+fn amount(arguments: &serde_json::Value, name: &str) -> Result<u128, String> {
+    match number(arguments, name)? {
+        None => Err(format!("`{name}` is missing")),
+        Some(0) => Err(format!("`{name}` should be more than zero")),
+        Some(amount) => Ok(amount),
+    }
+}
+
+fn satoshis(arguments: &serde_json::Value, name: &str) -> Result<u64, String> {
+    u64::try_from(amount(arguments, name)?).map_err(|_| format!("`{name}` is too large"))
+}
+
+/// finds the tool call in a reply of the llm, if there is one
+pub fn parse_tool_call(reply: &str) -> Option<Result<ToolCall, String>> {
+    let json = reply
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(TOOL_CALL_PREFIX))?;
+    let raw: RawToolCall = match serde_json::from_str(json.trim()) {
+        Ok(raw) => raw,
+        Err(err) => return Some(Err(format!("tool call isn't valid json: {err}"))),
+    };
+    let arguments = &raw.arguments;
+    let call = || -> Result<ToolCall, String> {
+        Ok(match raw.name.as_str() {
+            "get_balance" => ToolCall::GetBalance,
+            "get_price" => ToolCall::GetPrice,
+            "get_holders" => ToolCall::GetHolders {
+                limit: number(arguments, "limit")?.unwrap_or(10).min(100) as u32,
+            },
+            "quote_buy" => ToolCall::QuoteBuy {
+                bitcoin: satoshis(arguments, "bitcoin")?,
+            },
+            "quote_sell" => ToolCall::QuoteSell {
+                tokens: amount(arguments, "tokens")?,
+            },
+            "buy" => ToolCall::Buy {
+                bitcoin: satoshis(arguments, "bitcoin")?,
+                min_tokens: number(arguments, "min_tokens")?.unwrap_or(0),
+            },
+            "sell" => ToolCall::Sell {
+                tokens: amount(arguments, "tokens")?,
+                min_bitcoin: number(arguments, "min_bitcoin")?
+                    .unwrap_or(0)
+                    .try_into()
+                    .map_err(|_| String::from("`min_bitcoin` is too large"))?,
+            },
+            "withdraw" => ToolCall::Withdraw {
+                to: arguments
+                    .get("to")
+                    .and_then(serde_json::Value::as_str)
+                    .ok_or_else(|| String::from("`to` should be a bitcoin address"))?
+                    .trim()
+                    .to_string(),
+                asset: match arguments.get("asset").and_then(serde_json::Value::as_str) {
+                    Some("bitcoin") => Asset::Bitcoin,
+                    Some("token") => Asset::Token,
+                    _ => return Err(String::from("`asset` should be `bitcoin` or `token`")),
+                },
+                amount: amount(arguments, "amount")?,
+            },
+            name => return Err(format!("unknown tool `{name}`")),
+        })
+    };
+    Some(call())
+}
+
+/// the reply without the tool call lines, which are meant for the canister only
+pub fn strip_tool_calls(reply: &str) -> String {
+    reply
+        .lines()
+        .filter(|line| !line.trim().starts_with(TOOL_CALL_PREFIX))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// runs a read only tool, the result is handed back to the llm
+pub async fn run_query(user: Principal, agent_id: u128, call: &ToolCall) -> String {
+    let quote_to_text = |quote: Result<crate::TradeQuote, &'static str>| match quote {
+        Ok(quote) => format!(
+            "in: {}, out: {}, fees: {} satoshis, price impact: {} bps, price after: {} satoshis per token",
+            quote.amount_in,
+            quote.amount_out,
+            quote.treasury_fee + quote.dex_fee,
+            quote.price_impact_bps,
+            quote.spot_price
+        ),
+        Err(err) => format!("quote failed: {err}"),
+    };
+    match call {
+        ToolCall::GetBalance => {
+            let bitcoin = crate::spendable_bitcoin_balance(&user).await;
+            format!(
+                "bitcoin: {bitcoin} satoshis, tokens: {} (1000 units per token)",
+                get_rune_balance(&agent_id, &user)
+            )
+        }
+        ToolCall::GetPrice => read_agents(|agents| {
+            let agent = agents.mapping.get(&agent_id).unwrap();
+            format!(
+                "{} satoshis per token, market cap: {} satoshis",
+                agent.price(),
+                agent.market_cap()
+            )
+        }),
+        ToolCall::GetHolders { limit } => {
+            read_holders(|holders| holders.get_holders(agent_id, 0, *limit as usize))
+                .into_iter()
+                .map(|(holder, balance)| format!("{holder}: {balance}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        ToolCall::QuoteBuy { bitcoin } => read_agents(|agents| {
+            let agent = agents.mapping.get(&agent_id).unwrap();
+            quote_to_text(agent.quote_buy_exact_in(*bitcoin as u128))
+        }),
+        ToolCall::QuoteSell { tokens } => read_agents(|agents| {
+            let agent = agents.mapping.get(&agent_id).unwrap();
+            quote_to_text(agent.quote_sell_exact_in(*tokens))
+        }),
+        _ => String::from("this tool needs the user's confirmation"),
+    }
+}
+
+#[derive(CandidType, Clone)]
+pub struct PendingAction {
+    pub id: u64,
+    pub user: Principal,
+    pub agent_id: u128,
+    pub call: ToolCall,
+    pub description: String,
+    pub expires_at: u64,
+}
+
+thread_local! {
+    // heap only, an upgrade dropping unconfirmed actions is harmless
+    static PENDING_ACTIONS: RefCell<BTreeMap<u64, PendingAction>> = RefCell::default();
+    static NEXT_ACTION_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+// a missing slippage limit is filled in from a fresh quote, so that a confirmed trade can't fill
+// at a much worse price than the one the user saw
+fn with_slippage_limit(agent_id: u128, call: ToolCall) -> Result<ToolCall, String> {
+    read_agents(|agents| {
+        let agent = agents.mapping.get(&agent_id).unwrap();
+        Ok(match call {
+            ToolCall::Buy {
+                bitcoin,
+                min_tokens: 0,
+            } => {
+                let quote = agent.quote_buy_exact_in(bitcoin as u128)?;
+                ToolCall::Buy {
+                    bitcoin,
+                    min_tokens: quote.amount_out * (MAX_BPS - DEFAULT_SLIPPAGE_BPS) / MAX_BPS,
+                }
+            }
+            ToolCall::Sell {
+                tokens,
+                min_bitcoin: 0,
+            } => {
+                let quote = agent.quote_sell_exact_in(tokens)?;
+                ToolCall::Sell {
+                    tokens,
+                    min_bitcoin: (quote.amount_out * (MAX_BPS - DEFAULT_SLIPPAGE_BPS) / MAX_BPS)
+                        as u64,
+                }
+            }
+            call => call,
+        })
+    })
+}
+
+fn describe(call: &ToolCall) -> String {
+    match call {
+        ToolCall::Buy {
+            bitcoin,
+            min_tokens,
+        } => format!("buy at least {min_tokens} token units for {bitcoin} satoshis"),
+        ToolCall::Sell {
+            tokens,
+            min_bitcoin,
+        } => format!("sell {tokens} token units for at least {min_bitcoin} satoshis"),
+        ToolCall::Withdraw { to, asset, amount } => match asset {
+            Asset::Bitcoin => format!("withdraw {amount} satoshis to {to}"),
+            Asset::Token => format!("withdraw {amount} token units to {to}"),
+        },
+        call => format!("{call:?}"),
+    }
+}
+
+/// parks a value moving call until the user confirms it, returns the reply for the user
+pub fn queue_for_confirmation(
+    user: Principal,
+    agent_id: u128,
+    call: ToolCall,
+    reply: &str,
+) -> String {
+    let reply = strip_tool_calls(reply);
+    let call = match with_slippage_limit(agent_id, call) {
+        Ok(call) => call,
+        Err(err) => return format!("{reply}\n\nThe action can't be prepared: {err}"),
+    };
+    let now = ic_cdk::api::time();
+    let id = NEXT_ACTION_ID.replace(NEXT_ACTION_ID.get() + 1);
+    let description = describe(&call);
+    PENDING_ACTIONS.with_borrow_mut(|actions| {
+        actions.retain(|_, action| action.expires_at > now);
+        actions.insert(
+            id,
+            PendingAction {
+                id,
+                user,
+                agent_id,
+                call,
+                description: description.clone(),
+                expires_at: now + ACTION_TTL,
+            },
+        );
+    });
+    format!("{reply}\n\nTo {description}, confirm action {id} within 10 minutes.")
+}
+
+/// removes the action so that it can't be confirmed twice
+pub fn take_action(id: u64, user: Principal) -> Result<PendingAction, String> {
+    PENDING_ACTIONS.with_borrow_mut(|actions| {
+        match actions.get(&id) {
+            Some(action) if action.user == user => {}
+            _ => return Err(String::from("no such pending action")),
+        }
+        let action = actions.remove(&id).unwrap();
+        if action.expires_at <= ic_cdk::api::time() {
+            return Err(String::from("action expired"));
+        }
+        Ok(action)
+    })
+}
+
+pub fn pending_actions_of(user: Principal) -> Vec<PendingAction> {
+    let now = ic_cdk::api::time();
+    PENDING_ACTIONS.with_borrow(|actions| {
+        actions
+            .values()
+            .filter(|action| action.user == user && action.expires_at > now)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_calls_are_parsed_and_validated() {
+        let reply = "Sure!\nTOOL_CALL: {\"name\": \"buy\", \"arguments\": {\"bitcoin\": \"5000\"}}";
+        assert_eq!(
+            parse_tool_call(reply),
+            Some(Ok(ToolCall::Buy {
+                bitcoin: 5000,
+                min_tokens: 0
+            }))
+        );
+        assert_eq!(strip_tool_calls(reply), "Sure!");
+        assert_eq!(
+            parse_tool_call("TOOL_CALL: {\"name\": \"get_price\"}"),
+            Some(Ok(ToolCall::GetPrice))
+        );
+        assert!(matches!(
+            parse_tool_call("TOOL_CALL: {\"name\": \"sell\", \"arguments\": {\"tokens\": 0}}"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            parse_tool_call("TOOL_CALL: {\"name\": \"rug\"}"),
+            Some(Err(_))
+        ));
+        assert_eq!(parse_tool_call("no tools needed"), None);
+    }
+}
@@ -1,29 +1,35 @@
 use std::time::Duration;
 
 use crate::bitcoin_lib::{
-    Address, Amount, EcdsaSighashType, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
-    Witness,
-    absolute::LockTime,
-    hashes::Hash,
-    script::{Builder, PushBytesBuf},
-    sighash::SighashCache,
-    transaction::Version,
+    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+    absolute::LockTime, transaction::Version,
 };
 use candid::CandidType;
 use ic_cdk::api::management_canister::bitcoin::{
-    GetUtxosRequest, SendTransactionRequest, Utxo, bitcoin_get_utxos, bitcoin_send_transaction,
+    GetUtxosRequest, SendTransactionRequest, bitcoin_get_utxos, bitcoin_send_transaction,
 };
-use icrc_ledger_types::icrc1::account::Account;
 use ordinals::{Edict, Runestone};
 use slotmap::Key;
 
 use crate::{
-    bitcoin::{DUST_THRESHOLD, signer::ecdsa::ecdsa_sign, utils::*},
+    bitcoin::{
+        DUST_THRESHOLD,
+        signer::sign_transaction,
+        transaction::{
+            batch::BatchUtxos,
+            cpfp::{self, CpfpArgs},
+        },
+        utils::slice_to_txid,
+        wallet::{Wallet, WalletUtxo},
+    },
+    broadcast,
     indexer::RuneId,
     state::{
-        queue::ScheduledTransaction, read_agents, read_config, utxo_manager::RunicUtxo,
-        write_agents, write_scheduled_state, write_utxo_manager,
+        broadcasts::WatchedInput, queue::ScheduledTransaction, read_agents, read_config,
+        read_scheduled_state, utxo_manager::RunicUtxo, write_agents, write_scheduled_state,
+        write_utxo_manager,
     },
+    utils,
 };
 
 #[derive(CandidType, PartialEq, Eq)]
@@ -37,50 +43,192 @@ pub enum TransactionType {
         commit_tx_address: Address,
         commit: Transaction,
         reveal: Transaction,
-        fee_utxos: Vec<Utxo>,
-        fee_payer: Address,
+        fee_utxos: Vec<WalletUtxo>,
+        fee_payer: Wallet,
     },
     Bitcoin {
-        utxos: Vec<Utxo>,
+        utxos: Vec<WalletUtxo>,
         txn: Transaction,
-        sender: Address,
-        sender_account: Account,
+        sender: Wallet,
     },
     Rune {
         runic_utxos: Vec<RunicUtxo>,
         runeid: RuneId,
         rune_amount: u128,
-        rune_sender: Box<Address>,
+        rune_sender: Box<Wallet>,
         rune_receiver: Box<Address>,
-        rune_sender_account: Account,
-        fee_utxos: Vec<Utxo>,
+        fee_utxos: Vec<WalletUtxo>,
         fee: u64,
-        fee_payer: Box<Address>,
-        fee_payer_account: Account,
+        fee_payer: Box<Wallet>,
         postage: Amount,
     },
     Combined {
         runic_utxos: Vec<RunicUtxo>,
         runeid: RuneId,
         rune_amount: u128,
-        rune_sender: Box<Address>,
+        rune_sender: Box<Wallet>,
         rune_receiver: Box<Address>,
-        rune_sender_account: Account,
-        bitcoin_utxos: Vec<Utxo>,
+        bitcoin_utxos: Vec<WalletUtxo>,
         bitcoin_amount: u64,
-        bitcoin_sender: Box<Address>,
+        bitcoin_sender: Box<Wallet>,
         bitcoin_receiver: Box<Address>,
-        bitcoin_sender_account: Account,
-        fee_utxos: Vec<Utxo>,
+        fee_utxos: Vec<WalletUtxo>,
         fee: u64,
-        fee_payer: Box<Address>,
-        fee_payer_account: Account,
+        fee_payer: Box<Wallet>,
         postage: Amount,
     },
+    // settlement of every leg of an agent, the agent's change is the last output
+    Batch {
+        txn: Transaction,
+        runeid: Option<RuneId>,
+        agent: Box<Wallet>,
+        utxos: BatchUtxos,
+        fee: u64,
+        change: usize,
+    },
+    // child paying for an unconfirmed parent through the parent's change, topped up by the owner
+    Cpfp {
+        parent_output: WalletUtxo,
+        fee_utxos: Vec<WalletUtxo>,
+        txn: Transaction,
+        owner: Wallet,
+    },
 }
 
 impl TransactionType {
-    pub async fn submit(self) -> SubmittedTxidType {
+    /// network fee paid by the transaction, in satoshis
+    pub fn fee(&self) -> u64 {
+        match self {
+            Self::Etching {
+                commit, fee_utxos, ..
+            } => {
+                let spent = fee_utxos
+                    .iter()
+                    .fold(0, |total, utxo| total + utxo.utxo.value);
+                let created = commit
+                    .output
+                    .iter()
+                    .fold(0, |total, output| total + output.value.to_sat());
+                spent - created
+            }
+            Self::Bitcoin { utxos, txn, .. } => {
+                let spent = utxos.iter().fold(0, |total, utxo| total + utxo.utxo.value);
+                let created = txn
+                    .output
+                    .iter()
+                    .fold(0, |total, output| total + output.value.to_sat());
+                spent - created
+            }
+            Self::Rune { fee, .. } | Self::Combined { fee, .. } | Self::Batch { fee, .. } => *fee,
+            Self::Cpfp {
+                parent_output,
+                fee_utxos,
+                txn,
+                ..
+            } => {
+                let spent = fee_utxos
+                    .iter()
+                    .fold(parent_output.utxo.value, |total, utxo| {
+                        total + utxo.utxo.value
+                    });
+                spent - txn.output[0].value.to_sat()
+            }
+        }
+    }
+
+    /// First input of a settlement batch, it's gone from its address once the batch confirms
+    pub fn watched_input(&self) -> Option<WatchedInput> {
+        let Self::Batch {
+            txn, agent, utxos, ..
+        } = self
+        else {
+            return None;
+        };
+        // inputs are the runic utxos, then the agent's and the deposits' bitcoin
+        let (address, utxo) = if let Some(runic) = utxos.runic.first() {
+            (agent.address().clone(), &runic.utxo)
+        } else if let Some(utxo) = utxos.agent.first() {
+            (agent.address_of(utxo.address_type).clone(), &utxo.utxo)
+        } else {
+            let (trader, deposit) = utxos.deposits.first()?;
+            let utxo = deposit.first()?;
+            (trader.address_of(utxo.address_type).clone(), &utxo.utxo)
+        };
+        let input = txn.input.first()?;
+        Some(WatchedInput {
+            address: address.to_string(),
+            txid: input.previous_output.txid.to_string(),
+            vout: input.previous_output.vout,
+            // utxos of unconfirmed transactions don't carry a height
+            seen_unspent: utxo.height > 0,
+        })
+    }
+
+    /// Hands the reserved utxos back to the utxo manager without broadcasting.
+    pub fn release(self) {
+        write_utxo_manager(|manager| match self {
+            Self::Etching {
+                fee_utxos,
+                fee_payer,
+                ..
+            } => fee_payer.release(manager, fee_utxos),
+            Self::Bitcoin { utxos, sender, .. } => sender.release(manager, utxos),
+            Self::Rune {
+                runic_utxos,
+                runeid,
+                rune_sender,
+                fee_utxos,
+                fee_payer,
+                ..
+            } => {
+                manager.release_runic_utxos(
+                    rune_sender.address().to_string().as_ref(),
+                    runeid,
+                    runic_utxos,
+                );
+                fee_payer.release(manager, fee_utxos);
+            }
+            Self::Combined {
+                runic_utxos,
+                runeid,
+                rune_sender,
+                bitcoin_utxos,
+                bitcoin_sender,
+                fee_utxos,
+                fee_payer,
+                ..
+            } => {
+                manager.release_runic_utxos(
+                    rune_sender.address().to_string().as_ref(),
+                    runeid,
+                    runic_utxos,
+                );
+                bitcoin_sender.release(manager, bitcoin_utxos);
+                fee_payer.release(manager, fee_utxos);
+            }
+            Self::Batch {
+                runeid,
+                agent,
+                utxos,
+                ..
+            } => utxos.release(manager, runeid, &agent),
+            // the parent's change isn't in the pool, its lease is only dropped
+            Self::Cpfp {
+                parent_output,
+                fee_utxos,
+                owner,
+                ..
+            } => {
+                manager.drop_lease(&parent_output.utxo);
+                owner.release(manager, fee_utxos);
+            }
+        })
+    }
+
+    /// Signs and broadcasts the transaction.
+    /// On failure the reserved utxos are handed back to the utxo manager.
+    pub async fn submit(self) -> Result<SubmittedTxidType, String> {
+        let fee = self.fee();
         match self {
             Self::Etching {
                 agent_id,
@@ -106,11 +254,12 @@ impl TransactionType {
                 .await
                 .is_err()
                 {
-                    write_utxo_manager(|manager| {
-                        manager.record_bitcoin_utxos(fee_payer.to_string().as_ref(), fee_utxos);
-                    });
-                    ic_cdk::trap("failed submitting the transaction")
+                    write_utxo_manager(|manager| fee_payer.release(manager, fee_utxos));
+                    return Err(String::from("failed submitting the transaction"));
                 }
+                write_utxo_manager(|manager| {
+                    manager.mark_spent(&commit, &txid, ic_cdk::api::time())
+                });
                 write_scheduled_state(|state| {
                     let id = state.get_id();
                     let timer_id =
@@ -124,53 +273,23 @@ impl TransactionType {
                             txn: reveal,
                             commit_tx_address: commit_tx_address.to_string(),
                             timer_id: timer_id.data(),
+                            commit: Some(commit),
+                            commit_fee: Some(fee),
+                            cpfp_fee: None,
                         },
                     );
                 });
-                SubmittedTxidType::Bitcoin { txid }
+                Ok(SubmittedTxidType::Bitcoin { txid })
             }
-            Self::Bitcoin {
-                utxos,
-                txn,
-                sender,
-                sender_account,
-            } => {
+            Self::Bitcoin { utxos, txn, sender } => {
                 let mut txn: Transaction = txn;
-                let (path, pubkey) = read_config(|config| {
-                    let ecdsa_pubkey = config.ecdsa_public_key();
-                    let path = account_to_derivation_path(&sender_account);
-                    let pubkey = derive_public_key(&ecdsa_pubkey, &path).public_key;
-                    (
-                        path.iter().map(|x| x.to_vec()).collect::<Vec<Vec<u8>>>(),
-                        pubkey,
-                    )
-                });
+                let signers = utxos
+                    .iter()
+                    .map(|utxo| sender.signer_for(utxo))
+                    .collect::<Vec<_>>();
+                sign_transaction(&mut txn, &signers).await;
 
-                let txn_cache = SighashCache::new(txn.clone());
                 let network = read_config(|config| config.bitcoin_network());
-
-                for (index, input) in txn.input.iter_mut().enumerate() {
-                    let sighash = txn_cache
-                        .legacy_signature_hash(
-                            index,
-                            &sender.script_pubkey(),
-                            EcdsaSighashType::All.to_u32(),
-                        )
-                        .unwrap();
-
-                    let signature = ecdsa_sign(sighash.as_byte_array().to_vec(), path.clone())
-                        .await
-                        .signature;
-                    let mut signature = sec1_to_der(signature);
-                    signature.push(EcdsaSighashType::All.to_u32() as u8);
-                    let signature = PushBytesBuf::try_from(signature).unwrap();
-                    let pubkey = PushBytesBuf::try_from(pubkey.clone()).unwrap();
-                    input.script_sig = Builder::new()
-                        .push_slice(signature)
-                        .push_slice(pubkey)
-                        .into_script();
-                    input.witness.clear();
-                }
                 let txid = txn.compute_txid().to_string();
                 let txn_bytes = bitcoin::consensus::serialize(&txn);
                 ic_cdk::println!("bitcoin transaction bytes:");
@@ -182,12 +301,14 @@ impl TransactionType {
                 .await
                 .is_err()
                 {
-                    write_utxo_manager(|manager| {
-                        manager.record_bitcoin_utxos(sender.to_string().as_ref(), utxos);
-                    });
-                    ic_cdk::trap("failed submitting the transaction")
+                    write_utxo_manager(|manager| sender.release(manager, utxos));
+                    return Err(String::from("failed submitting the transaction"));
                 }
-                SubmittedTxidType::Bitcoin { txid }
+                write_utxo_manager(|manager| manager.mark_spent(&txn, &txid, ic_cdk::api::time()));
+                // the change to the sender follows the receiver's output
+                let change = (txn.output.len() > 1).then_some(1);
+                broadcast::track_transfer(&txn, &signers, change, &sender);
+                Ok(SubmittedTxidType::Bitcoin { txid })
             }
 
             Self::Rune {
@@ -196,11 +317,9 @@ impl TransactionType {
                 rune_amount,
                 rune_sender,
                 rune_receiver,
-                rune_sender_account,
                 fee_utxos,
                 fee,
                 fee_payer,
-                fee_payer_account,
                 postage,
             } => {
                 let mut input = vec![];
@@ -210,13 +329,11 @@ impl TransactionType {
                 let mut bitcoin_spent_in_runic = 0;
                 let mut fee_total_spent = 0;
 
-                let mut indexes_for_sender = vec![];
-
                 runic_utxos.iter().for_each(|RunicUtxo { utxo, balance }| {
                     runic_total_spent += balance;
                     bitcoin_spent_in_runic += utxo.value;
                     let txin = TxIn {
-                        sequence: Sequence::MAX,
+                        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                         witness: Witness::new(),
                         script_sig: ScriptBuf::new(),
                         previous_output: OutPoint {
@@ -224,9 +341,7 @@ impl TransactionType {
                             vout: utxo.outpoint.vout,
                         },
                     };
-                    let len = input.len();
                     input.push(txin);
-                    indexes_for_sender.push(len);
                 });
 
                 let need_change_rune_output =
@@ -256,7 +371,7 @@ impl TransactionType {
                         value: Amount::from_sat(0),
                     });
                     output.push(TxOut {
-                        script_pubkey: rune_sender.script_pubkey(),
+                        script_pubkey: rune_sender.address().script_pubkey(),
                         value: postage,
                     });
                     output.push(TxOut {
@@ -272,10 +387,10 @@ impl TransactionType {
 
                 // fee
 
-                fee_utxos.iter().for_each(|utxo| {
+                fee_utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
                     fee_total_spent += utxo.value;
                     let txin = TxIn {
-                        sequence: Sequence::MAX,
+                        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                         witness: Witness::new(),
                         script_sig: ScriptBuf::new(),
                         previous_output: OutPoint {
@@ -287,9 +402,10 @@ impl TransactionType {
                 });
 
                 let remaining = fee_total_spent - fee - required_bitcoin_for_postage;
+                let change = (remaining >= DUST_THRESHOLD).then_some(output.len());
                 if remaining >= DUST_THRESHOLD {
                     output.push(TxOut {
-                        script_pubkey: fee_payer.script_pubkey(),
+                        script_pubkey: fee_payer.address().script_pubkey(),
                         value: Amount::from_sat(remaining),
                     });
                 }
@@ -301,94 +417,43 @@ impl TransactionType {
                     lock_time: LockTime::ZERO,
                 };
 
-                // signing the transaction
-
-                let (rune_sender_path, rune_sender_pubkey, fee_payer_path, fee_payer_pubkey) =
-                    read_config(|config| {
-                        let ecdsa_public_key = config.ecdsa_public_key();
-                        let rune_sender_path = account_to_derivation_path(&rune_sender_account);
-                        let rune_sender_pubkey =
-                            derive_public_key(&ecdsa_public_key, &rune_sender_path).public_key;
-                        let fee_payer_path = account_to_derivation_path(&fee_payer_account);
-                        let fee_payer_pubkey =
-                            derive_public_key(&ecdsa_public_key, &fee_payer_path).public_key;
-                        (
-                            rune_sender_path
-                                .iter()
-                                .map(|x| x.to_vec())
-                                .collect::<Vec<Vec<u8>>>(),
-                            rune_sender_pubkey,
-                            fee_payer_path
-                                .iter()
-                                .map(|x| x.to_vec())
-                                .collect::<Vec<Vec<u8>>>(),
-                            fee_payer_pubkey,
-                        )
-                    });
-
-                let txn_cache = SighashCache::new(txn.clone());
-                let network = read_config(|config| config.bitcoin_network());
+                // signing the transaction, inputs can be of different address types
 
-                for (index, input) in txn.input.iter_mut().enumerate() {
-                    if indexes_for_sender.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &rune_sender.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature =
-                            ecdsa_sign(sighash.as_byte_array().to_vec(), rune_sender_path.clone())
-                                .await
-                                .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(rune_sender_pubkey.clone()).unwrap();
-
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    } else {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &fee_payer.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature =
-                            ecdsa_sign(sighash.as_byte_array().to_vec(), fee_payer_path.clone())
-                                .await
-                                .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(fee_payer_pubkey.clone()).unwrap();
-
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    }
-                }
+                let signers = runic_utxos
+                    .iter()
+                    .map(|RunicUtxo { utxo, .. }| {
+                        rune_sender.signer(rune_sender.preferred(), utxo.value)
+                    })
+                    .chain(fee_utxos.iter().map(|utxo| fee_payer.signer_for(utxo)))
+                    .collect::<Vec<_>>();
+                sign_transaction(&mut txn, &signers).await;
 
+                let network = read_config(|config| config.bitcoin_network());
                 let txid = txn.compute_txid().to_string();
                 let txn_bytes = bitcoin::consensus::serialize(&txn);
                 ic_cdk::println!("{}", hex::encode(&txn_bytes));
 
-                bitcoin_send_transaction(SendTransactionRequest {
+                if bitcoin_send_transaction(SendTransactionRequest {
                     transaction: txn_bytes,
                     network,
                 })
                 .await
-                .unwrap();
+                .is_err()
+                {
+                    write_utxo_manager(|manager| {
+                        manager.release_runic_utxos(
+                            rune_sender.address().to_string().as_ref(),
+                            runeid,
+                            runic_utxos,
+                        );
+                        fee_payer.release(manager, fee_utxos);
+                    });
+                    return Err(String::from("failed submitting the transaction"));
+                }
+                write_utxo_manager(|manager| manager.mark_spent(&txn, &txid, ic_cdk::api::time()));
+                broadcast::track_transfer(&txn, &signers, change, &fee_payer);
 
-                SubmittedTxidType::Bitcoin { txid }
+                Ok(SubmittedTxidType::Bitcoin { txid })
             }
             Self::Combined {
                 runic_utxos,
@@ -396,16 +461,13 @@ impl TransactionType {
                 rune_amount,
                 rune_sender,
                 rune_receiver,
-                rune_sender_account,
                 bitcoin_utxos,
                 bitcoin_amount,
                 bitcoin_sender,
                 bitcoin_receiver,
-                bitcoin_sender_account,
                 fee_utxos,
                 fee,
                 fee_payer,
-                fee_payer_account,
                 postage,
             } => {
                 let mut runic_total_spent = 0;
@@ -413,11 +475,8 @@ impl TransactionType {
                 let mut btc_total_spent = 0;
                 let mut fee_total_spent = 0;
 
-                let mut index_of_utxos_to_be_signed_by_rune_sender = vec![];
-                let mut index_of_utxos_to_be_signed_by_btc_sender = vec![];
-                let mut index_of_utxos_to_be_signed_by_fee_payer = vec![];
-
                 let (mut input, mut output) = (vec![], vec![]);
+                let mut change = None;
 
                 runic_utxos.iter().for_each(|RunicUtxo { utxo, balance }| {
                     runic_total_spent += balance;
@@ -425,14 +484,12 @@ impl TransactionType {
                     let txin = TxIn {
                         script_sig: ScriptBuf::new(),
                         witness: Witness::new(),
-                        sequence: Sequence::MAX,
+                        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                         previous_output: OutPoint {
                             txid: slice_to_txid(&utxo.outpoint.txid),
                             vout: utxo.outpoint.vout,
                         },
                     };
-                    let len = input.len();
-                    index_of_utxos_to_be_signed_by_rune_sender.push(len);
                     input.push(txin);
                 });
 
@@ -462,7 +519,7 @@ impl TransactionType {
                     });
 
                     output.push(TxOut {
-                        script_pubkey: rune_sender.script_pubkey(),
+                        script_pubkey: rune_sender.address().script_pubkey(),
                         value: postage,
                     });
 
@@ -478,19 +535,17 @@ impl TransactionType {
                 }
 
                 // btc
-                bitcoin_utxos.iter().for_each(|utxo| {
+                bitcoin_utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
                     btc_total_spent += utxo.value;
                     let txin = TxIn {
                         script_sig: ScriptBuf::new(),
                         witness: Witness::new(),
-                        sequence: Sequence::MAX,
+                        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                         previous_output: OutPoint {
                             txid: slice_to_txid(&utxo.outpoint.txid),
                             vout: utxo.outpoint.vout,
                         },
                     };
-                    let len = input.len();
-                    index_of_utxos_to_be_signed_by_btc_sender.push(len);
                     input.push(txin);
                 });
 
@@ -503,8 +558,9 @@ impl TransactionType {
                     });
 
                     if remaining > DUST_THRESHOLD {
+                        change = Some(output.len());
                         output.push(TxOut {
-                            script_pubkey: bitcoin_sender.script_pubkey(),
+                            script_pubkey: bitcoin_sender.address().script_pubkey(),
                             value: Amount::from_sat(remaining),
                         });
                     }
@@ -516,10 +572,10 @@ impl TransactionType {
 
                     // fee
 
-                    fee_utxos.iter().for_each(|utxo| {
+                    fee_utxos.iter().for_each(|WalletUtxo { utxo, .. }| {
                         fee_total_spent += utxo.value;
                         let txin = TxIn {
-                            sequence: Sequence::MAX,
+                            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                             script_sig: ScriptBuf::new(),
                             witness: Witness::new(),
                             previous_output: OutPoint {
@@ -527,14 +583,13 @@ impl TransactionType {
                                 vout: utxo.outpoint.vout,
                             },
                         };
-                        let len = input.len();
-                        index_of_utxos_to_be_signed_by_fee_payer.push(len);
                         input.push(txin);
                     });
 
                     let remaining = fee_total_spent - fee - required_postage_btc;
+                    change = Some(output.len());
                     output.push(TxOut {
-                        script_pubkey: fee_payer.script_pubkey(),
+                        script_pubkey: fee_payer.address().script_pubkey(),
                         value: Amount::from_sat(remaining),
                     });
                 }
@@ -546,162 +601,170 @@ impl TransactionType {
                     lock_time: LockTime::ZERO,
                 };
 
-                // signing
-
-                let (
-                    rune_sender_path,
-                    rune_sender_pubkey,
-                    bitcoin_sender_path,
-                    bitcoin_sender_pubkey,
-                    fee_payer_path,
-                    fee_payer_pubkey,
-                ) = read_config(|config| {
-                    let ecdsa_public_key = config.ecdsa_public_key();
-                    let rune_sender_path = account_to_derivation_path(&rune_sender_account);
-                    let rune_sender_pubkey =
-                        derive_public_key(&ecdsa_public_key, &rune_sender_path).public_key;
-                    let bitcoin_sender_path = account_to_derivation_path(&bitcoin_sender_account);
-                    let bitcoin_sender_pubkey =
-                        derive_public_key(&ecdsa_public_key, &bitcoin_sender_path).public_key;
-                    let fee_payer_path = account_to_derivation_path(&fee_payer_account);
-                    let fee_payer_pubkey =
-                        derive_public_key(&ecdsa_public_key, &fee_payer_path).public_key;
-                    (
-                        rune_sender_path
-                            .iter()
-                            .map(|x| x.to_vec())
-                            .collect::<Vec<Vec<u8>>>(),
-                        rune_sender_pubkey,
-                        bitcoin_sender_path
-                            .iter()
-                            .map(|x| x.to_vec())
-                            .collect::<Vec<Vec<u8>>>(),
-                        bitcoin_sender_pubkey,
-                        fee_payer_path
+                // signing, inputs can be of different address types
+
+                let mut signers = runic_utxos
+                    .iter()
+                    .map(|RunicUtxo { utxo, .. }| {
+                        rune_sender.signer(rune_sender.preferred(), utxo.value)
+                    })
+                    .chain(
+                        bitcoin_utxos
                             .iter()
-                            .map(|x| x.to_vec())
-                            .collect::<Vec<Vec<u8>>>(),
-                        fee_payer_pubkey,
+                            .map(|utxo| bitcoin_sender.signer_for(utxo)),
                     )
-                });
-
-                let txn_cache = SighashCache::new(txn.clone());
+                    .collect::<Vec<_>>();
+                if fee_payer != bitcoin_sender {
+                    signers.extend(fee_utxos.iter().map(|utxo| fee_payer.signer_for(utxo)));
+                }
+                sign_transaction(&mut txn, &signers).await;
 
                 let network = read_config(|config| config.bitcoin_network());
+                let txid = txn.compute_txid().to_string();
+                let txn_bytes = bitcoin::consensus::serialize(&txn);
+                ic_cdk::println!("{}", hex::encode(&txn_bytes));
 
-                for (index, input) in txn.input.iter_mut().enumerate() {
-                    if index_of_utxos_to_be_signed_by_rune_sender.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &rune_sender.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature =
-                            ecdsa_sign(sighash.as_byte_array().to_vec(), rune_sender_path.clone())
-                                .await
-                                .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(rune_sender_pubkey.clone()).unwrap();
-
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    } else if index_of_utxos_to_be_signed_by_btc_sender.contains(&index) {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &bitcoin_sender.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature = ecdsa_sign(
-                            sighash.as_byte_array().to_vec(),
-                            bitcoin_sender_path.clone(),
-                        )
-                        .await
-                        .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(bitcoin_sender_pubkey.clone()).unwrap();
-
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    } else {
-                        let sighash = txn_cache
-                            .legacy_signature_hash(
-                                index,
-                                &fee_payer.script_pubkey(),
-                                EcdsaSighashType::All.to_u32(),
-                            )
-                            .unwrap();
-                        let signature =
-                            ecdsa_sign(sighash.as_byte_array().to_vec(), fee_payer_path.clone())
-                                .await
-                                .signature;
-                        let mut signature = sec1_to_der(signature);
-                        signature.push(EcdsaSighashType::All.to_u32() as u8);
-                        let signature = PushBytesBuf::try_from(signature).unwrap();
-                        let pubkey = PushBytesBuf::try_from(fee_payer_pubkey.clone()).unwrap();
-
-                        input.script_sig = Builder::new()
-                            .push_slice(signature)
-                            .push_slice(pubkey)
-                            .into_script();
-                        input.witness.clear();
-                    }
+                if bitcoin_send_transaction(SendTransactionRequest {
+                    transaction: txn_bytes,
+                    network,
+                })
+                .await
+                .is_err()
+                {
+                    write_utxo_manager(|manager| {
+                        manager.release_runic_utxos(
+                            rune_sender.address().to_string().as_ref(),
+                            runeid,
+                            runic_utxos,
+                        );
+                        bitcoin_sender.release(manager, bitcoin_utxos);
+                        fee_payer.release(manager, fee_utxos);
+                    });
+                    return Err(String::from("failed submitting the transaction"));
                 }
+                write_utxo_manager(|manager| manager.mark_spent(&txn, &txid, ic_cdk::api::time()));
+                broadcast::track_transfer(&txn, &signers, change, &fee_payer);
 
+                Ok(SubmittedTxidType::Bitcoin { txid })
+            }
+            Self::Batch {
+                txn,
+                runeid,
+                agent,
+                utxos,
+                change,
+                ..
+            } => {
+                let mut txn: Transaction = txn;
+                let signers = utxos
+                    .runic
+                    .iter()
+                    .map(|RunicUtxo { utxo, .. }| agent.signer(agent.preferred(), utxo.value))
+                    .chain(utxos.agent.iter().map(|utxo| agent.signer_for(utxo)))
+                    .chain(utxos.deposits.iter().flat_map(|(trader, deposit)| {
+                        deposit.iter().map(|utxo| trader.signer_for(utxo))
+                    }))
+                    .collect::<Vec<_>>();
+                sign_transaction(&mut txn, &signers).await;
+
+                let network = read_config(|config| config.bitcoin_network());
                 let txid = txn.compute_txid().to_string();
                 let txn_bytes = bitcoin::consensus::serialize(&txn);
                 ic_cdk::println!("{}", hex::encode(&txn_bytes));
-
-                bitcoin_send_transaction(SendTransactionRequest {
+                if bitcoin_send_transaction(SendTransactionRequest {
                     transaction: txn_bytes,
                     network,
                 })
                 .await
-                .unwrap();
+                .is_err()
+                {
+                    write_utxo_manager(|manager| utxos.release(manager, runeid, &agent));
+                    return Err(String::from("failed submitting the transaction"));
+                }
+                write_utxo_manager(|manager| manager.mark_spent(&txn, &txid, ic_cdk::api::time()));
+                // bumps come out of the agent's change
+                broadcast::track_transfer(&txn, &signers, Some(change), &agent);
+                Ok(SubmittedTxidType::Bitcoin { txid })
+            }
+            Self::Cpfp {
+                parent_output,
+                fee_utxos,
+                txn,
+                owner,
+            } => {
+                let mut txn: Transaction = txn;
+                let signers = std::iter::once(&parent_output)
+                    .chain(fee_utxos.iter())
+                    .map(|utxo| owner.signer_for(utxo))
+                    .collect::<Vec<_>>();
+                sign_transaction(&mut txn, &signers).await;
 
-                SubmittedTxidType::Bitcoin { txid }
+                let network = read_config(|config| config.bitcoin_network());
+                let txid = txn.compute_txid().to_string();
+                let txn_bytes = bitcoin::consensus::serialize(&txn);
+                ic_cdk::println!("{}", hex::encode(&txn_bytes));
+                if bitcoin_send_transaction(SendTransactionRequest {
+                    transaction: txn_bytes,
+                    network,
+                })
+                .await
+                .is_err()
+                {
+                    write_utxo_manager(|manager| {
+                        manager.drop_lease(&parent_output.utxo);
+                        owner.release(manager, fee_utxos);
+                    });
+                    return Err(String::from("failed submitting the transaction"));
+                }
+                // not tracked for bumps, `accelerate_etching` replaces the child with the fee it recorded
+                write_utxo_manager(|manager| manager.mark_spent(&txn, &txid, ic_cdk::api::time()));
+                Ok(SubmittedTxidType::Bitcoin { txid })
             }
         }
     }
 }
 
 async fn submit_txn(id: u128) {
-    let txn = write_scheduled_state(|state| state.remove_txn(id));
+    // the entry is only removed once the reveal is broadcasted, so that a failed attempt is retried
+    let Some(txn) = read_scheduled_state(|state| state.get_txn(id)) else {
+        return;
+    };
     ic_cdk::println!("commit tx address: {}", txn.commit_tx_address);
     let network = read_config(|config| config.bitcoin_network());
-    let utxos_response = bitcoin_get_utxos(GetUtxosRequest {
+    let utxos_response = match bitcoin_get_utxos(GetUtxosRequest {
         network,
         address: txn.commit_tx_address.clone(),
         filter: None,
     })
     .await
-    .unwrap()
-    .0;
+    {
+        Err(_) => return,
+        Ok((response,)) => response,
+    };
     let utxos = utxos_response.utxos;
     for utxo in utxos.iter() {
         ic_cdk::println!("bitcoin in utxo: {}", utxo.value);
     }
     if utxos.is_empty() {
-        write_scheduled_state(|state| state.record_txn(id, txn));
-        ic_cdk::trap("No UTXOs Found")
+        ic_cdk::println!("No UTXOs Found");
+        return;
     }
     if utxos_response.tip_height - utxos[0].height < Runestone::COMMIT_CONFIRMATIONS as u32 {
-        write_scheduled_state(|state| state.record_txn(id, txn));
-        ic_cdk::trap("Not enough commit confirmation")
+        ic_cdk::println!("Not enough commit confirmation");
+        return;
     }
+    let commit_outpoint = txn.txn.input[0].previous_output;
+    let Some(commit_value) = utxos
+        .iter()
+        .find(|utxo| {
+            slice_to_txid(&utxo.outpoint.txid) == commit_outpoint.txid
+                && utxo.outpoint.vout == commit_outpoint.vout
+        })
+        .map(|utxo| utxo.value)
+    else {
+        ic_cdk::println!("commit output not found");
+        return;
+    };
     let transaction = bitcoin::consensus::serialize(&txn.txn);
     ic_cdk::println!("reveal: {}", hex::encode(&transaction));
     if bitcoin_send_transaction(SendTransactionRequest {
@@ -712,9 +775,25 @@ async fn submit_txn(id: u128) {
     .is_err()
     {
         ic_cdk::println!("Timer was hit for reveal txn submission but failed to submit due to err");
-        write_scheduled_state(|state| state.record_txn(id, txn));
     } else {
         ic_cdk::println!("transaction was submitted");
+        write_scheduled_state(|state| state.remove_txn(id));
+        // bumps of the reveal are paid by the creator, like the etching
+        let creator = read_agents(|agents| {
+            agents
+                .mapping
+                .get(&txn.agent_id)
+                .map(|agent| agent.created_by)
+        });
+        if let Some(creator) = creator {
+            broadcast::track_reveal(
+                txn.agent_id,
+                &txn.txn,
+                &txn.commit_tx_address,
+                commit_value,
+                &utils::get_wallet_for(&creator),
+            );
+        }
         ic_cdk_timers::clear_timer(txn.timer_id.into());
         // starting another timer to get the runeid
         ic_cdk_timers::set_timer(Duration::from_secs(10 * 60), move || {
@@ -723,6 +802,85 @@ async fn submit_txn(id: u128) {
     }
 }
 
+/*
+ * pays for the unconfirmed commit of an agent's etching through a child spending its change
+ * the reveal waits for the commit's confirmations, so the commit alone delays the launch
+ * calling it again replaces the earlier child with one paying more
+*/
+pub async fn accelerate_etching(agent_id: u128, fee_per_vbytes: u64) -> Result<String, String> {
+    let (id, scheduled) = read_scheduled_state(|state| {
+        state
+            .pending_txns()
+            .into_iter()
+            .find(|(_, other)| *other == agent_id)
+            .and_then(|(id, _)| state.get_txn(id).map(|txn| (id, txn)))
+    })
+    .ok_or_else(|| String::from("etching isn't waiting for its commit"))?;
+    let (Some(commit), Some(commit_fee)) = (scheduled.commit, scheduled.commit_fee) else {
+        return Err(String::from("commit of the etching wasn't recorded"));
+    };
+
+    // the commit output shows up once the commit is mined
+    let network = read_config(|config| config.bitcoin_network());
+    let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+        network,
+        address: scheduled.commit_tx_address,
+        filter: None,
+    })
+    .await
+    .map_err(|(_, err)| err)?;
+    let commit_txid = commit.compute_txid();
+    if response
+        .utxos
+        .iter()
+        .any(|utxo| slice_to_txid(&utxo.outpoint.txid) == commit_txid)
+    {
+        return Err(String::from("commit is already confirmed"));
+    }
+
+    let creator = read_agents(|agents| agents.mapping.get(&agent_id).map(|agent| agent.created_by))
+        .ok_or_else(|| String::from("agent doesn't exist"))?;
+    let handler = cpfp::transfer(CpfpArgs {
+        parent: commit,
+        parent_fee: commit_fee,
+        replaced_fee: scheduled.cpfp_fee,
+        owner: utils::get_wallet_for(&creator),
+        fee_per_vbytes,
+    })?;
+    let fee = handler.fee();
+    let SubmittedTxidType::Bitcoin { txid } = handler.submit().await?;
+    write_scheduled_state(|state| state.record_cpfp_fee(id, fee));
+    Ok(txid)
+}
+
+/// Timers don't survive upgrades.
+/// Re-arms the reveal submissions and the rune id lookups of etchings that are in progress.
+pub fn rearm_timers() {
+    let timer = read_config(|config| config.get_timer_for_txn_submission());
+    let pending = read_scheduled_state(|state| state.pending_txns());
+    for &(id, _) in pending.iter() {
+        let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(timer), move || {
+            ic_cdk::spawn(submit_txn(id))
+        });
+        write_scheduled_state(|state| state.update_timer(id, timer_id.data()));
+    }
+    let awaiting_runeid = read_agents(|agents| {
+        agents
+            .mapping
+            .iter()
+            .filter(|(id, agent)| {
+                agent.runeid.is_none() && !pending.iter().any(|(_, agent_id)| agent_id == id)
+            })
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>()
+    });
+    for id in awaiting_runeid {
+        ic_cdk_timers::set_timer(Duration::from_secs(60), move || {
+            ic_cdk::spawn(get_runeid(id))
+        });
+    }
+}
+
 async fn get_runeid(id: u128) {
     let runename = read_agents(|agents| {
         let agent = agents.mapping.get(&id).expect("should exist");
@@ -734,10 +892,20 @@ async fn get_runeid(id: u128) {
                 ic_cdk::spawn(get_runeid(id))
             });
         }
-        Some(entry) => write_agents(|agents| {
-            let mut agent = agents.mapping.get(&id).expect("should exist");
-            agent.runeid.replace(entry.rune_id);
-            agents.mapping.insert(id, agent);
-        }),
+        Some(entry) => {
+            let commit = write_agents(|agents| {
+                let mut agent = agents.mapping.get(&id).expect("should exist");
+                agent.runeid.replace(entry.rune_id);
+                // the version of the reveal that confirmed, it may have been replaced
+                agent.txns.1.replace(entry.etching);
+                let commit = agent.txns.0.clone();
+                agents.mapping.insert(id, agent);
+                commit
+            });
+            // the commit isn't tracked as a broadcast, its inputs are spent for good once the reveal confirms
+            if let Some(commit) = commit {
+                write_utxo_manager(|manager| manager.drop_spent_leases(&[commit]));
+            }
+        }
     }
 }
@@ -1,5 +1,8 @@
 use candid::Principal;
 use icrc_ledger_types::icrc1::account::Account;
+
+use crate::bitcoin::wallet::Wallet;
+use crate::state::read_ledger_entries;
 use tiny_keccak::{Hasher, Sha3};
 
 pub fn principal_to_subaccount(principal: &Principal) -> [u8; 32] {
@@ -18,6 +21,13 @@ pub fn get_account_for(principal: &Principal) -> Account {
     }
 }
 
+/// the deposit addresses of the principal
+pub fn get_wallet_for(principal: &Principal) -> Wallet {
+    let address_types =
+        read_ledger_entries(|entries| entries.get(principal).unwrap_or_default().address_types());
+    Wallet::new(get_account_for(principal), &address_types)
+}
+
 pub fn generate_subaccount_for_agent(id: u128) -> [u8; 32] {
     let mut hash = [0u8; 32];
     let mut hasher = Sha3::v256();
@@ -0,0 +1,88 @@
+//! Stand-in for the llm canister in local tests.
+//! Serves `v0_chat` with canned responses, so that the backend can be exercised without ollama.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use candid::CandidType;
+use ic_cdk::{query, update};
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Role {
+    #[serde(rename = "system")]
+    System,
+    #[serde(rename = "user")]
+    User,
+    #[serde(rename = "assistant")]
+    Assistant,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LlmRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+thread_local! {
+    static RESPONSES: RefCell<VecDeque<String>> = RefCell::default();
+    static LAST_REQUEST: RefCell<Option<LlmRequest>> = RefCell::default();
+}
+
+/// queued responses are returned first, in order
+#[update]
+pub fn push_responses(responses: Vec<String>) {
+    RESPONSES.with_borrow_mut(|queue| queue.extend(responses))
+}
+
+#[update]
+pub fn clear_responses() {
+    RESPONSES.with_borrow_mut(|queue| queue.clear())
+}
+
+#[query]
+pub fn last_request() -> Option<LlmRequest> {
+    LAST_REQUEST.with_borrow(|request| request.clone())
+}
+
+#[update]
+pub fn v0_chat(request: LlmRequest) -> String {
+    let response = RESPONSES
+        .with_borrow_mut(|queue| queue.pop_front())
+        .unwrap_or_else(|| default_response(&request));
+    LAST_REQUEST.with_borrow_mut(|last| last.replace(request));
+    response
+}
+
+// answers the prompts of the backend in the format it expects
+fn default_response(request: &LlmRequest) -> String {
+    let system = request
+        .messages
+        .iter()
+        .find(|message| matches!(message.role, Role::System))
+        .map_or("", |message| message.content.as_str());
+    if system.contains("write a riddle") {
+        return String::from("RIDDLE: What has keys but can't open locks?\nANSWER: piano");
+    }
+    if system.contains("generate some word") {
+        return String::from("stub secret");
+    }
+    if system.contains("You summarise a chat") {
+        return String::from("The user chatted with the agent.");
+    }
+    let last = request
+        .messages
+        .last()
+        .map_or("", |message| message.content.as_str());
+    format!("stub reply to: {last}")
+}
+
+ic_cdk::export_candid!();
@@ -49,6 +49,7 @@ export default function CreateAgent({ wallet, setWarningMessage }) {
         website: formData.website ? [formData.website] : [],
         discord: formData.discord ? [formData.discord] : [],
         openchat: formData.openchat ? [formData.openchat] : [],
+        games: [],
       });
       setIsOpen(false);
     } catch (error) {
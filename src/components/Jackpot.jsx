@@ -36,7 +36,7 @@ export default function Jackpot({
       return;
     }
     try {
-      const arg = { id: { Id: agent_id }, message: inputText };
+      const arg = { id: { Id: agent_id }, message: inputText, pay_with: [] };
       const response = await backend.lucky_draw(arg);
       setWarningMessage(response);
     } catch (error) {
@@ -20,6 +20,7 @@ export default function Market({ agent_id, wallet, setWarningMessage }) {
         id: { Id: agent_id },
         amount_out_min: 0,
         buy_exact_in: btcAmountInSatoshis,
+        deliver_to: [],
       };
       const result = await backend.buy(buyArgs);
       // Convert token amount (assumed to be in integer form with 3 decimals) back to human readable value
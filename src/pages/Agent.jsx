@@ -109,6 +109,9 @@ export default function Agent({
           <p>
             <strong>Holders:</strong> {agentData.holders}
           </p>
+          <p>
+            <strong>Status:</strong> {Object.keys(agentData.lifecycle)[0]}
+          </p>
         </div>
         {agentData.logo && (
           <img
@@ -22,8 +22,14 @@ export default function Home({
   // Function to fetch the agents list from the backend
   const fetchAgents = async () => {
     try {
-      const agentList = await backend.get_agents();
-      setAgents(agentList);
+      const page = await backend.get_agents({
+        cursor: [],
+        limit: 50,
+        sort_by: { CreatedAt: null },
+        created_by: [],
+        lifecycle: [],
+      });
+      setAgents(page.agents);
     } catch (error) {
       setAgents([])
       console.error("Error fetching agents:", error);